@@ -6,7 +6,9 @@ use crate::{
     DisplayPoint, Editor, EditorMode, MultiBuffer,
 };
 
-use gpui::{Context, Font, FontFeatures, FontStyle, FontWeight, Model, Pixels, ViewContext};
+use gpui::{
+    Context, Font, FontFeatures, FontStretch, FontStyle, FontWeight, Model, Pixels, ViewContext,
+};
 
 use project::Project;
 use util::test::{marked_text_offsets, marked_text_ranges};
@@ -31,6 +33,8 @@ pub fn marked_display_snapshot(
         features: FontFeatures::default(),
         weight: FontWeight::default(),
         style: FontStyle::default(),
+        stretch: FontStretch::default(),
+        variations: Vec::new(),
     };
     let font_size: Pixels = 14usize.into();
 
@@ -111,11 +111,18 @@ impl App {
     /// Builds an app with the given asset source.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
+        Self::new_with_font_path_cache(None)
+    }
+
+    /// Builds an app whose text system persists the system font files it finds to the given
+    /// file, if any, so that they don't have to be searched for again on the next start while
+    /// they're unchanged. Only used on platforms where fonts are loaded by GPUI itself.
+    pub fn new_with_font_path_cache(font_path_cache: Option<PathBuf>) -> Self {
         #[cfg(any(test, feature = "test-support"))]
         log::info!("GPUI was compiled in test mode");
 
         Self(AppContext::new(
-            current_platform(),
+            current_platform(font_path_cache),
             Arc::new(()),
             http::client(),
         ))
@@ -66,11 +66,11 @@ use time::UtcOffset;
 pub(crate) use windows::*;
 
 #[cfg(target_os = "macos")]
-pub(crate) fn current_platform() -> Rc<dyn Platform> {
+pub(crate) fn current_platform(_font_path_cache: Option<PathBuf>) -> Rc<dyn Platform> {
     Rc::new(MacPlatform::new())
 }
 #[cfg(target_os = "linux")]
-pub(crate) fn current_platform() -> Rc<dyn Platform> {
+pub(crate) fn current_platform(font_path_cache: Option<PathBuf>) -> Rc<dyn Platform> {
     let wayland_display = std::env::var_os("WAYLAND_DISPLAY");
     let x11_display = std::env::var_os("DISPLAY");
 
@@ -78,17 +78,17 @@ pub(crate) fn current_platform() -> Rc<dyn Platform> {
     let use_x11 = x11_display.is_some_and(|display| !display.is_empty());
 
     if use_wayland {
-        Rc::new(WaylandClient::new())
+        Rc::new(WaylandClient::new(font_path_cache))
     } else if use_x11 {
-        Rc::new(X11Client::new())
+        Rc::new(X11Client::new(font_path_cache))
     } else {
-        Rc::new(HeadlessClient::new())
+        Rc::new(HeadlessClient::new(font_path_cache))
     }
 }
 // todo("windows")
 #[cfg(target_os = "windows")]
-pub(crate) fn current_platform() -> Rc<dyn Platform> {
-    Rc::new(WindowsPlatform::new())
+pub(crate) fn current_platform(font_path_cache: Option<PathBuf>) -> Rc<dyn Platform> {
+    Rc::new(WindowsPlatform::new(font_path_cache))
 }
 
 pub(crate) trait Platform: 'static {
@@ -266,6 +266,20 @@ pub(crate) trait PlatformTextSystem: Send + Sync {
         raster_bounds: Bounds<DevicePixels>,
     ) -> Result<(Size<DevicePixels>, Vec<u8>)>;
     fn layout_line(&self, text: &str, font_size: Pixels, runs: &[FontRun]) -> LineLayout;
+    /// Lays out a line like `layout_line`, marking it as truncated if it's wider than
+    /// `max_width`, e.g. so single-line inputs can show an ellipsis. The whole line is still laid
+    /// out, so callers can find where to cut it off with `index_for_x`.
+    fn layout_line_with_max_width(
+        &self,
+        text: &str,
+        font_size: Pixels,
+        runs: &[FontRun],
+        max_width: Option<Pixels>,
+    ) -> LineLayout {
+        let mut layout = self.layout_line(text, font_size, runs);
+        layout.truncated = max_width.map_or(false, |max_width| layout.width > max_width);
+        layout
+    }
 }
 
 /// Basic metadata about the current application and operating system.
@@ -1,516 +1,7734 @@
 use crate::{
-    point, size, Bounds, DevicePixels, Font, FontFeatures, FontId, FontMetrics, FontRun, FontStyle,
-    FontWeight, GlyphId, LineLayout, Pixels, PlatformTextSystem, Point, RenderGlyphParams,
-    ShapedGlyph, SharedString, Size,
+    point, px, size, Bounds, DevicePixels, Font, FontFeatures, FontId, FontMetrics, FontRun,
+    FontStretch, FontStyle, FontVariation, FontWeight, GlyphId, LineLayout, Pixels,
+    PlatformTextSystem, Point, RenderGlyphParams, ShapedGlyph, SharedString, Size, TextDirection,
+    SUBPIXEL_VARIANTS,
 };
-use anyhow::{anyhow, Context, Ok, Result};
-use collections::HashMap;
+use anyhow::{anyhow, Ok, Result};
+use collections::{HashMap, HashSet};
 use cosmic_text::{
-    Attrs, AttrsList, BufferLine, CacheKey, Family, Font as CosmicTextFont, FontSystem, SwashCache,
+    fontdb,
+    rustybuzz::{
+        self,
+        ttf_parser::{self, name::Name, name_id, Language},
+    },
+    Align, Attrs, AttrsList, BufferLine, CacheKey, Family, Font as CosmicTextFont, FontSystem,
+    LayoutGlyph, Shaping, SwashCache, SwashContent, SwashImage,
 };
 
 use itertools::Itertools;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use pathfinder_geometry::{
     rect::{RectF, RectI},
     vector::{Vector2F, Vector2I},
 };
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    fmt::Write,
+    hash::{DefaultHasher, Hash, Hasher},
+    iter,
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+use swash::{
+    scale::{Render, ScaleContext, Source, StrikeWith},
+    zeno::{Angle, Format, Transform, Vector},
+};
+use thiserror::Error;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+use unicode_segmentation::UnicodeSegmentation;
+use util::ResultExt;
 
 pub(crate) struct CosmicTextSystem(RwLock<CosmicTextSystemState>);
 
+/// Supplies font data on demand, for fonts that don't live in the system font directories, such as
+/// fonts embedded as resources, streamed over the network or read from a virtual file system.
+pub(crate) trait FontSource: Send + Sync {
+    /// Returns the data of every face in the given family, or `None` if this source doesn't
+    /// provide the family. Called at most once per family, when it's first requested.
+    fn load_family(&self, family: &str) -> Option<Vec<Cow<'static, [u8]>>>;
+}
+
 struct CosmicTextSystemState {
     swash_cache: SwashCache,
     font_system: FontSystem,
-    /// Contains all already loaded fonts, including all faces. Indexed by `FontId`.
-    loaded_fonts_store: Vec<Arc<CosmicTextFont>>,
+    /// Contains all already loaded fonts, including all faces. Indexed by `FontId`, with `None` for
+    /// fonts that were unloaded, so that the ids of the other fonts stay valid.
+    loaded_fonts_store: Vec<Option<Arc<CosmicTextFont>>>,
+    /// The font id of each face in `loaded_fonts_store`, for looking up the faces that cosmic-text
+    /// shaped glyphs with. Faces derived with features or variations map to their base font id.
+    font_ids_by_face_id: HashMap<fontdb::ID, FontId>,
     /// Caches the `FontId`s associated with a specific family to avoid iterating the font database
     /// for every font face in a family.
     font_ids_by_family_cache: HashMap<SharedString, SmallVec<[FontId; 4]>>,
     /// The name of each font associated with the given font id
     postscript_names: HashMap<FontId, String>,
+    /// The fonts with color glyph tables, which are rasterized as emoji.
+    color_font_ids: HashSet<FontId>,
+    /// The OpenType features that fonts requested with non-default features are shaped with. Each
+    /// face gets a separate font id for every set of features it's requested with.
+    font_features: HashMap<FontId, Vec<rustybuzz::Feature>>,
+    /// The font id of each face and set of features it was requested with.
+    font_ids_by_features: HashMap<(FontId, FontFeatures), FontId>,
+    /// The faces loaded from each font file added with `add_fonts`, keyed by a hash and the length
+    /// of its data, so adding the same data again can reuse them.
+    face_ids_by_font_data: HashMap<(u64, usize), SmallVec<[fontdb::ID; 1]>>,
+    /// The faces loaded by `add_fonts_with_ids`, which are the only ones that can be unloaded.
+    /// Fonts added with `add_fonts`, such as the bundled fonts, and system fonts stay loaded.
+    unloadable_face_ids: HashSet<fontdb::ID>,
+    /// Families whose faces are never unloaded, e.g. the UI font. The icon font families are
+    /// always pinned, without being listed here.
+    pinned_families: HashSet<String>,
+    /// The variation axis coordinates of registered variable font instances.
+    font_variations: HashMap<FontId, Vec<rustybuzz::Variation>>,
+    /// The font id of each registered instance, keyed by the font it was registered for and the
+    /// tags and bits of its coordinates.
+    font_ids_by_instance: HashMap<(FontId, Vec<(u32, u32)>), FontId>,
+    /// The styles that are faked for fonts whose family has no face with them.
+    synthetic_styles: HashMap<FontId, SyntheticStyle>,
+    /// The font id of each face and style it was synthesized with.
+    font_ids_by_synthetic_style: HashMap<(FontId, SyntheticStyle), FontId>,
+    /// Renders glyphs of variable font instances and synthesized styles, which `swash_cache` can't
+    /// tell apart.
+    scale_context: ScaleContext,
+    /// The rendered glyphs of variable font instances and synthesized styles.
+    instance_images: HashMap<(FontId, CacheKey), Option<SwashImage>>,
+    /// System fonts that are still being enumerated and parsed on a background thread.
+    pending_system_fonts: Option<JoinHandle<SystemFonts>>,
+    /// How many fonts are checked for a character missing from its run's font before giving up
+    /// and rendering it as a notdef glyph.
+    max_fallback_attempts: usize,
+    /// Families that are tried first when looking for a font covering a character that is missing
+    /// from its run's font. Defaults to fonts suited to the system locale.
+    fallback_families: Vec<String>,
+    /// The family of the font each character falls back to, if one was found.
+    fallback_coverage: HashMap<char, Option<SharedString>>,
+    /// Caches the result of looking up fonts by their full name, keyed by the lowercased name.
+    font_ids_by_full_name: HashMap<String, Option<FontId>>,
+    /// How many system font files were found and loaded, for diagnosing missing fonts.
+    system_font_stats: SystemFontStats,
+    /// When each loaded system font file was last modified, used to detect fonts that were
+    /// updated in place.
+    system_font_modified_times: HashMap<PathBuf, Option<SystemTime>>,
+    /// Where the font files found in the system font directories are persisted between starts, if
+    /// anywhere.
+    font_path_cache: Option<PathBuf>,
+    /// Sources that are asked for families that aren't in the font database, in registration order.
+    font_sources: Vec<Box<dyn FontSource>>,
+    /// Whether color glyphs such as emoji are rendered in color, rather than as monochrome
+    /// silhouettes in the color of the surrounding text.
+    color_glyphs_enabled: bool,
+    /// Whether characters that no font covers are rendered as the notdef glyph of their run's font,
+    /// rather than being left out of the layout.
+    notdef_glyphs_enabled: bool,
+    /// Icon font families in order of preference. Requests for any of them resolve to the first
+    /// one that is installed, since older systems only ship the legacy icon fonts.
+    icon_font_families: Vec<String>,
+    /// The advance reported for glyph ids that are out of range for their font.
+    missing_glyph_advance: MissingGlyphAdvance,
+    /// The largest glyph bitmap, in pixels, that will be rasterized.
+    max_glyph_pixels: usize,
+    /// Whether a line's ascent and descent only account for the fonts of its runs, and not for
+    /// the fonts characters fell back to, which keeps line heights stable.
+    normalize_fallback_metrics: bool,
+    /// Transparent pixels added around every glyph bitmap.
+    glyph_padding: u32,
+    /// Families that are tried before any other fallback for characters the font of their run
+    /// doesn't cover, most recently pushed first.
+    fallback_overrides: Vec<SharedString>,
+    /// Counts glyph rasterization work over the lifetime of the text system.
+    raster_counters: RasterCounters,
+    /// The advance of each glyph that was measured, in font units, keyed by font. Advances are
+    /// measured behind a read lock, so the cache has its own lock.
+    advance_cache: Mutex<HashMap<FontId, HashMap<GlyphId, Size<f32>>>>,
+    /// How control characters other than tabs and line breaks are laid out.
+    control_character_display: ControlCharacterDisplay,
+    /// Whether text is normalized to NFC before it's shaped.
+    normalize_to_nfc: bool,
+    /// How much larger than the text around them emoji are rendered.
+    emoji_scale: f32,
+}
+
+/// The font of each span in a range of text, relative to the start of the range.
+type FontSpans = SmallVec<[(Range<usize>, FontId); 4]>;
+
+/// The advance reported for glyph ids that don't exist in a font, e.g. stale ids from a font that
+/// was since replaced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum MissingGlyphAdvance {
+    /// Use the advance of the font's `.notdef` glyph, matching how the glyph is rendered.
+    #[default]
+    Notdef,
+    /// Treat the glyph as taking up no space.
+    Zero,
+}
+
+/// How control characters, which fonts usually render as boxes or not at all, are laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ControlCharacterDisplay {
+    /// Take up no space and draw nothing.
+    #[default]
+    Hidden,
+    /// Draw the matching symbol from the Control Pictures block, e.g. ␀ for NUL, for showing
+    /// invisible characters.
+    Pictures,
+}
+
+/// The slant and offset of a text caret drawn for a font, in font units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct CaretMetrics {
+    /// The vertical component of the caret's slope.
+    pub slope_rise: f32,
+    /// The horizontal component of the caret's slope, positive for right-leaning carets.
+    pub slope_run: f32,
+    /// How far to shift a slanted caret horizontally so it lines up with the glyphs.
+    pub offset: f32,
+}
+
+impl CaretMetrics {
+    /// Returns how far the caret moves horizontally per unit of height.
+    pub fn slope(&self) -> f32 {
+        if self.slope_rise == 0. {
+            0.
+        } else {
+            self.slope_run / self.slope_rise
+        }
+    }
+}
+
+/// The distance between tab stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TabWidth {
+    /// Tab stops are at every multiple of the given width.
+    Pixels(Pixels),
+    /// Tab stops are at every multiple of the given number of spaces in the font.
+    Spaces(u32),
+}
+
+/// Why a font couldn't be loaded. The text system's loading methods return these wrapped in
+/// `anyhow::Error`, so callers can `downcast_ref` them to react to specific failures.
+#[derive(Debug, Error)]
+pub(crate) enum FontLoadError {
+    /// The font file doesn't exist.
+    #[error("font file {0:?} not found")]
+    FileNotFound(PathBuf),
+    /// The font file exists but couldn't be read.
+    #[error("failed to read font file {path:?}: {error}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+    /// The font data at the given index of the fonts being added isn't a valid font.
+    #[error("failed to parse font {index}: {error}")]
+    ParseFailed {
+        index: usize,
+        #[source]
+        error: ttf_parser::FaceParsingError,
+    },
+    /// The font file doesn't contain any faces that could be loaded.
+    #[error("font file {0:?} contains no usable font faces")]
+    NoFaces(PathBuf),
+    /// The thread loading the font file, among others, panicked.
+    #[error("loading font file {0:?} panicked")]
+    LoadPanicked(PathBuf),
+    /// A face was found in the font database but couldn't be loaded.
+    #[error("failed to load font face {0:?}")]
+    FaceUnavailable(String),
+    /// No loaded font belongs to the requested family.
+    #[error("no font family named {0:?} is loaded")]
+    FamilyNotFound(SharedString),
+    /// The requested family has no font matching the requested weight and style.
+    #[error("font family {0:?} contains no font matching the other parameters")]
+    NoMatchingFace(SharedString),
+}
+
+/// A line drawn along text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TextDecoration {
+    Underline,
+    Strikethrough,
+    Overline,
+}
+
+/// How much glyph rasterization work the text system has done, for profiling text rendering.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RasterStats {
+    /// How many glyph bitmaps were rasterized.
+    pub glyphs_rasterized: u64,
+    /// How many glyph images were already rendered when their bounds or bitmap were requested.
+    pub cache_hits: u64,
+    /// How many glyph images had to be rendered when their bounds or bitmap were requested.
+    pub cache_misses: u64,
+    /// The time spent looking up and rendering glyph images.
+    pub total_raster_time: Duration,
+}
+
+#[derive(Debug, Default)]
+struct RasterCounters {
+    glyphs_rasterized: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    raster_nanos: AtomicU64,
+}
+
+/// The system fonts loaded on a background thread, to be merged into the font database.
+struct SystemFonts {
+    db: fontdb::Database,
+    modified_times: HashMap<PathBuf, Option<SystemTime>>,
+    stats: SystemFontStats,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct SystemFontStats {
+    discovered: usize,
+    loaded: usize,
+    failed: usize,
+}
+
+const DEFAULT_MAX_FALLBACK_ATTEMPTS: usize = 8;
+
+const SOFT_HYPHEN: char = '\u{AD}';
+
+/// Large enough for any glyph at sensible zoom levels, while an RGBA bitmap of this size is
+/// still only 64MB.
+const DEFAULT_MAX_GLYPH_PIXELS: usize = 4096 * 4096;
+
+/// Segoe Fluent Icons ships with Windows 11, Windows 10 only has its predecessor.
+const DEFAULT_ICON_FONT_FAMILIES: &[&str] = &["Segoe Fluent Icons", "Segoe MDL2 Assets"];
+
+/// The slant, in degrees, of italics synthesized for faces that are styled as italic but don't
+/// specify an angle. Matches the shear cosmic-text applies when faking italics.
+const SYNTHETIC_ITALIC_ANGLE: f32 = -14.;
+
+/// How much thicker, as a fraction of the font size, synthesized bold makes strokes. Glyphs get
+/// wider by the same amount. Matches FreeType's emboldening.
+const SYNTHETIC_BOLD_STRENGTH: f32 = 1. / 24.;
+
+/// Glyphs are rendered at sizes rounded to this fraction of a device pixel, so that sizes that
+/// only differ by rounding errors, e.g. from fractional scale factors, share cached images.
+const RASTER_FONT_SIZE_STEP: f32 = 1. / 64.;
+
+/// The styles faked for a face because its family has no face that is bold or italic.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+struct SyntheticStyle {
+    bold: bool,
+    italic: bool,
 }
 
 impl CosmicTextSystem {
+    /// Creates a text system with the fonts installed on the system, unless the
+    /// `ZED_NO_SYSTEM_FONTS` environment variable is set.
     pub(crate) fn new() -> Self {
-        let mut font_system = FontSystem::new();
+        Self::new_with_font_path_cache(None)
+    }
 
-        // todo(linux) make font loading non-blocking
-        font_system.db_mut().load_system_fonts();
+    /// Like [`CosmicTextSystem::new`], but if a cache file is given, the font files found in the
+    /// system font directories are persisted to it, so that the directories only have to be
+    /// searched again on the next start if they changed.
+    pub(crate) fn new_with_font_path_cache(font_path_cache: Option<PathBuf>) -> Self {
+        let no_system_fonts =
+            std::env::var("ZED_NO_SYSTEM_FONTS").map_or(false, |value| !value.is_empty());
+        Self::new_with_options(!no_system_fonts, font_path_cache)
+    }
+
+    /// Creates a text system that, unless `include_system_fonts` is set, only knows about the fonts
+    /// passed to `add_fonts`, so that it behaves the same on every machine, e.g. in tests.
+    ///
+    /// System fonts are loaded on a background thread so that they don't block startup. Looking up
+    /// a family that isn't loaded yet, or laying out text its fonts don't cover, waits for them.
+    pub(crate) fn new_with_system_fonts(include_system_fonts: bool) -> Self {
+        Self::new_with_options(include_system_fonts, None)
+    }
+
+    fn new_with_options(include_system_fonts: bool, font_path_cache: Option<PathBuf>) -> Self {
+        let pending_system_fonts = include_system_fonts.then(|| {
+            let font_path_cache = font_path_cache.clone();
+            std::thread::spawn(move || {
+                let mut db = fontdb::Database::new();
+                let mut modified_times = HashMap::default();
+                let stats =
+                    load_system_fonts(&mut db, &mut modified_times, font_path_cache.as_deref());
+                SystemFonts {
+                    db,
+                    modified_times,
+                    stats,
+                }
+            })
+        });
+        let locale = sys_locale::get_locale().unwrap_or_else(|| {
+            log::warn!("failed to get system locale, falling back to en-US");
+            String::from("en-US")
+        });
+        let font_system = FontSystem::new_with_locale_and_db(locale, fontdb::Database::new());
+        let fallback_families = default_fallback_families(font_system.locale())
+            .iter()
+            .map(|family| family.to_string())
+            .collect();
 
         Self(RwLock::new(CosmicTextSystemState {
             font_system,
             swash_cache: SwashCache::new(),
             loaded_fonts_store: Vec::new(),
+            font_ids_by_face_id: HashMap::default(),
             font_ids_by_family_cache: HashMap::default(),
             postscript_names: HashMap::default(),
+            color_font_ids: HashSet::default(),
+            font_features: HashMap::default(),
+            font_ids_by_features: HashMap::default(),
+            face_ids_by_font_data: HashMap::default(),
+            unloadable_face_ids: HashSet::default(),
+            pinned_families: HashSet::default(),
+            font_variations: HashMap::default(),
+            font_ids_by_instance: HashMap::default(),
+            synthetic_styles: HashMap::default(),
+            font_ids_by_synthetic_style: HashMap::default(),
+            scale_context: ScaleContext::new(),
+            instance_images: HashMap::default(),
+            pending_system_fonts,
+            max_fallback_attempts: DEFAULT_MAX_FALLBACK_ATTEMPTS,
+            fallback_families,
+            fallback_coverage: HashMap::default(),
+            font_ids_by_full_name: HashMap::default(),
+            system_font_stats: SystemFontStats::default(),
+            system_font_modified_times: HashMap::default(),
+            font_path_cache,
+            font_sources: Vec::new(),
+            color_glyphs_enabled: true,
+            notdef_glyphs_enabled: true,
+            icon_font_families: DEFAULT_ICON_FONT_FAMILIES
+                .iter()
+                .map(|family| family.to_string())
+                .collect(),
+            missing_glyph_advance: MissingGlyphAdvance::default(),
+            max_glyph_pixels: DEFAULT_MAX_GLYPH_PIXELS,
+            normalize_fallback_metrics: true,
+            glyph_padding: 0,
+            fallback_overrides: Vec::new(),
+            raster_counters: RasterCounters::default(),
+            advance_cache: Mutex::default(),
+            control_character_display: ControlCharacterDisplay::default(),
+            normalize_to_nfc: false,
+            emoji_scale: 1.,
         }))
     }
-}
 
-impl Default for CosmicTextSystem {
-    fn default() -> Self {
-        Self::new()
+    /// Blocks until any system fonts being loaded in the background have been added to the
+    /// font database. Returns immediately if loading already finished or was never started.
+    pub(crate) fn wait_for_fonts_loaded(&self) {
+        self.0.write().finish_loading_system_fonts();
     }
-}
 
-impl PlatformTextSystem for CosmicTextSystem {
-    fn add_fonts(&self, fonts: Vec<Cow<'static, [u8]>>) -> Result<()> {
-        self.0.write().add_fonts(fonts)
+    /// Registers a source that is asked for the data of families that aren't otherwise available,
+    /// the first time one of them is requested.
+    pub(crate) fn register_font_source(&self, source: Box<dyn FontSource>) {
+        self.0.write().font_sources.push(source);
     }
 
-    // todo(linux) ensure that this integrates with platform font loading
-    // do we need to do more than call load_system_fonts()?
-    fn all_font_names(&self) -> Vec<String> {
-        self.0
-            .read()
-            .font_system
-            .db()
-            .faces()
-            .map(|face| face.post_script_name.clone())
-            .collect()
+    /// Resolves a batch of fonts under a single lock, e.g. when applying a theme. Each font's
+    /// result is reported separately.
+    pub(crate) fn font_ids(&self, fonts: &[Font]) -> Vec<Result<FontId>> {
+        let mut state = self.0.write();
+        fonts.iter().map(|font| state.font_id(font)).collect()
     }
 
-    fn all_font_families(&self) -> Vec<String> {
-        self.0
-            .read()
-            .font_system
-            .db()
-            .faces()
-            // todo(linux) this will list the same font family multiple times
-            .filter_map(|face| face.families.first().map(|family| family.0.clone()))
-            .collect_vec()
+    /// Returns the ids of every face in the given family, covering all of its weights and styles.
+    pub(crate) fn family_font_ids(&self, family: &str) -> Vec<FontId> {
+        let family = SharedString::from(family.to_string());
+        let mut state = self.0.write();
+        state
+            .cache_family(&family, &FontFeatures::default())
+            .log_err();
+        state
+            .font_ids_by_family_cache
+            .get(&family)
+            .map_or_else(Vec::new, |font_ids| font_ids.to_vec())
     }
 
-    fn font_id(&self, font: &Font) -> Result<FontId> {
-        // todo(linux): Do we need to use CosmicText's Font APIs? Can we consolidate this to use font_kit?
+    /// Returns the ids of every face whose PostScript name is the given prefix or starts with it
+    /// followed by a hyphen, ignoring case, e.g. `Roboto-Regular` and `Roboto-Bold` for `Roboto`
+    /// but not `RobotoMono-Regular`. PostScript names group faces differently from family names,
+    /// which some tools rely on.
+    pub(crate) fn faces_by_postscript_prefix(&self, prefix: &str) -> Vec<FontId> {
+        let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        state.faces_by_postscript_prefix(prefix)
+    }
+
+    /// Returns the id of the face with exactly the given PostScript name, which documents and
+    /// config files often use to reference fonts. Unlike family names, PostScript names are
+    /// case-sensitive.
+    pub(crate) fn font_id_for_postscript_name(&self, name: &str) -> Option<FontId> {
         let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        state.font_id_for_postscript_name(name)
+    }
 
-        let candidates = if let Some(font_ids) = state.font_ids_by_family_cache.get(&font.family) {
-            font_ids.as_slice()
-        } else {
-            let font_ids = state.load_family(&font.family, &font.features)?;
+    /// Sets the icon font families in order of preference. Requests for any of them resolve to the
+    /// first one that is installed.
+    pub(crate) fn set_icon_font_families(&self, families: Vec<String>) {
+        let state = &mut *self.0.write();
+        for family in state.icon_font_families.iter().chain(&families) {
             state
                 .font_ids_by_family_cache
-                .insert(font.family.clone(), font_ids);
-            state.font_ids_by_family_cache[&font.family].as_ref()
-        };
-
-        // todo(linux) ideally we would make fontdb's `find_best_match` pub instead of using font-kit here
-        let candidate_properties = candidates
-            .iter()
-            .map(|font_id| {
-                let database_id = state.loaded_fonts_store[font_id.0].id();
-                let face_info = state.font_system.db().face(database_id).expect("");
-                face_info_into_properties(face_info)
-            })
-            .collect::<SmallVec<[_; 4]>>();
-
-        let ix =
-            font_kit::matching::find_best_match(&candidate_properties, &font_into_properties(font))
-                .context("requested font family contains no font matching the other parameters")?;
+                .remove(&SharedString::from(family.clone()));
+        }
+        state.icon_font_families = families;
+    }
 
-        Ok(candidates[ix])
+    /// Pins a family, so that its faces are never unloaded, including faces of the family that
+    /// are added later, e.g. to keep the UI font available. The icon font families are pinned by
+    /// default.
+    pub(crate) fn pin_family(&self, family: impl Into<String>) {
+        self.0.write().pinned_families.insert(family.into());
     }
 
-    fn font_metrics(&self, font_id: FontId) -> FontMetrics {
-        let metrics = self.0.read().loaded_fonts_store[font_id.0]
-            .as_swash()
-            .metrics(&[]);
+    /// Returns the installed icon font family that requests for icon fonts resolve to, if any.
+    pub(crate) fn icon_font_family(&self) -> Option<String> {
+        let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        state.icon_font_family().map(str::to_string)
+    }
 
-        FontMetrics {
-            units_per_em: metrics.units_per_em as u32,
-            ascent: metrics.ascent,
-            descent: -metrics.descent, // todo(linux) confirm this is correct
-            line_gap: metrics.leading,
-            underline_position: metrics.underline_offset,
-            underline_thickness: metrics.stroke_size,
-            cap_height: metrics.cap_height,
-            x_height: metrics.x_height,
-            // todo(linux): Compute this correctly
-            bounding_box: Bounds {
-                origin: point(0.0, 0.0),
-                size: size(metrics.max_width, metrics.ascent + metrics.descent),
-            },
+    /// Returns whether the two fonts render identically: they resolve to the same face with the
+    /// same features, and neither needs emboldening or slanting that the other doesn't. Lets
+    /// caches skip invalidation when a font "change" is a no-op.
+    pub(crate) fn fonts_equivalent(&self, a: &Font, b: &Font) -> bool {
+        if a == b {
+            return true;
         }
+        let (Some(a_id), Some(b_id)) = (self.font_id(a).ok(), self.font_id(b).ok()) else {
+            return false;
+        };
+        let state = self.0.read();
+        a_id == b_id
+            && a.features == b.features
+            && state.synthetic_style(a, a_id) == state.synthetic_style(b, b_id)
     }
 
-    fn typographic_bounds(&self, font_id: FontId, glyph_id: GlyphId) -> Result<Bounds<f32>> {
-        let lock = self.0.read();
-        let glyph_metrics = lock.loaded_fonts_store[font_id.0]
-            .as_swash()
-            .glyph_metrics(&[]);
-        let glyph_id = glyph_id.0 as u16;
-        // todo(linux): Compute this correctly
-        // see https://github.com/servo/font-kit/blob/master/src/loaders/freetype.rs#L614-L620
-        Ok(Bounds {
-            origin: point(0.0, 0.0),
-            size: size(
-                glyph_metrics.advance_width(glyph_id),
-                glyph_metrics.advance_height(glyph_id),
-            ),
-        })
+    /// Returns whether the font's GSUB or GPOS table has rules for the given OpenType script tag,
+    /// such as "arab", and language system tag, such as "URD", if one is given. A font that covers
+    /// a script's characters but has no rules for it can't shape text in that script properly.
+    pub(crate) fn supports_shaping_for(
+        &self,
+        font_id: FontId,
+        script_tag: &str,
+        language_tag: Option<&str>,
+    ) -> bool {
+        self.0
+            .read()
+            .supports_shaping_for(font_id, script_tag, language_tag)
     }
 
-    fn advance(&self, font_id: FontId, glyph_id: GlyphId) -> Result<Size<f32>> {
-        self.0.read().advance(font_id, glyph_id)
+    /// Returns whether the font has a glyph for the given character. Fonts without a character
+    /// map, such as some symbol fonts, never cover any characters and can only be used to render
+    /// glyphs by id.
+    pub(crate) fn can_render(&self, font_id: FontId, ch: char) -> bool {
+        self.0.read().glyph_for_char(font_id, ch).is_some()
     }
 
-    fn glyph_for_char(&self, font_id: FontId, ch: char) -> Option<GlyphId> {
-        self.0.read().glyph_for_char(font_id, ch)
+    /// Returns whether the font's glyphs are rasterized in color, i.e. it has color glyph tables.
+    pub(crate) fn is_emoji(&self, font_id: FontId) -> bool {
+        self.0.read().is_emoji(font_id)
     }
 
-    fn glyph_raster_bounds(&self, params: &RenderGlyphParams) -> Result<Bounds<DevicePixels>> {
-        self.0.write().raster_bounds(params)
+    /// Returns whether any font in the database has color glyphs, i.e. a COLR, CBDT, sbix or SVG
+    /// table, so that emoji can render in color rather than falling back to monochrome outlines.
+    pub(crate) fn has_color_emoji_font(&self) -> bool {
+        let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        state.has_color_emoji_font()
     }
 
-    fn rasterize_glyph(
-        &self,
-        params: &RenderGlyphParams,
-        raster_bounds: Bounds<DevicePixels>,
-    ) -> Result<(Size<DevicePixels>, Vec<u8>)> {
-        self.0.write().rasterize_glyph(params, raster_bounds)
+    /// Looks up a font by its full name (e.g. "Helvetica Neue Bold Condensed") rather than by
+    /// family and attributes. The name is compared case-insensitively in any of the languages the
+    /// font provides it in.
+    pub(crate) fn font_id_by_full_name(&self, full_name: &str) -> Option<FontId> {
+        let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        state.font_id_by_full_name(full_name)
     }
 
-    fn layout_line(&self, text: &str, font_size: Pixels, runs: &[FontRun]) -> LineLayout {
-        self.0.write().layout_line(text, font_size, runs)
+    /// Returns how many system font files were discovered, how many of those were loaded, and how
+    /// many failed to load, either because they couldn't be read or contained no usable faces.
+    pub(crate) fn system_font_stats(&self) -> (usize, usize, usize) {
+        let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        let stats = state.system_font_stats;
+        (stats.discovered, stats.loaded, stats.failed)
     }
-}
 
-impl CosmicTextSystemState {
-    #[profiling::function]
-    fn add_fonts(&mut self, fonts: Vec<Cow<'static, [u8]>>) -> Result<()> {
-        let db = self.font_system.db_mut();
-        for bytes in fonts {
-            match bytes {
-                Cow::Borrowed(embedded_font) => {
-                    db.load_font_data(embedded_font.to_vec());
-                }
-                Cow::Owned(bytes) => {
-                    db.load_font_data(bytes);
-                }
-            }
-        }
-        Ok(())
+    /// Enumerates the system fonts again, reloading files that were added, removed or modified
+    /// since they were loaded, so fonts that were updated in place stop rendering stale faces.
+    /// Returns how many files changed.
+    pub(crate) fn reload_changed_system_fonts(&self) -> usize {
+        self.0.write().reload_changed_system_fonts()
     }
 
-    // todo(linux) handle `FontFeatures`
-    #[profiling::function]
-    fn load_family(
-        &mut self,
-        name: &str,
-        _features: &FontFeatures,
-    ) -> Result<SmallVec<[FontId; 4]>> {
-        // TODO: Determine the proper system UI font.
-        let name = if name == ".SystemUIFont" {
-            "Zed Sans"
-        } else {
-            name
-        };
+    /// Returns the font's revision from its `head` table as a 16.16 fixed-point number, for
+    /// diagnosing which version of a font is in use.
+    pub(crate) fn font_version(&self, font_id: FontId) -> Option<u32> {
+        self.0.read().font_version(font_id)
+    }
 
-        let mut font_ids = SmallVec::new();
-        let families = self
+    /// Returns whether the font's glyphs all have the same advance, as declared by the font, e.g.
+    /// to only offer fonts suited for a terminal.
+    pub(crate) fn is_monospace(&self, font_id: FontId) -> bool {
+        let state = self.0.read();
+        state.try_loaded_font(font_id).map_or(false, |font| {
+            state
+                .font_system
+                .db()
+                .face(font.id())
+                .map_or(false, |face| face.monospaced)
+        })
+    }
+
+    /// Returns the families of [`PlatformTextSystem::all_font_families`] that have a monospaced
+    /// face.
+    pub(crate) fn monospace_font_families(&self) -> Vec<String> {
+        let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        let families = state
             .font_system
             .db()
             .faces()
-            .filter(|face| face.families.iter().any(|family| *name == family.0))
-            .map(|face| (face.id, face.post_script_name.clone()))
-            .collect::<SmallVec<[_; 4]>>();
+            .filter(|face| face.monospaced)
+            .filter_map(|face| face.families.first().map(|family| family.0.clone()))
+            .collect_vec();
+        sorted_font_names(families)
+    }
 
-        for (font_id, postscript_name) in families {
-            let font = self
-                .font_system
-                .get_font(font_id)
-                .ok_or_else(|| anyhow!("Could not load font"))?;
+    /// Returns the path of the file the font was loaded from, or `None` if it was loaded from
+    /// memory, e.g. an embedded font.
+    pub(crate) fn font_path(&self, font_id: FontId) -> Option<PathBuf> {
+        self.0.read().font_path(font_id)
+    }
 
-            // HACK: To let the storybook run and render Windows caption icons. We should actually do better font fallback.
-            let allowed_bad_font_names = [
-                "SegoeFluentIcons", // NOTE: Segoe fluent icons postscript name is inconsistent
-                "Segoe Fluent Icons",
-            ];
+    /// Sets the advance reported for glyph ids that are out of range for their font.
+    pub(crate) fn set_missing_glyph_advance(&self, missing_glyph_advance: MissingGlyphAdvance) {
+        let mut state = self.0.write();
+        state.missing_glyph_advance = missing_glyph_advance;
+    }
 
-            if font.as_swash().charmap().map('m') == 0
-                && !allowed_bad_font_names.contains(&postscript_name.as_str())
-            {
-                self.font_system.db_mut().remove_face(font.id());
-                continue;
-            };
+    /// Sets whether the ascent and descent of laid out lines only account for the fonts of their
+    /// runs. This is the default, and keeps lines containing fallback text with very different
+    /// metrics, such as CJK characters in a Latin font, from growing taller than other lines.
+    /// Fallback glyphs are always placed on the baseline of the surrounding text.
+    pub(crate) fn set_normalize_fallback_metrics(&self, normalize_fallback_metrics: bool) {
+        let mut state = self.0.write();
+        state.normalize_fallback_metrics = normalize_fallback_metrics;
+    }
 
-            let font_id = FontId(self.loaded_fonts_store.len());
-            font_ids.push(font_id);
-            self.loaded_fonts_store.push(font);
-            self.postscript_names.insert(font_id, postscript_name);
-        }
+    /// Sets how much larger than the surrounding text emoji are rendered, e.g. `1.1` to make them
+    /// 10% larger, so they can be tuned to optically match the UI font. Text glyphs aren't
+    /// affected. Advances of emoji glyphs are scaled too, and text following a scaled emoji is
+    /// moved over to make room for it. Defaults to 1.
+    pub(crate) fn set_emoji_scale(&self, scale: f32) {
+        let mut state = self.0.write();
+        state.emoji_scale = scale;
+    }
 
-        Ok(font_ids)
+    /// Sets whether text is normalized to NFC before it's shaped, so that text renders the same
+    /// whether its accents are precomposed or decomposed. Glyph indices in the resulting layouts
+    /// still refer to the text as it was passed in: glyphs of characters that were composed
+    /// together point to the start of the grapheme cluster they came from. Off by default.
+    pub(crate) fn set_normalize_to_nfc(&self, normalize_to_nfc: bool) {
+        let mut state = self.0.write();
+        state.normalize_to_nfc = normalize_to_nfc;
     }
 
-    fn advance(&self, font_id: FontId, glyph_id: GlyphId) -> Result<Size<f32>> {
-        let width = self.loaded_fonts_store[font_id.0]
-            .as_swash()
-            .glyph_metrics(&[])
-            .advance_width(glyph_id.0 as u16);
-        let height = self.loaded_fonts_store[font_id.0]
-            .as_swash()
-            .glyph_metrics(&[])
-            .advance_height(glyph_id.0 as u16);
-        Ok(Size { width, height })
+    /// Sets how control characters other than tabs and line breaks are laid out, rather than
+    /// leaving it to the font. They're hidden by default.
+    pub(crate) fn set_control_character_display(&self, display: ControlCharacterDisplay) {
+        let mut state = self.0.write();
+        state.control_character_display = display;
     }
 
-    fn glyph_for_char(&self, font_id: FontId, ch: char) -> Option<GlyphId> {
-        let glyph_id = self.loaded_fonts_store[font_id.0]
-            .as_swash()
-            .charmap()
-            .map(ch);
-        if glyph_id == 0 {
-            None
-        } else {
-            Some(GlyphId(glyph_id.into()))
+    /// Sets how many transparent pixels are added around every glyph bitmap, so that glyphs packed
+    /// into a texture atlas don't bleed into each other when sampled with filtering. Glyph raster
+    /// bounds grow by the padding on every side, with their origin moved up and to the left by
+    /// it, so glyphs are still drawn in the same place. Defaults to no padding.
+    pub(crate) fn set_glyph_padding(&self, padding: u32) {
+        self.0.write().glyph_padding = padding;
+    }
+
+    /// Returns how much glyph rasterization work has been done since the text system was created.
+    pub(crate) fn raster_stats(&self) -> RasterStats {
+        let counters = &self.0.read().raster_counters;
+        RasterStats {
+            glyphs_rasterized: counters.glyphs_rasterized.load(Ordering::Relaxed),
+            cache_hits: counters.cache_hits.load(Ordering::Relaxed),
+            cache_misses: counters.cache_misses.load(Ordering::Relaxed),
+            total_raster_time: Duration::from_nanos(counters.raster_nanos.load(Ordering::Relaxed)),
         }
     }
 
-    fn is_emoji(&self, font_id: FontId) -> bool {
-        // TODO: Include other common emoji fonts
-        self.postscript_names
-            .get(&font_id)
-            .map_or(false, |postscript_name| postscript_name == "NotoColorEmoji")
+    /// Makes the given family the preferred fallback for characters that the font of their run
+    /// doesn't cover, until it's popped again, e.g. to prefer a specific CJK font while laying out
+    /// a terminal. Characters that the run's font covers are unaffected.
+    pub(crate) fn push_fallback_override(&self, family: impl Into<SharedString>) {
+        let mut state = self.0.write();
+        state.fallback_overrides.push(family.into());
     }
 
-    fn raster_bounds(&mut self, params: &RenderGlyphParams) -> Result<Bounds<DevicePixels>> {
-        let font = &self.loaded_fonts_store[params.font_id.0];
-        let font_system = &mut self.font_system;
-        let image = self
-            .swash_cache
-            .get_image(
-                font_system,
-                CacheKey::new(
-                    font.id(),
-                    params.glyph_id.0 as u16,
-                    (params.font_size * params.scale_factor).into(),
-                    (0.0, 0.0),
-                    cosmic_text::CacheKeyFlags::empty(),
-                )
-                .0,
-            )
-            .clone()
-            .unwrap();
-        Ok(Bounds {
-            origin: point(image.placement.left.into(), (-image.placement.top).into()),
-            size: size(image.placement.width.into(), image.placement.height.into()),
-        })
+    /// Removes the most recently pushed fallback override, returning its family.
+    pub(crate) fn pop_fallback_override(&self) -> Option<SharedString> {
+        let mut state = self.0.write();
+        state.fallback_overrides.pop()
     }
 
-    #[profiling::function]
-    fn rasterize_glyph(
-        &mut self,
-        params: &RenderGlyphParams,
-        glyph_bounds: Bounds<DevicePixels>,
-    ) -> Result<(Size<DevicePixels>, Vec<u8>)> {
-        if glyph_bounds.size.width.0 == 0 || glyph_bounds.size.height.0 == 0 {
-            Err(anyhow!("glyph bounds are empty"))
-        } else {
-            // todo(linux) handle subpixel variants
-            let bitmap_size = glyph_bounds.size;
-            let font = &self.loaded_fonts_store[params.font_id.0];
-            let font_system = &mut self.font_system;
-            let image = self
-                .swash_cache
-                .get_image(
-                    font_system,
-                    CacheKey::new(
-                        font.id(),
-                        params.glyph_id.0 as u16,
-                        (params.font_size * params.scale_factor).into(),
-                        (0.0, 0.0),
-                        cosmic_text::CacheKeyFlags::empty(),
-                    )
-                    .0,
-                )
-                .clone()
-                .unwrap();
+    /// Sets the largest glyph bitmap, in pixels, that will be rasterized. Larger glyphs, e.g. from
+    /// broken fonts or extreme zoom levels, fail to render instead of allocating huge bitmaps.
+    pub(crate) fn set_max_glyph_pixels(&self, max_glyph_pixels: usize) {
+        self.0.write().max_glyph_pixels = max_glyph_pixels;
+    }
 
-            Ok((bitmap_size, image.data))
-        }
+    /// Sets whether color glyphs such as emoji are rendered in color. When disabled, they're
+    /// rendered as monochrome silhouettes in the text color, e.g. for printing or high-contrast
+    /// themes. Lines that were laid out before the change keep their rendering until they're laid
+    /// out again.
+    pub(crate) fn set_color_glyphs_enabled(&self, enabled: bool) {
+        let mut state = self.0.write();
+        state.color_glyphs_enabled = enabled;
     }
 
-    fn font_id_for_cosmic_id(&mut self, id: cosmic_text::fontdb::ID) -> FontId {
-        if let Some(ix) = self
-            .loaded_fonts_store
-            .iter()
-            .position(|font| font.id() == id)
-        {
-            FontId(ix)
-        } else {
-            // This matches the behavior of the mac text system
-            let font = self.font_system.get_font(id).unwrap();
-            let face = self
-                .font_system
-                .db()
-                .faces()
-                .find(|info| info.id == id)
-                .unwrap();
+    /// Sets whether characters that no font covers are rendered as the notdef glyph of their run's
+    /// font, usually a box, so that missing glyphs are visible. When disabled, such characters are
+    /// left out of the layout and take up no space. Enabled by default.
+    pub(crate) fn set_notdef_glyphs_enabled(&self, enabled: bool) {
+        let mut state = self.0.write();
+        state.notdef_glyphs_enabled = enabled;
+    }
 
-            let font_id = FontId(self.loaded_fonts_store.len());
-            self.loaded_fonts_store.push(font);
-            self.postscript_names
-                .insert(font_id, face.post_script_name.clone());
+    /// Returns the families that are tried first when looking for a font covering a character that
+    /// is missing from its run's font, in order of preference.
+    pub(crate) fn fallback_fonts(&self) -> Vec<String> {
+        self.0.read().fallback_families.clone()
+    }
 
-            font_id
+    /// Sets the families that are tried first when looking for a font covering a character that
+    /// is missing from its run's font, replacing the defaults for the system locale. Families
+    /// that aren't installed are skipped.
+    pub(crate) fn set_fallback_fonts(&self, families: Vec<String>) {
+        let mut state = self.0.write();
+        state.fallback_families = families;
+        state.fallback_coverage.clear();
+    }
+
+    /// Sets how many fonts, besides the fallback families and the fonts for its script, are checked
+    /// for a character that is missing from its run's font before it is rendered as a notdef
+    /// glyph, bounding the cost of laying out uncoverable text.
+    pub(crate) fn set_max_fallback_attempts(&self, max_fallback_attempts: usize) {
+        let mut state = self.0.write();
+        state.max_fallback_attempts = max_fallback_attempts;
+        state.fallback_coverage.clear();
+    }
+
+    /// Returns the full name of the font as it should be displayed to users of the given locale
+    /// (a BCP 47 tag such as "ja" or "zh-TW"), falling back to its English name.
+    pub(crate) fn font_display_name(&self, font_id: FontId, locale: &str) -> Option<String> {
+        self.0.read().font_display_name(font_id, locale)
+    }
+
+    /// Lays out a line like [`PlatformTextSystem::layout_line`], but with the given shaping level.
+    ///
+    /// [`Shaping::Advanced`] is what `layout_line` uses, and is always correct. [`Shaping::Basic`]
+    /// maps characters straight to glyphs without running the font's shaping rules or falling
+    /// back to other fonts, which is considerably cheaper. It is only safe for text that is known
+    /// to be simple (e.g. Latin UI labels) in a font that covers every character, since it drops
+    /// ligatures, contextual forms, combining marks and complex scripts.
+    pub(crate) fn layout_line_with_shaping(
+        &self,
+        text: &str,
+        font_size: Pixels,
+        runs: &[FontRun],
+        shaping: Shaping,
+    ) -> LineLayout {
+        self.0.write().layout_line(text, font_size, runs, shaping)
+    }
+
+    /// Returns the width of the text, e.g. for sizing columns. The text is only shaped, summing the
+    /// advances of its glyphs, which is cheaper than laying it out with
+    /// [`PlatformTextSystem::layout_line`] but doesn't account for adjustments made while laying
+    /// out, such as the overflow of scaled emoji.
+    pub(crate) fn measure_width(&self, text: &str, font_size: Pixels, runs: &[FontRun]) -> Pixels {
+        self.0.write().measure_width(text, font_size, runs)
+    }
+
+    /// Lays out a line like [`PlatformTextSystem::layout_line`], with `letter_spacing` added
+    /// between each pair of adjacent grapheme clusters, e.g. to track out headings. No space is
+    /// added after the last cluster, and glyphs of the same cluster, such as combining marks, stay
+    /// together. Negative spacing moves glyphs closer.
+    pub(crate) fn layout_line_with_letter_spacing(
+        &self,
+        text: &str,
+        font_size: Pixels,
+        runs: &[FontRun],
+        letter_spacing: Pixels,
+    ) -> LineLayout {
+        let mut layout = self
+            .0
+            .write()
+            .layout_line(text, font_size, runs, Shaping::Advanced);
+        let mut spacing = px(0.);
+        let mut previous_index = None;
+        for glyph in layout.runs.iter_mut().flat_map(|run| run.glyphs.iter_mut()) {
+            if previous_index.map_or(false, |index| index != glyph.index) {
+                spacing += letter_spacing;
+            }
+            previous_index = Some(glyph.index);
+            glyph.position.x += spacing;
         }
+        layout.width += spacing;
+        layout
     }
 
-    // todo(linux) This is all a quick first pass, maybe we should be using cosmic_text::Buffer
-    #[profiling::function]
-    fn layout_line(&mut self, text: &str, font_size: Pixels, font_runs: &[FontRun]) -> LineLayout {
-        let mut attrs_list = AttrsList::new(Attrs::new());
-        let mut offs = 0;
-        for run in font_runs {
-            // todo(linux) We need to check we are doing utf properly
-            let font = &self.loaded_fonts_store[run.font_id.0];
-            let font = self.font_system.db().face(font.id()).unwrap();
-            attrs_list.add_span(
-                offs..(offs + run.len),
-                Attrs::new()
-                    .family(Family::Name(&font.families.first().unwrap().0))
-                    .stretch(font.stretch)
-                    .style(font.style)
-                    .weight(font.weight),
-            );
-            offs += run.len;
-        }
-        let mut line = BufferLine::new(text, attrs_list, cosmic_text::Shaping::Advanced);
+    /// Returns the byte offsets at which lines start when wrapping the text to the given width in
+    /// the given font, like [`LineWrapper::wrap_line`] but measured with the same layout that
+    /// `layout_line` produces, so that it accounts for shaping and fallback fonts. Lines can also
+    /// break after soft hyphens.
+    ///
+    /// [`LineWrapper::wrap_line`]: crate::LineWrapper::wrap_line
+    pub(crate) fn wrap_line(
+        &self,
+        text: &str,
+        font_id: FontId,
+        font_size: Pixels,
+        width: Pixels,
+    ) -> Vec<usize> {
+        let runs = [FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        }];
+        self.0.write().wrap_line(text, font_size, &runs, width)
+    }
 
-        let layout = line.layout(
-            &mut self.font_system,
-            font_size.0,
-            f32::MAX, // We do our own wrapping
-            cosmic_text::Wrap::None,
-            None,
-        );
-        let mut runs = Vec::new();
+    /// Wraps text to the given width and lays out each line, stopping after `max_lines`. If text
+    /// remains, the last line is cut short and ends in an ellipsis instead, e.g. for clamped
+    /// descriptions. Like [`LineWrapper::wrap_line`], lines break after whitespace, and words that
+    /// are wider than a line on their own are broken wherever they overflow. Lines also break after
+    /// soft hyphens, which are drawn as hyphens when a line breaks at them and are invisible
+    /// anywhere else. Every line holds at least one character, even if that doesn't fit.
+    ///
+    /// [`LineWrapper::wrap_line`]: crate::LineWrapper::wrap_line
+    pub(crate) fn layout_wrapped(
+        &self,
+        text: &str,
+        font_size: Pixels,
+        runs: &[FontRun],
+        width: Pixels,
+        max_lines: usize,
+    ) -> Vec<LineLayout> {
+        self.0
+            .write()
+            .layout_wrapped(text, font_size, runs, width, max_lines)
+    }
 
-        let layout = layout.first().unwrap();
-        for glyph in &layout.glyphs {
-            let font_id = glyph.font_id;
-            let font_id = self.font_id_for_cosmic_id(font_id);
-            let mut glyphs = SmallVec::new();
-            // todo(linux) this is definitely wrong, each glyph in glyphs from cosmic-text is a cluster with one glyph, ShapedRun takes a run of glyphs with the same font and direction
-            glyphs.push(ShapedGlyph {
-                id: GlyphId(glyph.glyph_id as u32),
-                position: point((glyph.x).into(), glyph.y.into()),
-                index: glyph.start,
-                is_emoji: self.is_emoji(font_id),
+    /// Returns whether the font joins the characters into fewer glyphs than there are grapheme
+    /// clusters, e.g. for `=>` in fonts with programming ligatures, so that carets can avoid
+    /// landing inside them. The characters are shaped with the same features `layout_line` uses.
+    /// Fonts that draw ligatures by substituting each character with a part of the ligature keep
+    /// one glyph per character, and aren't detected.
+    pub(crate) fn forms_ligature(&self, font_id: FontId, chars: &[char]) -> bool {
+        self.0.write().forms_ligature(font_id, chars)
+    }
+
+    /// Returns the angle of the font's italic slant in degrees counter-clockwise from vertical,
+    /// so right-leaning italics are negative and upright fonts are 0. Returns `None` if the font
+    /// was unloaded.
+    pub(crate) fn italic_angle(&self, font_id: FontId) -> Option<f32> {
+        self.0.read().italic_angle(font_id)
+    }
+
+    /// Returns how a text caret should be slanted for the font, from the font's own caret slope
+    /// when it declares one, then its italic angle, and otherwise upright. Returns `None` if the
+    /// font was unloaded.
+    pub(crate) fn caret_metrics(&self, font_id: FontId) -> Option<CaretMetrics> {
+        self.0.read().caret_metrics(font_id)
+    }
+
+    /// Adds the given font files like `add_fonts` and returns the ids of their faces. Unless
+    /// `deduplicate` is false, data that was added before isn't added again, and the ids of the
+    /// faces that were loaded from it are returned instead, so that registering the same fonts
+    /// repeatedly doesn't produce duplicate faces.
+    pub(crate) fn add_fonts_with_ids(
+        &self,
+        fonts: Vec<Cow<'static, [u8]>>,
+        deduplicate: bool,
+    ) -> Result<Vec<FontId>> {
+        let mut state = self.0.write();
+        let face_ids = state.add_fonts(fonts, deduplicate, true)?;
+        Ok(face_ids
+            .into_iter()
+            .filter_map(|face_id| state.font_id_for_cosmic_id(face_id))
+            .collect())
+    }
+
+    /// Unloads fonts that were added with `add_fonts_with_ids`, removing their faces from the font
+    /// database and their rendered glyphs from the glyph cache. Fonts derived from the same faces,
+    /// e.g. with other features, are unloaded too, and can't be used anymore. The ids of other
+    /// fonts stay valid. Fails without unloading anything if any of the fonts
+    /// weren't added with `add_fonts_with_ids`, e.g. bundled or system fonts, or belong to a family
+    /// pinned with `pin_family`. Fonts that were already unloaded are skipped.
+    pub(crate) fn unload_fonts(&self, font_ids: &[FontId]) -> Result<()> {
+        self.0.write().unload_fonts(font_ids)
+    }
+
+    /// Returns a font id for the instance of a variable font at the given axis coordinates, e.g.
+    /// `wght` at 450, which layout and rasterization honor. Axes that aren't given keep their
+    /// coordinates from the given font. Registering the same coordinates again returns the same id.
+    /// Fails if the font was unloaded.
+    pub(crate) fn register_instance(
+        &self,
+        font_id: FontId,
+        coords: &[(ttf_parser::Tag, f32)],
+    ) -> Result<FontId> {
+        let mut state = self.0.write();
+        state.try_loaded_font(font_id)?;
+        Ok(state.register_instance(font_id, coords))
+    }
+
+    /// Returns a font that resolves back to the given id, e.g. to persist the font that was actually
+    /// used in settings.
+    pub(crate) fn font_for_id(&self, font_id: FontId) -> Option<Font> {
+        let state = self.0.read();
+        let database_id = state.loaded_fonts_store.get(font_id.0)?.as_ref()?.id();
+        let face = state.font_system.db().face(database_id)?;
+        Some(Font {
+            family: face.families.first()?.0.clone().into(),
+            features: FontFeatures::default(),
+            weight: FontWeight(face.weight.0.into()),
+            style: match face.style {
+                cosmic_text::Style::Normal => FontStyle::Normal,
+                cosmic_text::Style::Italic => FontStyle::Italic,
+                cosmic_text::Style::Oblique => FontStyle::Oblique,
+            },
+            stretch: face.stretch.into(),
+            variations: state
+                .font_variations
+                .get(&font_id)
+                .into_iter()
+                .flatten()
+                .map(|variation| FontVariation {
+                    tag: variation.tag.to_bytes(),
+                    value: variation.value,
+                })
+                .collect(),
+        })
+    }
+
+    /// Describes which font each part of a laid out line was rendered with, e.g.
+    /// `"6..10: Noto Color Emoji (NotoColorEmoji)"`, for debugging unexpected font substitution.
+    /// Consecutive runs in the same font are merged.
+    pub(crate) fn describe_layout_fonts(&self, layout: &LineLayout) -> Vec<String> {
+        let state = self.0.read();
+        let runs = layout
+            .runs
+            .iter()
+            .filter_map(|run| Some((run.font_id, run.glyphs.first()?.index)))
+            .dedup_by(|(font_a, _), (font_b, _)| font_a == font_b)
+            .collect::<Vec<_>>();
+        runs.iter()
+            .enumerate()
+            .map(|(ix, (font_id, start))| {
+                let end = runs.get(ix + 1).map_or(layout.len, |(_, end)| *end);
+                let family = state
+                    .try_loaded_font(*font_id)
+                    .ok()
+                    .and_then(|font| state.font_system.db().face(font.id()))
+                    .and_then(|face| Some(face.families.first()?.0.as_str()))
+                    .unwrap_or("unknown family");
+                let postscript_name = state
+                    .postscript_names
+                    .get(font_id)
+                    .map_or("unknown", String::as_str);
+                format!("{start}..{end}: {family} ({postscript_name})")
+            })
+            .collect()
+    }
+
+    /// Returns the visual extent of a run of a laid out line: the union of the ink of its glyphs,
+    /// relative to the start of the line on the baseline, with y increasing downwards. Unlike the
+    /// advances of the glyphs, this includes ink that overhangs them, e.g. in italic or script
+    /// fonts. Runs without any ink have empty bounds at the position of their first glyph. Returns
+    /// `None` if the line has no such run or the run's font was unloaded since the line was laid
+    /// out.
+    pub(crate) fn run_ink_bounds(
+        &self,
+        line: &LineLayout,
+        run_index: usize,
+    ) -> Option<Bounds<Pixels>> {
+        let state = self.0.read();
+        let run = line.runs.get(run_index)?;
+        let font_size = run.font_size.unwrap_or(line.font_size);
+        let face = state.try_loaded_font(run.font_id).ok()?.rustybuzz();
+        let scale = font_size.0 / face.units_per_em() as f32;
+        let bounds = run
+            .glyphs
+            .iter()
+            .filter_map(|glyph| {
+                let ink = state.typographic_bounds(run.font_id, glyph.id).ok()?;
+                (ink.size.width > 0. && ink.size.height > 0.).then(|| Bounds {
+                    origin: point(
+                        glyph.position.x + px(ink.origin.x * scale),
+                        glyph.position.y - px((ink.origin.y + ink.size.height) * scale),
+                    ),
+                    size: size(px(ink.size.width * scale), px(ink.size.height * scale)),
+                })
+            })
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or_else(|| Bounds {
+                origin: run
+                    .glyphs
+                    .first()
+                    .map_or_else(Point::default, |glyph| glyph.position),
+                size: Size::default(),
             });
+        Some(bounds)
+    }
 
-            runs.push(crate::ShapedRun { font_id, glyphs });
+    /// Returns the grapheme boundary of a laid out line closest to the given x position, e.g. to
+    /// place the caret where the user clicked. Positions before the start of the line map to 0 and
+    /// positions past its end map to its length.
+    pub(crate) fn index_for_x(&self, layout: &LineLayout, x: Pixels) -> usize {
+        if x <= px(0.) {
+            return 0;
+        }
+        if x >= layout.width {
+            return layout.len;
         }
 
-        LineLayout {
-            font_size,
-            width: layout.w.into(),
-            ascent: layout.max_ascent.into(),
-            descent: layout.max_descent.into(),
-            runs,
-            len: text.len(),
+        caret_stops(layout)
+            .into_iter()
+            .min_by_key(|(stop_x, _)| (*stop_x - x).abs())
+            .map_or(0, |(_, index)| index)
+    }
+
+    /// Returns the x position of the caret before the grapheme cluster at the given index of a laid
+    /// out line, the inverse of [`Self::index_for_x`]. Indices inside a cluster snap to its leading
+    /// edge, and the length of the line maps to its width.
+    pub(crate) fn x_for_index(&self, layout: &LineLayout, index: usize) -> Pixels {
+        if index >= layout.len {
+            return layout.width;
         }
+        caret_stops(layout)
+            .into_iter()
+            .filter(|(_, stop_index)| *stop_index <= index)
+            .max_by_key(|(_, stop_index)| *stop_index)
+            .map_or(px(0.), |(x, _)| x)
     }
-}
 
-impl From<RectF> for Bounds<f32> {
-    fn from(rect: RectF) -> Self {
-        Bounds {
-            origin: point(rect.origin_x(), rect.origin_y()),
-            size: size(rect.width(), rect.height()),
+    /// Returns where to draw a decoration line of the given width under, through or over text in
+    /// the font, relative to the start of the text on the baseline, with y increasing downwards.
+    /// Uses the positions and thicknesses the font recommends, which OpenType measures to the top
+    /// of the line. Returns `None` if the font was unloaded.
+    pub(crate) fn decoration_rect(
+        &self,
+        font_id: FontId,
+        font_size: Pixels,
+        kind: TextDecoration,
+        width: Pixels,
+    ) -> Option<Bounds<Pixels>> {
+        self.0
+            .read()
+            .decoration_rect(font_id, font_size, kind, width)
+    }
+
+    /// Returns how far a tab at `current_x` advances to reach the next tab stop, for computing
+    /// column positions. A tab that starts exactly on a tab stop advances to the one after it.
+    /// Returns zero if tab stops are zero pixels apart, and `None` if they're measured in spaces of
+    /// a font that was unloaded.
+    pub(crate) fn tab_advance(
+        &self,
+        font_id: FontId,
+        font_size: Pixels,
+        current_x: Pixels,
+        tab_width: TabWidth,
+    ) -> Option<Pixels> {
+        let tab_width = match tab_width {
+            TabWidth::Pixels(width) => width,
+            TabWidth::Spaces(count) => {
+                let state = self.0.read();
+                let units_per_em = state
+                    .try_loaded_font(font_id)
+                    .ok()?
+                    .rustybuzz()
+                    .units_per_em() as f32;
+                let space_width = state
+                    .glyph_for_char(font_id, ' ')
+                    .and_then(|glyph_id| state.advance(font_id, glyph_id).log_err())
+                    .map_or(0., |advance| advance.width);
+                px(space_width / units_per_em * font_size.0 * count as f32)
+            }
+        };
+        if tab_width <= px(0.) {
+            return Some(px(0.));
         }
+        let next_stop = ((current_x / tab_width).floor() + 1.) * tab_width.0;
+        Some(px(next_stop) - current_x)
+    }
+
+    /// Returns the distance between the baselines of consecutive lines that the font recommends:
+    /// its ascent and descent plus its line gap. Unlike a line height, which is chosen by the
+    /// caller, this is fixed by the font. The metrics are read from the OS/2 table's typographic
+    /// metrics when the font sets `USE_TYPO_METRICS`, and from the `hhea` table otherwise, falling
+    /// back to OS/2 when `hhea` has no ascender or descender. Returns `None` if the font was
+    /// unloaded.
+    pub(crate) fn baseline_to_baseline(
+        &self,
+        font_id: FontId,
+        font_size: Pixels,
+    ) -> Option<Pixels> {
+        let state = self.0.read();
+        let face = state.try_loaded_font(font_id).ok()?.rustybuzz();
+        let units = face.ascender() as f32 - face.descender() as f32 + face.line_gap() as f32;
+        Some(px(units / face.units_per_em() as f32 * font_size.0))
+    }
+
+    /// Returns the sample text the font provides for previewing it, if any.
+    pub(crate) fn font_sample_text(&self, font_id: FontId) -> Option<String> {
+        self.0.read().font_sample_text(font_id)
+    }
+
+    /// Renders the outline of a glyph as an SVG document whose view box is the font's em square.
+    /// Returns `None` if the glyph has no outline, e.g. for whitespace or bitmap-only glyphs.
+    pub(crate) fn glyph_to_svg(&self, font_id: FontId, glyph_id: GlyphId) -> Option<String> {
+        self.0.read().glyph_to_svg(font_id, glyph_id)
     }
 }
 
-impl From<RectI> for Bounds<DevicePixels> {
-    fn from(rect: RectI) -> Self {
-        Bounds {
-            origin: point(DevicePixels(rect.origin_x()), DevicePixels(rect.origin_y())),
-            size: size(DevicePixels(rect.width()), DevicePixels(rect.height())),
-        }
+impl Default for CosmicTextSystem {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl From<Vector2I> for Size<DevicePixels> {
-    fn from(value: Vector2I) -> Self {
-        size(value.x().into(), value.y().into())
+impl PlatformTextSystem for CosmicTextSystem {
+    fn add_fonts(&self, fonts: Vec<Cow<'static, [u8]>>) -> Result<()> {
+        self.0.write().add_fonts(fonts, true, false)?;
+        Ok(())
+    }
+
+    // todo(linux) ensure that this integrates with platform font loading
+    // do we need to do more than call load_system_fonts()?
+    fn all_font_names(&self) -> Vec<String> {
+        let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        let names = state
+            .font_system
+            .db()
+            .faces()
+            .map(|face| face.post_script_name.clone())
+            .collect();
+        sorted_font_names(names)
+    }
+
+    fn all_font_families(&self) -> Vec<String> {
+        let mut state = self.0.write();
+        state.finish_loading_system_fonts();
+        let families = state
+            .font_system
+            .db()
+            .faces()
+            .filter_map(|face| face.families.first().map(|family| family.0.clone()))
+            .collect_vec();
+        sorted_font_names(families)
+    }
+
+    fn font_id(&self, font: &Font) -> Result<FontId> {
+        self.0.write().font_id(font)
+    }
+
+    fn font_metrics(&self, font_id: FontId) -> FontMetrics {
+        // Fonts that were unloaded have no extent, since this can't fail.
+        self.0
+            .read()
+            .font_metrics(font_id)
+            .log_err()
+            .unwrap_or(FontMetrics {
+                units_per_em: 1,
+                ascent: 0.,
+                descent: 0.,
+                line_gap: 0.,
+                underline_position: 0.,
+                underline_thickness: 0.,
+                cap_height: 0.,
+                x_height: 0.,
+                bounding_box: Bounds::default(),
+            })
+    }
+
+    fn typographic_bounds(&self, font_id: FontId, glyph_id: GlyphId) -> Result<Bounds<f32>> {
+        self.0.read().typographic_bounds(font_id, glyph_id)
+    }
+    fn advance(&self, font_id: FontId, glyph_id: GlyphId) -> Result<Size<f32>> {
+        self.0.read().advance(font_id, glyph_id)
+    }
+
+    fn glyph_for_char(&self, font_id: FontId, ch: char) -> Option<GlyphId> {
+        self.0.read().glyph_for_char(font_id, ch)
+    }
+
+    fn glyph_raster_bounds(&self, params: &RenderGlyphParams) -> Result<Bounds<DevicePixels>> {
+        self.0.write().raster_bounds(params)
+    }
+
+    fn rasterize_glyph(
+        &self,
+        params: &RenderGlyphParams,
+        raster_bounds: Bounds<DevicePixels>,
+    ) -> Result<(Size<DevicePixels>, Vec<u8>)> {
+        self.0.write().rasterize_glyph(params, raster_bounds)
+    }
+
+    fn layout_line(&self, text: &str, font_size: Pixels, runs: &[FontRun]) -> LineLayout {
+        self.0
+            .write()
+            .layout_line(text, font_size, runs, Shaping::Advanced)
     }
 }
 
-impl From<RectI> for Bounds<i32> {
-    fn from(rect: RectI) -> Self {
-        Bounds {
-            origin: point(rect.origin_x(), rect.origin_y()),
-            size: size(rect.width(), rect.height()),
+impl CosmicTextSystemState {
+    fn finish_loading_system_fonts(&mut self) {
+        let Some(task) = self.pending_system_fonts.take() else {
+            return;
+        };
+        let Some(system_fonts) = task.join().ok() else {
+            log::error!("background system font loading panicked");
+            return;
+        };
+        let db = self.font_system.db_mut();
+        for face in system_fonts.db.faces() {
+            db.push_face_info(face.clone());
         }
+        // Generic families may have been configured while discovering the system fonts.
+        set_generic_families(db, &generic_families(&system_fonts.db));
+        self.system_font_modified_times
+            .extend(system_fonts.modified_times);
+        self.system_font_stats = system_fonts.stats;
+        // Families that were looked up before their fonts were loaded were cached without them.
+        self.invalidate_font_caches();
     }
-}
 
-impl From<Point<u32>> for Vector2I {
-    fn from(size: Point<u32>) -> Self {
-        Vector2I::new(size.x as i32, size.y as i32)
+    fn reload_changed_system_fonts(&mut self) -> usize {
+        self.finish_loading_system_fonts();
+        let mut system_fonts = fontdb::Database::new();
+        system_fonts.load_system_fonts();
+        let paths = font_file_paths(system_fonts.faces());
+        if let Some(cache_file) = &self.font_path_cache {
+            write_font_path_cache(cache_file, paths.clone(), generic_families(&system_fonts));
+        }
+        let mut changed_paths = paths
+            .iter()
+            .map(|path| (path, file_modified_time(path)))
+            .filter(|(path, modified)| self.system_font_modified_times.get(*path) != Some(modified))
+            .map(|(path, modified)| (path.clone(), Some(modified)))
+            .collect::<Vec<_>>();
+        changed_paths.extend(
+            self.system_font_modified_times
+                .keys()
+                .filter(|path| paths.binary_search(path).is_err())
+                .map(|path| (path.clone(), None)),
+        );
+        if changed_paths.is_empty() {
+            return 0;
+        }
+
+        let db = self.font_system.db_mut();
+        let mut stale_face_ids = HashSet::default();
+        for (path, modified) in &changed_paths {
+            stale_face_ids.extend(
+                db.faces()
+                    .filter(|face| face_path(face) == Some(path.as_path()))
+                    .map(|face| face.id),
+            );
+            match modified {
+                Some(modified) => {
+                    for face in system_fonts
+                        .faces()
+                        .filter(|face| face_path(face) == Some(path.as_path()))
+                    {
+                        db.push_face_info(face.clone());
+                    }
+                    self.system_font_modified_times
+                        .insert(path.clone(), *modified);
+                }
+                None => {
+                    self.system_font_modified_times.remove(path);
+                }
+            }
+        }
+        // The fonts of the replaced faces are unloaded, so that they aren't rendered stale, while
+        // looking up their family again resolves to the new faces.
+        self.remove_faces(&stale_face_ids);
+        self.system_font_stats = SystemFontStats {
+            discovered: paths.len(),
+            loaded: paths.len(),
+            failed: 0,
+        };
+        log::info!("reloaded {} changed system font files", changed_paths.len());
+        changed_paths.len()
     }
-}
 
-impl From<Vector2F> for Size<f32> {
-    fn from(vec: Vector2F) -> Self {
-        size(vec.x(), vec.y())
+    fn font_version(&self, font_id: FontId) -> Option<u32> {
+        let face = self.try_loaded_font(font_id).ok()?.rustybuzz();
+        let head = face
+            .raw_face()
+            .table(ttf_parser::Tag::from_bytes(b"head"))?;
+        // The font revision follows the table's own 4 byte version number.
+        let revision = head.get(4..8)?.try_into().ok()?;
+        Some(u32::from_be_bytes(revision))
     }
-}
 
-impl From<FontWeight> for cosmic_text::Weight {
-    fn from(value: FontWeight) -> Self {
-        cosmic_text::Weight(value.0 as u16)
+    fn font_path(&self, font_id: FontId) -> Option<PathBuf> {
+        let face_id = self.try_loaded_font(font_id).ok()?.id();
+        Some(face_path(self.font_system.db().face(face_id)?)?.to_path_buf())
     }
-}
 
-impl From<FontStyle> for cosmic_text::Style {
-    fn from(style: FontStyle) -> Self {
-        match style {
-            FontStyle::Normal => cosmic_text::Style::Normal,
-            FontStyle::Italic => cosmic_text::Style::Italic,
-            FontStyle::Oblique => cosmic_text::Style::Oblique,
+    fn icon_font_family(&self) -> Option<&str> {
+        let db = self.font_system.db();
+        self.icon_font_families
+            .iter()
+            .find(|icon_family| {
+                db.faces()
+                    .any(|face| face.families.iter().any(|family| family.0 == **icon_family))
+            })
+            .map(String::as_str)
+    }
+
+    /// Returns whether rendering the font with the face it resolved to requires emboldening or
+    /// slanting the face, because its family has no face that is bold or italic.
+    fn synthetic_style(&self, font: &Font, font_id: FontId) -> SyntheticStyle {
+        let database_id = self.loaded_font(font_id).id();
+        let Some(face) = self.font_system.db().face(database_id) else {
+            return SyntheticStyle::default();
+        };
+        SyntheticStyle {
+            bold: font.weight.0 >= FontWeight::SEMIBOLD.0
+                && f32::from(face.weight.0) < FontWeight::SEMIBOLD.0,
+            italic: font.style != FontStyle::Normal && face.style == fontdb::Style::Normal,
         }
     }
-}
 
-fn font_into_properties(font: &crate::Font) -> font_kit::properties::Properties {
-    font_kit::properties::Properties {
-        style: match font.style {
-            crate::FontStyle::Normal => font_kit::properties::Style::Normal,
-            crate::FontStyle::Italic => font_kit::properties::Style::Italic,
-            crate::FontStyle::Oblique => font_kit::properties::Style::Oblique,
-        },
-        weight: font_kit::properties::Weight(font.weight.0),
-        stretch: Default::default(),
+    fn font_display_name(&self, font_id: FontId, locale: &str) -> Option<String> {
+        let font = self.try_loaded_font(font_id).ok()?;
+        let localized = windows_language_id(locale).and_then(|language_id| {
+            find_font_name(font, name_id::FULL_NAME, |name| {
+                name.language_id == language_id
+                    || (!locale.contains('-')
+                        && name.language_id & PRIMARY_LANGUAGE_MASK
+                            == language_id & PRIMARY_LANGUAGE_MASK)
+            })
+        });
+        localized
+            .or_else(|| {
+                find_font_name(font, name_id::FULL_NAME, |name| {
+                    name.language() == Language::English_UnitedStates
+                })
+            })
+            .or_else(|| {
+                let face = self.font_system.db().face(font.id())?;
+                face.families.first().map(|family| family.0.clone())
+            })
     }
-}
 
-fn face_info_into_properties(
-    face_info: &cosmic_text::fontdb::FaceInfo,
-) -> font_kit::properties::Properties {
-    font_kit::properties::Properties {
-        style: match face_info.style {
-            cosmic_text::Style::Normal => font_kit::properties::Style::Normal,
-            cosmic_text::Style::Italic => font_kit::properties::Style::Italic,
-            cosmic_text::Style::Oblique => font_kit::properties::Style::Oblique,
-        },
-        // both libs use the same values for weight
-        weight: font_kit::properties::Weight(face_info.weight.0.into()),
-        stretch: match face_info.stretch {
-            cosmic_text::Stretch::Condensed => font_kit::properties::Stretch::CONDENSED,
-            cosmic_text::Stretch::Expanded => font_kit::properties::Stretch::EXPANDED,
-            cosmic_text::Stretch::ExtraCondensed => font_kit::properties::Stretch::EXTRA_CONDENSED,
-            cosmic_text::Stretch::ExtraExpanded => font_kit::properties::Stretch::EXTRA_EXPANDED,
-            cosmic_text::Stretch::Normal => font_kit::properties::Stretch::NORMAL,
-            cosmic_text::Stretch::SemiCondensed => font_kit::properties::Stretch::SEMI_CONDENSED,
-            cosmic_text::Stretch::SemiExpanded => font_kit::properties::Stretch::SEMI_EXPANDED,
-            cosmic_text::Stretch::UltraCondensed => font_kit::properties::Stretch::ULTRA_CONDENSED,
-            cosmic_text::Stretch::UltraExpanded => font_kit::properties::Stretch::ULTRA_EXPANDED,
-        },
+    fn has_color_emoji_font(&self) -> bool {
+        let db = self.font_system.db();
+        db.faces().any(|face| {
+            db.with_face_data(face.id, |data, index| {
+                let face = ttf_parser::Face::parse(data, index).ok()?;
+                Some(has_color_tables(&face))
+            })
+            .flatten()
+            .unwrap_or(false)
+        })
+    }
+
+    fn font_id_by_full_name(&mut self, full_name: &str) -> Option<FontId> {
+        let full_name = full_name.to_lowercase();
+        if let Some(font_id) = self.font_ids_by_full_name.get(&full_name) {
+            return *font_id;
+        }
+
+        let db = self.font_system.db();
+        let face_id = db.faces().map(|face| face.id).find(|face_id| {
+            db.with_face_data(*face_id, |data, index| {
+                let face = ttf_parser::Face::parse(data, index).ok()?;
+                let found = face.names().into_iter().any(|name| {
+                    name.name_id == name_id::FULL_NAME
+                        && name.is_unicode()
+                        && name
+                            .to_string()
+                            .map_or(false, |name| name.to_lowercase() == full_name)
+                });
+                Some(found)
+            })
+            .flatten()
+            .unwrap_or(false)
+        });
+        let font_id = face_id.and_then(|face_id| self.font_id_for_cosmic_id(face_id));
+        self.font_ids_by_full_name.insert(full_name, font_id);
+        font_id
+    }
+
+    fn faces_by_postscript_prefix(&mut self, prefix: &str) -> Vec<FontId> {
+        let prefix = prefix.to_lowercase();
+        let face_ids = self
+            .font_system
+            .db()
+            .faces()
+            .filter(|face| {
+                let name = face.post_script_name.to_lowercase();
+                name.strip_prefix(&prefix)
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with('-'))
+            })
+            .map(|face| face.id)
+            .collect::<Vec<_>>();
+        face_ids
+            .into_iter()
+            .filter_map(|face_id| self.font_id_for_cosmic_id(face_id))
+            .collect()
+    }
+
+    fn font_id_for_postscript_name(&mut self, name: &str) -> Option<FontId> {
+        let face_id = self
+            .font_system
+            .db()
+            .faces()
+            .find(|face| face.post_script_name == name)?
+            .id;
+        self.font_id_for_cosmic_id(face_id)
+    }
+
+    fn forms_ligature(&mut self, font_id: FontId, chars: &[char]) -> bool {
+        if self.try_loaded_font(font_id).is_err() {
+            return false;
+        }
+        let text = chars.iter().collect::<String>();
+        let run = FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        };
+        let layout = self.layout_line(&text, px(16.), &[run], Shaping::Advanced);
+        let glyph_count = layout
+            .runs
+            .iter()
+            .map(|run| run.glyphs.len())
+            .sum::<usize>();
+        glyph_count < text.graphemes(true).count()
+    }
+
+    fn italic_angle(&self, font_id: FontId) -> Option<f32> {
+        let font = self.try_loaded_font(font_id).ok()?;
+        let angle = font.rustybuzz().italic_angle().unwrap_or(0.);
+        if angle != 0. {
+            return Some(angle);
+        }
+        let is_styled_italic = self
+            .font_system
+            .db()
+            .face(font.id())
+            .map_or(false, |face| face.style != fontdb::Style::Normal)
+            || self
+                .synthetic_styles
+                .get(&font_id)
+                .map_or(false, |style| style.italic);
+        Some(if is_styled_italic {
+            SYNTHETIC_ITALIC_ANGLE
+        } else {
+            0.
+        })
+    }
+
+    fn caret_metrics(&self, font_id: FontId) -> Option<CaretMetrics> {
+        let face = self.try_loaded_font(font_id).ok()?.rustybuzz();
+        // caretSlopeRise, caretSlopeRun and caretOffset follow the hhea table's first 18 bytes.
+        let hhea_caret = face
+            .raw_face()
+            .table(ttf_parser::Tag::from_bytes(b"hhea"))
+            .and_then(|hhea| hhea.get(18..24))
+            .map(|caret| {
+                let read = |i: usize| i16::from_be_bytes([caret[i], caret[i + 1]]) as f32;
+                (read(0), read(2), read(4))
+            });
+        let offset = match hhea_caret {
+            Some((rise, run, offset)) if rise != 0. && run != 0. => {
+                return Some(CaretMetrics {
+                    slope_rise: rise,
+                    slope_run: run,
+                    offset,
+                })
+            }
+            Some((_, _, offset)) => offset,
+            None => 0.,
+        };
+
+        let angle = self.italic_angle(font_id)?;
+        let rise = face.units_per_em() as f32;
+        Some(CaretMetrics {
+            slope_rise: rise,
+            slope_run: if angle == 0. {
+                0.
+            } else {
+                (-angle).to_radians().tan() * rise
+            },
+            offset,
+        })
+    }
+
+    fn decoration_rect(
+        &self,
+        font_id: FontId,
+        font_size: Pixels,
+        kind: TextDecoration,
+        width: Pixels,
+    ) -> Option<Bounds<Pixels>> {
+        let face = self.try_loaded_font(font_id).ok()?.rustybuzz();
+        let units_per_em = face.units_per_em() as f32;
+        let underline = face
+            .underline_metrics()
+            .filter(|metrics| metrics.thickness > 0);
+        // Fall back to a thickness typical of regular weights when the font doesn't specify one.
+        let default_thickness =
+            underline.map_or(units_per_em / 14., |metrics| metrics.thickness as f32);
+        let (top, thickness) = match kind {
+            TextDecoration::Underline => underline
+                .map_or((-default_thickness, default_thickness), |metrics| {
+                    (metrics.position as f32, metrics.thickness as f32)
+                }),
+            TextDecoration::Strikethrough => face
+                .strikeout_metrics()
+                .filter(|metrics| metrics.thickness > 0)
+                .map_or_else(
+                    || {
+                        let x_height = face.x_height().map_or(units_per_em / 2., f32::from);
+                        ((x_height + default_thickness) / 2., default_thickness)
+                    },
+                    |metrics| (metrics.position as f32, metrics.thickness as f32),
+                ),
+            TextDecoration::Overline => (face.ascender() as f32, default_thickness),
+        };
+        let scale = font_size.0 / units_per_em;
+        Some(Bounds {
+            origin: point(px(0.), px(-top * scale)),
+            size: size(width, px(thickness * scale)),
+        })
+    }
+
+    fn supports_shaping_for(
+        &self,
+        font_id: FontId,
+        script_tag: &str,
+        language_tag: Option<&str>,
+    ) -> bool {
+        let Result::Ok(font) = self.try_loaded_font(font_id) else {
+            return false;
+        };
+        let face = font.rustybuzz();
+        let script_tag = ttf_parser::Tag::from_bytes_lossy(script_tag.as_bytes());
+        let language_tag =
+            language_tag.map(|tag| ttf_parser::Tag::from_bytes_lossy(tag.as_bytes()));
+        let tables = face.tables();
+        [tables.gsub, tables.gpos]
+            .into_iter()
+            .flatten()
+            .filter_map(|table| table.scripts.find(script_tag))
+            .any(|script| {
+                language_tag.map_or(true, |language_tag| {
+                    script.languages.find(language_tag).is_some()
+                })
+            })
+    }
+
+    fn font_sample_text(&self, font_id: FontId) -> Option<String> {
+        let font = self.try_loaded_font(font_id).ok()?;
+        find_font_name(font, name_id::SAMPLE_TEXT, |name| {
+            name.language() == Language::English_UnitedStates
+        })
+        .or_else(|| find_font_name(font, name_id::SAMPLE_TEXT, |_| true))
+    }
+
+    fn glyph_to_svg(&self, font_id: FontId, glyph_id: GlyphId) -> Option<String> {
+        let face = self.try_loaded_font(font_id).ok()?.rustybuzz();
+        let mut path = SvgPathBuilder(String::new());
+        face.outline_glyph(ttf_parser::GlyphId(glyph_id.0 as u16), &mut path)?;
+        let units_per_em = face.units_per_em();
+        Some(format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 {} {units_per_em} {units_per_em}\"><path d=\"{}\"/></svg>",
+            -face.ascender(),
+            path.0.trim_end(),
+        ))
+    }
+
+    /// Adds the given font files to the font database and returns the ids of their faces there,
+    /// without loading them. Fails without adding any of the files if one of them isn't a valid
+    /// font. The faces that are added can later be unloaded if `unloadable` is set.
+    #[profiling::function]
+    fn add_fonts(
+        &mut self,
+        fonts: Vec<Cow<'static, [u8]>>,
+        deduplicate: bool,
+        unloadable: bool,
+    ) -> Result<Vec<fontdb::ID>> {
+        // fontdb skips data it can't parse, so it's checked up front to report why, before any of
+        // the fonts are added.
+        for (index, bytes) in fonts.iter().enumerate() {
+            if let Err(error) = ttf_parser::Face::parse(bytes, 0) {
+                return Err(FontLoadError::ParseFailed { index, error }.into());
+            }
+        }
+
+        self.invalidate_font_caches();
+        let mut face_ids = Vec::new();
+        for bytes in fonts {
+            let data_key = font_data_key(&bytes);
+            let db = self.font_system.db();
+            let loaded_face_ids = self
+                .face_ids_by_font_data
+                .get(&data_key)
+                .map(|ids| ids.iter().copied().filter(|id| db.face(*id).is_some()))
+                .into_iter()
+                .flatten()
+                .collect::<SmallVec<[_; 1]>>();
+            if deduplicate && !loaded_face_ids.is_empty() {
+                face_ids.extend(loaded_face_ids);
+                continue;
+            }
+
+            let loaded_face_ids = self
+                .font_system
+                .db_mut()
+                .load_font_source(fontdb::Source::Binary(Arc::new(bytes.into_owned())));
+            face_ids.extend(loaded_face_ids.iter().copied());
+            if unloadable {
+                self.unloadable_face_ids
+                    .extend(loaded_face_ids.iter().copied());
+            }
+            self.face_ids_by_font_data
+                .insert(data_key, loaded_face_ids.into_iter().collect());
+        }
+        Ok(face_ids)
+    }
+
+    fn unload_fonts(&mut self, font_ids: &[FontId]) -> Result<()> {
+        let mut face_ids = HashSet::default();
+        for font_id in font_ids {
+            let Some(font) = self.loaded_fonts_store.get(font_id.0) else {
+                return Err(anyhow!("font {font_id:?} doesn't exist"));
+            };
+            let Some(font) = font else {
+                continue;
+            };
+            if !self.unloadable_face_ids.contains(&font.id()) {
+                return Err(anyhow!(
+                    "font {font_id:?} wasn't added with `add_fonts_with_ids`, so it can't be unloaded"
+                ));
+            }
+            if let Some(family) = self.pinned_family(font.id()) {
+                return Err(anyhow!(
+                    "font {font_id:?} belongs to the pinned family {family}, so it can't be unloaded"
+                ));
+            }
+            face_ids.insert(font.id());
+        }
+        if face_ids.is_empty() {
+            return Ok(());
+        }
+
+        self.remove_faces(&face_ids);
+        Ok(())
+    }
+
+    /// Removes the given faces from the font database and marks their fonts as unloaded, along
+    /// with the fonts derived from them, dropping everything cached about them.
+    fn remove_faces(&mut self, face_ids: &HashSet<fontdb::ID>) {
+        let db = self.font_system.db_mut();
+        for face_id in face_ids {
+            db.remove_face(*face_id);
+        }
+        self.swash_cache
+            .image_cache
+            .retain(|cache_key, _| !face_ids.contains(&cache_key.font_id));
+        self.swash_cache
+            .outline_command_cache
+            .retain(|cache_key, _| !face_ids.contains(&cache_key.font_id));
+
+        // Fonts derived from the faces, e.g. with features or synthesized styles, go with them.
+        let mut unloaded = HashSet::default();
+        for (ix, font) in self.loaded_fonts_store.iter_mut().enumerate() {
+            if font
+                .as_ref()
+                .map_or(false, |font| face_ids.contains(&font.id()))
+            {
+                *font = None;
+                unloaded.insert(FontId(ix));
+            }
+        }
+        let is_loaded = |font_id: &FontId| !unloaded.contains(font_id);
+        self.font_ids_by_face_id
+            .retain(|face_id, _| !face_ids.contains(face_id));
+        self.postscript_names
+            .retain(|font_id, _| is_loaded(font_id));
+        self.color_font_ids.retain(is_loaded);
+        self.font_features.retain(|font_id, _| is_loaded(font_id));
+        self.font_ids_by_features
+            .retain(|(font_id, _), derived_id| is_loaded(font_id) && is_loaded(derived_id));
+        self.font_variations.retain(|font_id, _| is_loaded(font_id));
+        self.font_ids_by_instance
+            .retain(|(font_id, _), derived_id| is_loaded(font_id) && is_loaded(derived_id));
+        self.synthetic_styles
+            .retain(|font_id, _| is_loaded(font_id));
+        self.font_ids_by_synthetic_style
+            .retain(|(font_id, _), derived_id| is_loaded(font_id) && is_loaded(derived_id));
+        self.instance_images
+            .retain(|(font_id, _), _| is_loaded(font_id));
+        self.face_ids_by_font_data
+            .retain(|_, ids| ids.iter().all(|id| !face_ids.contains(id)));
+        self.unloadable_face_ids
+            .retain(|face_id| !face_ids.contains(face_id));
+        self.invalidate_font_caches();
+    }
+
+    /// Clears what's cached about the set of loaded fonts, after fonts were added or removed.
+    fn invalidate_font_caches(&mut self) {
+        self.font_ids_by_family_cache.clear();
+        self.fallback_coverage.clear();
+        self.font_ids_by_full_name.clear();
+        self.advance_cache.get_mut().clear();
+    }
+
+    /// Returns the family of the face that is pinned, either explicitly or as an icon font family.
+    fn pinned_family(&self, face_id: fontdb::ID) -> Option<&str> {
+        let face = self.font_system.db().face(face_id)?;
+        face.families
+            .iter()
+            .map(|(family, _)| family.as_str())
+            .find(|family| {
+                self.pinned_families.contains(*family)
+                    || self.icon_font_families.iter().any(|icon| icon == family)
+            })
+    }
+
+    fn font_id(&mut self, font: &Font) -> Result<FontId> {
+        // todo(linux): Do we need to use CosmicText's Font APIs? Can we consolidate this to use font_kit?
+        self.cache_family(&font.family, &font.features)?;
+        let candidates = self.font_ids_by_family_cache[&font.family].as_slice();
+        if candidates.is_empty() {
+            return Err(FontLoadError::FamilyNotFound(font.family.clone()).into());
+        }
+
+        // todo(linux) ideally we would make fontdb's `find_best_match` pub instead of using font-kit here
+        let candidate_properties = candidates
+            .iter()
+            .map(|font_id| {
+                let database_id = self.loaded_font(*font_id).id();
+                let face_info = self.font_system.db().face(database_id).expect("");
+                face_info_into_properties(face_info)
+            })
+            .collect::<SmallVec<[_; 4]>>();
+
+        let ix =
+            font_kit::matching::find_best_match(&candidate_properties, &font_into_properties(font))
+                .map_err(|_| FontLoadError::NoMatchingFace(font.family.clone()))?;
+
+        let mut font_id = candidates[ix];
+        let synthetic_style = self.synthetic_style(font, font_id);
+        if synthetic_style != SyntheticStyle::default() {
+            font_id = self.font_id_with_synthetic_style(font_id, synthetic_style);
+        }
+        if font.features != FontFeatures::default() {
+            font_id = self.font_id_with_features(font_id, &font.features);
+        }
+        if !font.variations.is_empty() {
+            let coords = font
+                .variations
+                .iter()
+                .map(|variation| (ttf_parser::Tag::from_bytes(&variation.tag), variation.value))
+                .collect::<SmallVec<[_; 4]>>();
+            font_id = self.register_instance(font_id, &coords);
+        }
+        Ok(font_id)
+    }
+
+    /// Returns the font id of the face with the given id that is rendered with the given style.
+    fn font_id_with_synthetic_style(&mut self, font_id: FontId, style: SyntheticStyle) -> FontId {
+        if let Some(font_id) = self.font_ids_by_synthetic_style.get(&(font_id, style)) {
+            return *font_id;
+        }
+        let synthetic_font_id = self.derive_font_id(font_id);
+        self.synthetic_styles.insert(synthetic_font_id, style);
+        self.font_ids_by_synthetic_style
+            .insert((font_id, style), synthetic_font_id);
+        synthetic_font_id
+    }
+
+    /// Returns the font id of the face with the given id that is shaped with the given features.
+    fn font_id_with_features(&mut self, font_id: FontId, features: &FontFeatures) -> FontId {
+        let key = (font_id, features.clone());
+        if let Some(font_id) = self.font_ids_by_features.get(&key) {
+            return *font_id;
+        }
+        let features_font_id = self.derive_font_id(font_id);
+        self.font_features
+            .insert(features_font_id, opentype_features(features));
+        self.font_ids_by_features.insert(key, features_font_id);
+        features_font_id
+    }
+
+    fn register_instance(&mut self, font_id: FontId, coords: &[(ttf_parser::Tag, f32)]) -> FontId {
+        let key = (
+            font_id,
+            coords
+                .iter()
+                .map(|(tag, value)| (tag.0, value.to_bits()))
+                .collect(),
+        );
+        if let Some(font_id) = self.font_ids_by_instance.get(&key) {
+            return *font_id;
+        }
+        let instance_font_id = self.derive_font_id(font_id);
+        let variations = self.font_variations.entry(instance_font_id).or_default();
+        for (tag, value) in coords {
+            variations.retain(|variation| variation.tag != *tag);
+            variations.push(rustybuzz::Variation {
+                tag: *tag,
+                value: *value,
+            });
+        }
+        self.font_ids_by_instance.insert(key, instance_font_id);
+        instance_font_id
+    }
+
+    /// Returns the normalized coordinates of a variable font instance, for reading its metrics.
+    fn normalized_coords(&self, font_id: FontId) -> SmallVec<[i16; 4]> {
+        let Some(variations) = self.font_variations.get(&font_id) else {
+            return SmallVec::new();
+        };
+        let mut face = self.loaded_font(font_id).rustybuzz().clone();
+        face.set_variations(variations);
+        face.variation_coordinates()
+            .iter()
+            .map(|coord| coord.get())
+            .collect()
+    }
+
+    /// Adds a font id for the face of the given font id, with the same features and variations.
+    fn derive_font_id(&mut self, font_id: FontId) -> FontId {
+        let derived_font_id = FontId(self.loaded_fonts_store.len());
+        self.loaded_fonts_store
+            .push(Some(self.loaded_font(font_id).clone()));
+        if let Some(postscript_name) = self.postscript_names.get(&font_id).cloned() {
+            self.postscript_names
+                .insert(derived_font_id, postscript_name);
+        }
+        if let Some(features) = self.font_features.get(&font_id).cloned() {
+            self.font_features.insert(derived_font_id, features);
+        }
+        if let Some(variations) = self.font_variations.get(&font_id).cloned() {
+            self.font_variations.insert(derived_font_id, variations);
+        }
+        if self.color_font_ids.contains(&font_id) {
+            self.color_font_ids.insert(derived_font_id);
+        }
+        if let Some(style) = self.synthetic_styles.get(&font_id).copied() {
+            self.synthetic_styles.insert(derived_font_id, style);
+        }
+        derived_font_id
+    }
+
+    /// Loads the faces of the given family into `font_ids_by_family_cache` unless they're cached.
+    fn cache_family(&mut self, family: &SharedString, features: &FontFeatures) -> Result<()> {
+        if !self.font_ids_by_family_cache.contains_key(family) {
+            let font_ids = self.load_family(family, features)?;
+            self.font_ids_by_family_cache
+                .insert(family.clone(), font_ids);
+        }
+        Ok(())
+    }
+
+    #[profiling::function]
+    fn load_family(
+        &mut self,
+        name: &str,
+        _features: &FontFeatures,
+    ) -> Result<SmallVec<[FontId; 4]>> {
+        // TODO: Determine the proper system UI font.
+        let name = if name == ".SystemUIFont" {
+            "Zed Sans"
+        } else {
+            name
+        };
+        let is_icon_font = self.icon_font_families.iter().any(|family| family == name);
+        if is_icon_font {
+            self.finish_loading_system_fonts();
+        }
+        let icon_font_family = is_icon_font
+            .then(|| self.icon_font_family().map(str::to_string))
+            .flatten();
+        let name = icon_font_family.as_deref().unwrap_or(name);
+
+        let has_family = |db: &fontdb::Database| {
+            db.faces()
+                .any(|face| face.families.iter().any(|family| *name == family.0))
+        };
+        if !has_family(self.font_system.db()) {
+            self.finish_loading_system_fonts();
+        }
+        if !has_family(self.font_system.db()) {
+            let fonts = self
+                .font_sources
+                .iter()
+                .find_map(|source| source.load_family(name));
+            if let Some(fonts) = fonts {
+                self.add_fonts(fonts, true, false)?;
+            }
+        }
+
+        let mut font_ids = SmallVec::new();
+        let families = self
+            .font_system
+            .db()
+            .faces()
+            .filter(|face| face.families.iter().any(|family| *name == family.0))
+            .map(|face| (face.id, face.post_script_name.clone()))
+            .collect::<SmallVec<[_; 4]>>();
+
+        for (font_id, postscript_name) in families {
+            let font = self
+                .font_system
+                .get_font(font_id)
+                .ok_or_else(|| FontLoadError::FaceUnavailable(postscript_name.clone()))?;
+
+            // Fonts without a character map can still be used to render glyphs by id, e.g. for
+            // icons, so only fonts that map characters but not 'm' are skipped. Icon fonts don't
+            // have an 'm' either, but are used to render Windows caption icons.
+            let has_cmap = font.rustybuzz().tables().cmap.is_some();
+            if has_cmap && font.as_swash().charmap().map('m') == 0 && !is_icon_font {
+                self.font_system.db_mut().remove_face(font.id());
+                continue;
+            };
+
+            // Faces can be loaded under several family names, e.g. for icon font aliases.
+            if let Some(font_id) = self.font_ids_by_face_id.get(&font.id()) {
+                font_ids.push(*font_id);
+                continue;
+            }
+
+            font_ids.push(self.push_loaded_font(font, postscript_name));
+        }
+
+        Ok(font_ids)
+    }
+
+    fn font_metrics(&self, font_id: FontId) -> Result<FontMetrics> {
+        let font = self.try_loaded_font(font_id)?;
+        let metrics = font.as_swash().metrics(&self.normalized_coords(font_id));
+        // The union of all glyph bounds from the head table, in font units with y pointing up.
+        let global_bounds = font.rustybuzz().global_bounding_box();
+
+        Ok(FontMetrics {
+            units_per_em: metrics.units_per_em as u32,
+            ascent: metrics.ascent,
+            descent: -metrics.descent, // todo(linux) confirm this is correct
+            line_gap: metrics.leading,
+            underline_position: metrics.underline_offset,
+            underline_thickness: metrics.stroke_size,
+            cap_height: metrics.cap_height,
+            x_height: metrics.x_height,
+            bounding_box: Bounds {
+                origin: point(global_bounds.x_min as f32, global_bounds.y_min as f32),
+                size: size(global_bounds.width() as f32, global_bounds.height() as f32),
+            },
+        })
+    }
+
+    /// Returns the glyph's ink bounds in font units, with y increasing upwards from the baseline.
+    /// Glyphs without an outline, such as spaces, have empty bounds at the origin.
+    fn typographic_bounds(&self, font_id: FontId, glyph_id: GlyphId) -> Result<Bounds<f32>> {
+        let face = self.try_loaded_font(font_id)?.rustybuzz();
+        Ok(face
+            .glyph_bounding_box(ttf_parser::GlyphId(glyph_id.0 as u16))
+            .map_or_else(Bounds::default, |bounds| Bounds {
+                origin: point(bounds.x_min as f32, bounds.y_min as f32),
+                size: size(bounds.width() as f32, bounds.height() as f32),
+            }))
+    }
+
+    fn advance(&self, font_id: FontId, glyph_id: GlyphId) -> Result<Size<f32>> {
+        let font = self.try_loaded_font(font_id)?;
+        let glyph_id = if glyph_id.0 < u32::from(font.rustybuzz().number_of_glyphs()) {
+            glyph_id.0 as u16
+        } else {
+            match self.missing_glyph_advance {
+                MissingGlyphAdvance::Notdef => 0,
+                MissingGlyphAdvance::Zero => return Ok(Size::default()),
+            }
+        };
+        let advance = *self
+            .advance_cache
+            .lock()
+            .entry(font_id)
+            .or_default()
+            .entry(GlyphId(glyph_id.into()))
+            .or_insert_with(|| {
+                let coords = self.normalized_coords(font_id);
+                let metrics = font.as_swash().glyph_metrics(&coords);
+                let mut width = metrics.advance_width(glyph_id);
+                if self.is_synthetic_bold(font_id) {
+                    width += metrics.units_per_em() as f32 * SYNTHETIC_BOLD_STRENGTH;
+                }
+                size(width, metrics.advance_height(glyph_id))
+            });
+        let scale = if self.is_emoji(font_id) {
+            self.emoji_scale
+        } else {
+            1.
+        };
+        Ok(Size {
+            width: advance.width * scale,
+            height: advance.height * scale,
+        })
+    }
+
+    fn glyph_for_char(&self, font_id: FontId, ch: char) -> Option<GlyphId> {
+        let glyph_id = self
+            .try_loaded_font(font_id)
+            .ok()?
+            .as_swash()
+            .charmap()
+            .map(ch);
+        if glyph_id == 0 {
+            None
+        } else {
+            Some(GlyphId(glyph_id.into()))
+        }
+    }
+
+    fn is_emoji(&self, font_id: FontId) -> bool {
+        self.color_font_ids.contains(&font_id)
+    }
+
+    /// Returns the size, in device pixels, at which the glyph is rendered.
+    fn raster_font_size(&self, params: &RenderGlyphParams) -> Pixels {
+        let mut font_size = params.font_size * params.scale_factor;
+        if self.is_emoji(params.font_id) {
+            font_size *= self.emoji_scale;
+        }
+        px((font_size.0 / RASTER_FONT_SIZE_STEP).round() * RASTER_FONT_SIZE_STEP)
+    }
+
+    fn raster_bounds(&mut self, params: &RenderGlyphParams) -> Result<Bounds<DevicePixels>> {
+        check_scale_factor(params.scale_factor)?;
+        if is_degenerate_font_size(self.raster_font_size(params)) {
+            return Ok(Bounds::default());
+        }
+
+        // Check the size of the glyph's outline before rendering it, since rendering allocates
+        // the bitmap. Glyphs without an outline, such as bitmap emoji, are assumed to fill the em
+        // square.
+        let font = self.try_loaded_font(params.font_id)?;
+        let face = font.rustybuzz();
+        let scale = f32::from(self.raster_font_size(params)) / face.units_per_em() as f32;
+        let estimated_size = face
+            .glyph_bounding_box(ttf_parser::GlyphId(params.glyph_id.0 as u16))
+            .map_or_else(
+                || size(face.units_per_em() as f32, face.units_per_em() as f32),
+                |bounds| size(bounds.width() as f32, bounds.height() as f32),
+            );
+        self.check_glyph_size(params, estimated_size.map(|length| length * scale))?;
+
+        let image = self.glyph_image(params)?;
+        // Glyphs without ink, such as spaces, have nothing to keep apart from their neighbors.
+        let padding = if image.placement.width == 0 || image.placement.height == 0 {
+            0
+        } else {
+            self.glyph_padding as i32
+        };
+        Ok(Bounds {
+            origin: point(
+                (image.placement.left - padding).into(),
+                (-image.placement.top - padding).into(),
+            ),
+            size: size(
+                (image.placement.width as i32 + 2 * padding).into(),
+                (image.placement.height as i32 + 2 * padding).into(),
+            ),
+        })
+    }
+
+    #[profiling::function]
+    fn rasterize_glyph(
+        &mut self,
+        params: &RenderGlyphParams,
+        glyph_bounds: Bounds<DevicePixels>,
+    ) -> Result<(Size<DevicePixels>, Vec<u8>)> {
+        check_scale_factor(params.scale_factor)?;
+        if is_degenerate_font_size(self.raster_font_size(params)) {
+            Ok((Size::default(), Vec::new()))
+        } else {
+            self.check_glyph_size(params, glyph_bounds.size.map(|length| length.0 as f32))?;
+
+            let bitmap_size = glyph_bounds.size;
+            let image = self.glyph_image(params)?;
+            if image.placement.width == 0 || image.placement.height == 0 {
+                return Ok((Size::default(), Vec::new()));
+            }
+            if bitmap_size.width.0 == 0 || bitmap_size.height.0 == 0 {
+                return Err(anyhow!("glyph bounds are empty"));
+            }
+            // The bounds are the glyph's placement with any padding added around it. The padding is
+            // taken from the bounds, since it may have been changed since they were computed.
+            let padding_left = image.placement.left - glyph_bounds.origin.x.0;
+            let padding_top = -image.placement.top - glyph_bounds.origin.y.0;
+            if padding_left < 0
+                || padding_top < 0
+                || padding_left + image.placement.width as i32 > bitmap_size.width.0
+                || padding_top + image.placement.height as i32 > bitmap_size.height.0
+            {
+                return Err(anyhow!("glyph bounds don't contain the glyph"));
+            }
+            self.raster_counters
+                .glyphs_rasterized
+                .fetch_add(1, Ordering::Relaxed);
+
+            let (bytes_per_pixel, data) = match (image.content, params.is_emoji) {
+                (SwashContent::Mask, false) => (1, image.data),
+                // Monochrome glyphs are drawn from a coverage mask, so reduce color glyphs to the
+                // silhouette given by their alpha channel.
+                (_, false) => {
+                    let coverage = image.data.chunks_exact(4).map(|pixel| pixel[3]).collect();
+                    (1, coverage)
+                }
+                // Emoji go in the polychrome atlas, which holds BGRA pixels, so glyphs of emoji
+                // fonts that only have an outline are drawn in black.
+                (SwashContent::Mask, true) => {
+                    let pixels = image
+                        .data
+                        .iter()
+                        .flat_map(|coverage| [0, 0, 0, *coverage])
+                        .collect();
+                    (4, pixels)
+                }
+                (_, true) => {
+                    // Convert from RGBA to BGRA.
+                    let mut pixels = image.data;
+                    for pixel in pixels.chunks_exact_mut(4) {
+                        pixel.swap(0, 2);
+                    }
+                    (4, pixels)
+                }
+            };
+            let image_size = size(
+                DevicePixels(image.placement.width as i32),
+                DevicePixels(image.placement.height as i32),
+            );
+            if image_size == bitmap_size {
+                return Ok((bitmap_size, data));
+            }
+
+            let source_row_len = image.placement.width as usize * bytes_per_pixel;
+            let row_len = bitmap_size.width.0 as usize * bytes_per_pixel;
+            let mut padded = vec![0; row_len * bitmap_size.height.0 as usize];
+            for (y, row) in data.chunks_exact(source_row_len).enumerate() {
+                let start =
+                    (y + padding_top as usize) * row_len + padding_left as usize * bytes_per_pixel;
+                padded[start..start + source_row_len].copy_from_slice(row);
+            }
+            Ok((bitmap_size, padded))
+        }
+    }
+
+    /// Returns the rendered image of a glyph, recording whether it was cached and how long it took
+    /// in the raster stats. The glyph is shifted by the fraction of a pixel its subpixel variant
+    /// stands for, which cosmic-text caches separately for each variant.
+    fn glyph_image(&mut self, params: &RenderGlyphParams) -> Result<SwashImage> {
+        let font = self.try_loaded_font(params.font_id)?;
+        let subpixel_shift = params
+            .subpixel_variant
+            .map(|variant| variant as f32 / SUBPIXEL_VARIANTS as f32);
+        let synthetic_style = self
+            .synthetic_styles
+            .get(&params.font_id)
+            .copied()
+            .unwrap_or_default();
+        let flags = if synthetic_style.italic {
+            cosmic_text::CacheKeyFlags::FAKE_ITALIC
+        } else {
+            cosmic_text::CacheKeyFlags::empty()
+        };
+        let (cache_key, _, _) = CacheKey::new(
+            font.id(),
+            params.glyph_id.0 as u16,
+            self.raster_font_size(params).into(),
+            (subpixel_shift.x, subpixel_shift.y),
+            flags,
+        );
+        // Emboldened glyphs would share their cache key with the regular ones.
+        let instance_key = (self.font_variations.contains_key(&params.font_id)
+            || synthetic_style.bold)
+            .then_some((params.font_id, cache_key));
+        let is_cached = match &instance_key {
+            Some(instance_key) => self.instance_images.contains_key(instance_key),
+            None => self.swash_cache.image_cache.contains_key(&cache_key),
+        };
+        let counter = if is_cached {
+            &self.raster_counters.cache_hits
+        } else {
+            &self.raster_counters.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let image = match instance_key {
+            Some(instance_key) => {
+                if !is_cached {
+                    let image = self.render_derived_glyph(params.font_id, cache_key);
+                    self.instance_images.insert(instance_key, image);
+                }
+                self.instance_images[&instance_key].clone()
+            }
+            None => self
+                .swash_cache
+                .get_image(&mut self.font_system, cache_key)
+                .clone(),
+        };
+        self.raster_counters
+            .raster_nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        image.ok_or_else(|| anyhow!("failed to render glyph {:?}", params.glyph_id))
+    }
+
+    /// Renders a glyph of a variable font instance at its coordinates, or in its synthesized
+    /// style, the way `swash_cache` renders other glyphs.
+    fn render_derived_glyph(&mut self, font_id: FontId, cache_key: CacheKey) -> Option<SwashImage> {
+        let font = self.loaded_font(font_id).clone();
+        let font_size = f32::from_bits(cache_key.font_size_bits);
+        let embolden = self.is_synthetic_bold(font_id);
+        let mut scaler = self
+            .scale_context
+            .builder(font.as_swash())
+            .size(font_size)
+            .hint(true)
+            .variations(
+                self.font_variations
+                    .get(&font_id)
+                    .into_iter()
+                    .flatten()
+                    .map(|variation| (variation.tag.0, variation.value)),
+            )
+            .build();
+        let mut render = Render::new(&[
+            Source::ColorOutline(0),
+            Source::ColorBitmap(StrikeWith::BestFit),
+            Source::Outline,
+        ]);
+        render
+            .format(Format::Alpha)
+            .offset(Vector::new(
+                cache_key.x_bin.as_float(),
+                cache_key.y_bin.as_float(),
+            ))
+            .transform(
+                cache_key
+                    .flags
+                    .contains(cosmic_text::CacheKeyFlags::FAKE_ITALIC)
+                    .then(|| Transform::skew(Angle::from_degrees(14.), Angle::ZERO)),
+            );
+        if embolden {
+            render.embolden(font_size * SYNTHETIC_BOLD_STRENGTH);
+        }
+        render.render(&mut scaler, cache_key.glyph_id)
+    }
+
+    fn is_synthetic_bold(&self, font_id: FontId) -> bool {
+        self.synthetic_styles
+            .get(&font_id)
+            .map_or(false, |style| style.bold)
+    }
+
+    fn check_glyph_size(&self, params: &RenderGlyphParams, glyph_size: Size<f32>) -> Result<()> {
+        let pixels = glyph_size.width.ceil() as f64 * glyph_size.height.ceil() as f64;
+        if pixels > self.max_glyph_pixels as f64 {
+            log::error!(
+                "glyph {:?} of font {:?} is {}x{} pixels at size {:?}, exceeding the limit of {} pixels",
+                params.glyph_id,
+                params.font_id,
+                glyph_size.width.ceil(),
+                glyph_size.height.ceil(),
+                self.raster_font_size(params),
+                self.max_glyph_pixels,
+            );
+            return Err(anyhow!(
+                "glyph {:?} is too large to render",
+                params.glyph_id
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the id of a face in the font database, loading it if it wasn't loaded yet, e.g. for
+    /// fonts that text fell back to while shaping. Returns `None` if the face can't be loaded.
+    fn font_id_for_cosmic_id(&mut self, id: cosmic_text::fontdb::ID) -> Option<FontId> {
+        if let Some(font_id) = self.font_ids_by_face_id.get(&id) {
+            return Some(*font_id);
+        }
+
+        // This matches the behavior of the mac text system
+        let Some(font) = self.font_system.get_font(id) else {
+            log::error!("failed to load font {id:?}");
+            return None;
+        };
+        let postscript_name = self.font_system.db().face(id)?.post_script_name.clone();
+        Some(self.push_loaded_font(font, postscript_name))
+    }
+
+    /// Returns the face of a font id. Panics if the font was unloaded, so it's only used for fonts
+    /// that are known to be loaded, e.g. the fonts of runs that `layout_line` checked.
+    fn loaded_font(&self, font_id: FontId) -> &Arc<CosmicTextFont> {
+        self.loaded_fonts_store[font_id.0]
+            .as_ref()
+            .unwrap_or_else(|| panic!("font {font_id:?} was unloaded"))
+    }
+
+    /// Returns the face of a font id, or an error if the font was unloaded, e.g. while rendering
+    /// a line that was laid out before.
+    fn try_loaded_font(&self, font_id: FontId) -> Result<&Arc<CosmicTextFont>> {
+        self.loaded_fonts_store
+            .get(font_id.0)
+            .and_then(Option::as_ref)
+            .ok_or_else(|| anyhow!("font {font_id:?} was unloaded"))
+    }
+
+    /// Adds a newly loaded face to `loaded_fonts_store` and returns its font id.
+    fn push_loaded_font(&mut self, font: Arc<CosmicTextFont>, postscript_name: String) -> FontId {
+        let font_id = FontId(self.loaded_fonts_store.len());
+        if has_color_tables(font.rustybuzz()) {
+            self.color_font_ids.insert(font_id);
+        }
+        self.font_ids_by_face_id.insert(font.id(), font_id);
+        self.loaded_fonts_store.push(Some(font));
+        self.postscript_names.insert(font_id, postscript_name);
+        font_id
+    }
+
+    /// Returns the ink bounds of an emoji relative to its origin on the baseline, with y pointing
+    /// down, from the metrics of its bitmap strike or else its outline. Color bitmap fonts scale
+    /// their strikes to the requested size, which doesn't always agree with the advance, so the
+    /// glyphs that follow an emoji are pushed over by however far it extends past it to avoid
+    /// overlapping it. The bounds come from the font's tables rather than from rasterizing the
+    /// glyph, so they scale linearly with the font size.
+    fn emoji_ink_bounds(
+        &self,
+        font_id: FontId,
+        glyph_id: GlyphId,
+        font_size: f32,
+    ) -> Option<Bounds<f32>> {
+        let face = self.loaded_font(font_id).rustybuzz();
+        let glyph_id = ttf_parser::GlyphId(glyph_id.0 as u16);
+        let font_size = font_size * self.emoji_scale;
+        if let Some(image) = face.glyph_raster_image(glyph_id, font_size.ceil() as u16) {
+            // The offsets of the image are those of its bottom left corner, with y pointing up.
+            let scale = font_size / image.pixels_per_em as f32;
+            return Some(Bounds {
+                origin: point(
+                    image.x as f32 * scale,
+                    -(image.y as f32 + image.height as f32) * scale,
+                ),
+                size: size(image.width as f32 * scale, image.height as f32 * scale),
+            });
+        }
+        let bounds = face.glyph_bounding_box(glyph_id)?;
+        let scale = font_size / face.units_per_em() as f32;
+        Some(Bounds {
+            origin: point(bounds.x_min as f32 * scale, -bounds.y_max as f32 * scale),
+            size: size(
+                bounds.width() as f32 * scale,
+                bounds.height() as f32 * scale,
+            ),
+        })
+    }
+
+    /// Returns how far to move an emoji down so that its bitmap is vertically centered on the
+    /// capital letters of the surrounding text. Emoji fonts draw their glyphs at different heights
+    /// relative to the baseline, so without this they can sit noticeably higher or lower than the
+    /// text around them.
+    fn emoji_baseline_offset(
+        &self,
+        ink_bounds: Bounds<f32>,
+        text_font_id: Option<FontId>,
+        font_size: f32,
+    ) -> Pixels {
+        let Some(text_font_id) = text_font_id.filter(|font_id| !self.is_emoji(*font_id)) else {
+            return px(0.);
+        };
+        let face = self.loaded_font(text_font_id).rustybuzz();
+        let Some(cap_height) = face.capital_height().or_else(|| face.x_height()) else {
+            return px(0.);
+        };
+        let text_center = -(cap_height as f32) / face.units_per_em() as f32 * font_size / 2.;
+        let ink_center = ink_bounds.origin.y + ink_bounds.size.height / 2.;
+        px(text_center - ink_center)
+    }
+
+    // todo(linux) This is all a quick first pass, maybe we should be using cosmic_text::Buffer
+    #[profiling::function]
+    fn layout_line(
+        &mut self,
+        text: &str,
+        font_size: Pixels,
+        font_runs: &[FontRun],
+        shaping: Shaping,
+    ) -> LineLayout {
+        if let Some(run) = font_runs
+            .iter()
+            .find(|run| self.try_loaded_font(run.font_id).is_err())
+        {
+            log::error!(
+                "can't lay out text in font {:?}, which was unloaded",
+                run.font_id
+            );
+            return LineLayout {
+                font_size,
+                len: text.len(),
+                ..LineLayout::default()
+            };
+        }
+        if self.pending_system_fonts.is_some() && self.needs_fallback(text, font_runs) {
+            self.finish_loading_system_fonts();
+        }
+        let mut layout = if self.normalize_to_nfc && !is_nfc(text) {
+            self.layout_normalized_line(text, font_size, font_runs, shaping)
+        } else {
+            self.layout_unnormalized_line(text, font_size, font_runs, shaping)
+        };
+        compute_grapheme_boundaries(&mut layout, text);
+        layout
+    }
+
+    fn measure_width(&mut self, text: &str, font_size: Pixels, font_runs: &[FontRun]) -> Pixels {
+        if text.is_empty()
+            || font_runs
+                .iter()
+                .any(|run| self.try_loaded_font(run.font_id).is_err())
+        {
+            return px(0.);
+        }
+        if self.pending_system_fonts.is_some() && self.needs_fallback(text, font_runs) {
+            self.finish_loading_system_fonts();
+        }
+
+        // Runs that override the font size are shaped at their own size, like `layout_line` does.
+        let mut width = 0.;
+        for (range, run_font_size) in font_size_ranges(font_runs, 0..text.len(), font_size) {
+            if is_degenerate_font_size(run_font_size) {
+                continue;
+            }
+            let mut spans = FontSpans::new();
+            let mut offset = 0;
+            for run in font_runs_in_range(font_runs, range.clone()) {
+                spans.push((offset..offset + run.len, run.font_id));
+                offset += run.len;
+            }
+            let range_text = &text[range];
+            let spans = self.apply_fallback_fonts(range_text, spans);
+            let attrs_list = self.attrs_list(&spans);
+            let mut line = BufferLine::new(range_text, attrs_list, Shaping::Advanced);
+            width += line
+                .layout(
+                    &mut self.font_system,
+                    run_font_size.0,
+                    0.,
+                    cosmic_text::Wrap::None,
+                    None,
+                )
+                .iter()
+                .flat_map(|layout_line| &layout_line.glyphs)
+                .map(|glyph| glyph.w)
+                .sum::<f32>();
+        }
+        px(width)
+    }
+
+    /// Returns attributes that shape each span in its font.
+    fn attrs_list(&self, spans: &FontSpans) -> AttrsList {
+        let mut attrs_list = AttrsList::new(Attrs::new());
+        for (span, font_id) in spans {
+            let font = self.loaded_font(*font_id);
+            let font = self.font_system.db().face(font.id()).unwrap();
+            attrs_list.add_span(
+                span.clone(),
+                Attrs::new()
+                    .family(Family::Name(&font.families.first().unwrap().0))
+                    .stretch(font.stretch)
+                    .style(font.style)
+                    .weight(font.weight),
+            );
+        }
+        attrs_list
+    }
+
+    /// Returns whether the text has characters that the fonts of their runs don't cover.
+    fn needs_fallback(&self, text: &str, font_runs: &[FontRun]) -> bool {
+        let mut offset = 0;
+        font_runs.iter().any(|run| {
+            let run_text = text.get(offset..offset + run.len).unwrap_or_default();
+            offset += run.len;
+            run_text.chars().any(|ch| {
+                !ch.is_control()
+                    && !is_zero_width_format_char(ch)
+                    && self.glyph_for_char(run.font_id, ch).is_none()
+            })
+        })
+    }
+
+    /// Lays out the NFC normalization of the text, mapping the indices of the resulting glyphs
+    /// back to the original text. Grapheme clusters are normalized independently, which lets
+    /// glyphs be mapped back to the cluster they came from.
+    fn layout_normalized_line(
+        &mut self,
+        text: &str,
+        font_size: Pixels,
+        font_runs: &[FontRun],
+        shaping: Shaping,
+    ) -> LineLayout {
+        // The start of each grapheme cluster in the normalized and the original text, and whether
+        // normalization changed it.
+        let mut clusters = Vec::<(usize, usize, bool)>::new();
+        let mut normalized = String::with_capacity(text.len());
+        for (start, grapheme) in text.grapheme_indices(true) {
+            let normalized_start = normalized.len();
+            normalized.extend(grapheme.nfc());
+            let changed = normalized[normalized_start..] != *grapheme;
+            clusters.push((normalized_start, start, changed));
+        }
+        let to_normalized = |ix: usize| {
+            if ix >= text.len() {
+                return normalized.len();
+            }
+            let cluster = clusters.partition_point(|(_, start, _)| *start <= ix) - 1;
+            match clusters[cluster] {
+                (normalized_start, start, false) => normalized_start + ix - start,
+                (normalized_start, start, true) if start == ix => normalized_start,
+                // Runs that start inside a cluster that changed begin after it instead.
+                _ => clusters
+                    .get(cluster + 1)
+                    .map_or(normalized.len(), |(normalized_start, _, _)| {
+                        *normalized_start
+                    }),
+            }
+        };
+
+        let mut run_start = 0;
+        let normalized_runs = font_runs
+            .iter()
+            .map(|run| {
+                let start = to_normalized(run_start);
+                run_start += run.len;
+                FontRun {
+                    len: to_normalized(run_start) - start,
+                    ..*run
+                }
+            })
+            .collect::<SmallVec<[_; 4]>>();
+
+        let mut layout =
+            self.layout_unnormalized_line(&normalized, font_size, &normalized_runs, shaping);
+        for glyph in layout.runs.iter_mut().flat_map(|run| run.glyphs.iter_mut()) {
+            let cluster = clusters.partition_point(|(start, _, _)| *start <= glyph.index) - 1;
+            glyph.index = match clusters[cluster] {
+                (normalized_start, start, false) => start + glyph.index - normalized_start,
+                (_, start, true) => start,
+            };
+        }
+        layout.len = text.len();
+        layout
+    }
+
+    fn layout_unnormalized_line(
+        &mut self,
+        text: &str,
+        font_size: Pixels,
+        font_runs: &[FontRun],
+        shaping: Shaping,
+    ) -> LineLayout {
+        let mut layout = LineLayout {
+            font_size,
+            len: text.len(),
+            ..Default::default()
+        };
+        // Text that is too small to be visible, e.g. while animating a font size, is left out.
+        if font_runs
+            .iter()
+            .all(|run| is_degenerate_font_size(run.font_size.unwrap_or(font_size)))
+        {
+            return layout;
+        }
+
+        for (range, shaping) in self.shaping_ranges(text, 0..text.len(), font_runs, shaping) {
+            // The bidi algorithm starts a new paragraph after every hard break, which cosmic-text
+            // expects a line not to contain, so each paragraph is shaped by itself.
+            for range in paragraph_ranges(text, range) {
+                let mut size_ranges = font_size_ranges(font_runs, range, font_size);
+                size_ranges.retain(|(_, font_size)| !is_degenerate_font_size(*font_size));
+                if let [(range, font_size)] = size_ranges.as_slice() {
+                    self.shape_range(
+                        &mut layout,
+                        text,
+                        range.clone(),
+                        font_runs,
+                        *font_size,
+                        shaping,
+                    );
+                } else {
+                    self.shape_font_size_ranges(&mut layout, text, size_ranges, font_runs, shaping);
+                }
+            }
+        }
+        layout
+    }
+
+    fn layout_wrapped(
+        &mut self,
+        text: &str,
+        font_size: Pixels,
+        runs: &[FontRun],
+        width: Pixels,
+        max_lines: usize,
+    ) -> Vec<LineLayout> {
+        if max_lines == 0 {
+            return Vec::new();
+        }
+
+        let mut line_ranges = Vec::new();
+        let mut line_start = 0;
+        for wrap_ix in self.wrap_line(text, font_size, runs, width) {
+            line_ranges.push(line_start..wrap_ix);
+            line_start = wrap_ix;
+        }
+        line_ranges.push(line_start..text.len());
+
+        let truncated = line_ranges.len() > max_lines;
+        line_ranges.truncate(max_lines);
+        let mut lines = Vec::with_capacity(line_ranges.len());
+        for (ix, range) in line_ranges.iter().enumerate() {
+            if truncated && ix + 1 == max_lines {
+                lines.push(self.layout_truncated_line(text, range.start, font_size, runs, width));
+            } else {
+                let line_text = &text[range.clone()];
+                let line_runs = font_runs_in_range(runs, range.clone());
+                let mut line =
+                    self.layout_line(line_text, font_size, &line_runs, Shaping::Advanced);
+                if ix + 1 < line_ranges.len() && line_text.ends_with(SOFT_HYPHEN) {
+                    self.push_soft_hyphen(&mut line, &line_runs);
+                }
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    /// Draws the soft hyphen a line was wrapped at as a hyphen at the end of the line, in the font
+    /// of the last run.
+    fn push_soft_hyphen(&mut self, line: &mut LineLayout, runs: &[FontRun]) {
+        let Some(run) = runs.last() else {
+            return;
+        };
+        let Some(glyph_id) = self.glyph_for_char(run.font_id, '-') else {
+            return;
+        };
+        let font_size = run.font_size.unwrap_or(line.font_size);
+        let units_per_em = self.loaded_font(run.font_id).rustybuzz().units_per_em() as f32;
+        let Some(advance) = self.advance(run.font_id, glyph_id).log_err() else {
+            return;
+        };
+        let glyph = ShapedGlyph {
+            id: glyph_id,
+            position: point(line.width, px(0.)),
+            index: line.len - SOFT_HYPHEN.len_utf8(),
+            cluster_len: 0,
+            is_emoji: false,
+        };
+        push_glyph(
+            line,
+            run.font_id,
+            run.font_size,
+            TextDirection::LeftToRight,
+            glyph,
+        );
+        line.width += px(advance.width / units_per_em * font_size.0);
+    }
+
+    /// Returns the byte offsets at which lines start when wrapping the text to the given width,
+    /// measured with the layout `layout_line` produces for it. Like `LineWrapper`, lines break
+    /// before the first character after whitespace other than the indentation, and wherever they
+    /// overflow if there's nowhere else to break. They also break after soft hyphens. Whitespace
+    /// hangs past the end of the line instead of wrapping, so lines don't start with it.
+    fn wrap_line(
+        &mut self,
+        text: &str,
+        font_size: Pixels,
+        runs: &[FontRun],
+        width: Pixels,
+    ) -> Vec<usize> {
+        let unwrapped = self.layout_line(text, font_size, runs, Shaping::Advanced);
+        // Glyphs are in visual order, which is the reverse of the text's in right-to-left runs, so
+        // the advance of each glyph is measured visually and summed up in the text's order.
+        let mut glyphs = unwrapped
+            .runs
+            .iter()
+            .flat_map(|run| run.glyphs.iter())
+            .map(|glyph| (glyph.position.x, glyph.index))
+            .collect::<Vec<_>>();
+        glyphs.sort_by(|(a, _), (b, _)| a.0.total_cmp(&b.0));
+        let mut advances = glyphs
+            .iter()
+            .enumerate()
+            .map(|(ix, (x, index))| {
+                let next_x = glyphs.get(ix + 1).map_or(unwrapped.width, |(x, _)| *x);
+                (*index, next_x - *x)
+            })
+            .collect::<Vec<_>>();
+        advances.sort_by_key(|(index, _)| *index);
+        let mut glyph_xs = Vec::with_capacity(advances.len());
+        let mut x = px(0.);
+        for (index, advance) in advances {
+            glyph_xs.push((index, x));
+            x += advance;
+        }
+        let x_for_index = |index: usize| {
+            let ix = glyph_xs.partition_point(|(glyph_index, _)| *glyph_index < index);
+            glyph_xs.get(ix).map_or(x, |(_, x)| *x)
+        };
+
+        let mut wrap_ixs = Vec::new();
+        let mut line_start = 0;
+        let mut last_candidate_ix = 0;
+        let mut first_non_whitespace_ix = None;
+        let mut prev_ch = '\0';
+        for (ix, ch) in text.char_indices() {
+            let is_whitespace = is_breaking_whitespace(ch);
+            if (is_breaking_whitespace(prev_ch)
+                && !is_whitespace
+                && first_non_whitespace_ix.is_some())
+                || prev_ch == SOFT_HYPHEN
+            {
+                last_candidate_ix = ix;
+            }
+            if !is_whitespace && first_non_whitespace_ix.is_none() {
+                first_non_whitespace_ix = Some(ix);
+            }
+            prev_ch = ch;
+            if is_whitespace {
+                continue;
+            }
+
+            let end = ix + ch.len_utf8();
+            if x_for_index(end) - x_for_index(line_start) > width && ix > line_start {
+                line_start = if last_candidate_ix > line_start {
+                    last_candidate_ix
+                } else {
+                    ix
+                };
+                wrap_ixs.push(line_start);
+            }
+        }
+        wrap_ixs
+    }
+
+    /// Lays out as much of the text from `start` onwards as fits in the given width when followed
+    /// by an ellipsis in the font the text is cut off in.
+    fn layout_truncated_line(
+        &mut self,
+        text: &str,
+        start: usize,
+        font_size: Pixels,
+        runs: &[FontRun],
+        width: Pixels,
+    ) -> LineLayout {
+        const ELLIPSIS: &str = "\u{2026}";
+        let mut ends = text[start..]
+            .char_indices()
+            .map(|(ix, _)| start + ix)
+            .skip(1)
+            .chain(Some(text.len()))
+            .collect::<Vec<_>>();
+        ends.insert(0, start);
+
+        let ellipsis_font = font_runs_in_range(runs, start..text.len())
+            .first()
+            .or(runs.last())
+            .copied();
+        let mut layout_with_ellipsis = |end: usize| {
+            let line_text = text[start..end].trim_end();
+            let mut line_runs = font_runs_in_range(runs, start..start + line_text.len());
+            if let Some(font) = line_runs.last().copied().or(ellipsis_font) {
+                line_runs.push(FontRun {
+                    len: ELLIPSIS.len(),
+                    ..font
+                });
+            }
+            let line_text = format!("{line_text}{ELLIPSIS}");
+            self.layout_line(&line_text, font_size, &line_runs, Shaping::Advanced)
+        };
+
+        // Find the longest prefix that fits, falling back to the ellipsis on its own.
+        let mut fitting = 0;
+        let mut low = 1;
+        let mut high = ends.len() - 1;
+        while low <= high {
+            let mid = (low + high) / 2;
+            if layout_with_ellipsis(ends[mid]).width <= width {
+                fitting = mid;
+                low = mid + 1;
+            } else {
+                high = mid - 1;
+            }
+        }
+        layout_with_ellipsis(ends[fitting])
+    }
+
+    /// Splits the given range of text so that characters no font could be found for, neither
+    /// among the fallback fonts for them nor within `max_fallback_attempts` other fonts, are shaped
+    /// without fallback, which renders them as notdef glyphs instead of letting cosmic-text try
+    /// every font on the system. Replacement characters are never shaped with fallback.
+    fn shaping_ranges(
+        &mut self,
+        text: &str,
+        range: Range<usize>,
+        font_runs: &[FontRun],
+        shaping: Shaping,
+    ) -> SmallVec<[(Range<usize>, Shaping); 1]> {
+        let mut ranges = SmallVec::<[(Range<usize>, Shaping); 1]>::new();
+        if shaping == Shaping::Basic {
+            ranges.push((range, shaping));
+            return ranges;
+        }
+
+        let mut runs = font_runs.iter();
+        let mut run = runs.next();
+        let mut run_end = range.start + run.map_or(0, |run| run.len);
+        for (ix, ch) in text[range.clone()].char_indices() {
+            let ix = range.start + ix;
+            while ix >= run_end {
+                run = runs.next();
+                run_end += run.map_or(0, |run| run.len);
+            }
+
+            let covered = ch.is_control()
+                || ch.is_whitespace()
+                || run.map_or(true, |run| {
+                    self.loaded_font(run.font_id)
+                        .as_swash()
+                        .charmap()
+                        .map(ch)
+                        != 0
+                })
+                // Replacement characters come from undecodable input, so render them in the run's
+                // font, as its replacement glyph or a notdef box, rather than in whichever font
+                // happens to cover them.
+                || (ch != char::REPLACEMENT_CHARACTER && self.fallback_family_for_char(ch).is_some());
+            let char_shaping = if covered { shaping } else { Shaping::Basic };
+
+            match ranges.last_mut() {
+                Some((last_range, last_shaping)) if *last_shaping == char_shaping => {
+                    last_range.end = ix + ch.len_utf8();
+                }
+                _ => ranges.push((ix..ix + ch.len_utf8(), char_shaping)),
+            }
+        }
+        if ranges.is_empty() {
+            ranges.push((range, shaping));
+        }
+        ranges
+    }
+
+    /// Returns the family of the first font in the database that covers the given character, in the
+    /// order cosmic-text falls back in: the fallback families, the fonts for the character's script
+    /// and the common fallback families first, then any other font, giving up after
+    /// `max_fallback_attempts` of those. The result is cached until the set of fonts changes.
+    fn fallback_family_for_char(&mut self, ch: char) -> Option<SharedString> {
+        if let Some(family) = self.fallback_coverage.get(&ch) {
+            return family.clone();
+        }
+
+        let db = self.font_system.db();
+        let preset_families = self
+            .fallback_families
+            .iter()
+            .map(String::as_str)
+            .chain(
+                script_fallback_families(ch, self.font_system.locale())
+                    .iter()
+                    .copied(),
+            )
+            .chain(COMMON_FALLBACK_FAMILIES.iter().copied());
+        let preset_face_ids = preset_families
+            .flat_map(|preset_family| {
+                db.faces()
+                    .filter(move |face| {
+                        face.families
+                            .iter()
+                            .any(|(family, _)| family == preset_family)
+                    })
+                    .map(|face| face.id)
+            })
+            .unique()
+            .collect::<Vec<_>>();
+        // Of the other fonts, those that have already been used are the most likely to cover the
+        // character, so they're tried before the rest of the database.
+        let other_face_ids = self
+            .loaded_fonts_store
+            .iter()
+            .flatten()
+            .map(|font| font.id())
+            .chain(db.faces().map(|face| face.id))
+            .filter(|id| !preset_face_ids.contains(id))
+            .unique()
+            .take(self.max_fallback_attempts)
+            .collect::<Vec<_>>();
+        let face_id = preset_face_ids
+            .into_iter()
+            .chain(other_face_ids)
+            .find(|id| {
+                self.font_system
+                    .get_font(*id)
+                    .map_or(false, |font| font.as_swash().charmap().map(ch) != 0)
+            });
+        let family = face_id.and_then(|id| {
+            let face = self.font_system.db().face(id)?;
+            Some(SharedString::from(face.families.first()?.0.clone()))
+        });
+        if family.is_none() {
+            log::warn!(
+                "no font covering {ch:?} (U+{:04X}) was found among the fallback fonts for it and \
+                {} other fonts",
+                ch as u32,
+                self.max_fallback_attempts
+            );
+        }
+        self.fallback_coverage.insert(ch, family.clone());
+        family
+    }
+
+    /// Finds a glyph for a control picture, preferring the font of the surrounding text, then fonts
+    /// that are already in use, then the fallback families.
+    fn control_picture_glyph(
+        &mut self,
+        run_font_id: Option<FontId>,
+        picture: char,
+    ) -> Option<(FontId, GlyphId)> {
+        let loaded_font_ids = (0..self.loaded_fonts_store.len())
+            .map(FontId)
+            .filter(|font_id| self.loaded_fonts_store[font_id.0].is_some())
+            .collect::<SmallVec<[_; 8]>>();
+        if let Some(glyph) = run_font_id
+            .into_iter()
+            .chain(loaded_font_ids)
+            .find_map(|font_id| Some((font_id, self.glyph_for_char(font_id, picture)?)))
+        {
+            return Some(glyph);
+        }
+
+        for family in self.fallback_families.clone() {
+            let family = SharedString::from(family);
+            if self
+                .cache_family(&family, &FontFeatures::default())
+                .is_err()
+            {
+                continue;
+            }
+            if let Some(glyph) = self.font_ids_by_family_cache[&family]
+                .iter()
+                .find_map(|font_id| Some((*font_id, self.glyph_for_char(*font_id, picture)?)))
+            {
+                return Some(glyph);
+            }
+        }
+        None
+    }
+
+    /// Splits the spans so that characters their font doesn't cover use the first fallback
+    /// override that covers them, or else the family `fallback_family_for_char` finds, if any.
+    /// cosmic-text's own fallback only considers faces of the same width and style as the text, so
+    /// e.g. Hebrew in an extended monospace font would otherwise never find a font.
+    fn apply_fallback_fonts(&mut self, text: &str, spans: FontSpans) -> FontSpans {
+        let mut overridden = FontSpans::new();
+        for (span, font_id) in spans {
+            for (ix, ch) in text[span.clone()].char_indices() {
+                let ix = span.start + ix;
+                let covered = ch.is_control()
+                    || ch.is_whitespace()
+                    || ch == char::REPLACEMENT_CHARACTER
+                    || is_zero_width_format_char(ch)
+                    || self.glyph_for_char(font_id, ch).is_some();
+                let char_font_id = if covered {
+                    font_id
+                } else {
+                    self.fallback_font_for_char(font_id, ch).unwrap_or(font_id)
+                };
+                match overridden.last_mut() {
+                    Some((last_span, last_font_id))
+                        if last_span.end == ix && *last_font_id == char_font_id =>
+                    {
+                        last_span.end = ix + ch.len_utf8();
+                    }
+                    _ => overridden.push((ix..ix + ch.len_utf8(), char_font_id)),
+                }
+            }
+        }
+        overridden
+    }
+
+    /// Returns a face covering the character from the most recently pushed fallback override that
+    /// covers it, or else from the family `fallback_family_for_char` finds. Of the family's faces,
+    /// the one closest in style and weight to the font of the run is used.
+    fn fallback_font_for_char(&mut self, run_font_id: FontId, ch: char) -> Option<FontId> {
+        let fallback_family = self.fallback_family_for_char(ch);
+        let families = self
+            .fallback_overrides
+            .iter()
+            .rev()
+            .cloned()
+            .chain(fallback_family)
+            .collect::<SmallVec<[_; 8]>>();
+        for family in families {
+            if let Err(error) = self.cache_family(&family, &FontFeatures::default()) {
+                log::warn!("failed to load fallback family {family:?}: {error:?}");
+                continue;
+            }
+            let db = self.font_system.db();
+            let run_face = db.face(self.loaded_font(run_font_id).id());
+            let font_id = self.font_ids_by_family_cache[&family]
+                .iter()
+                .copied()
+                .filter(|font_id| self.glyph_for_char(*font_id, ch).is_some())
+                .min_by_key(|font_id| {
+                    let face = db.face(self.loaded_font(*font_id).id());
+                    match (run_face, face) {
+                        (Some(run_face), Some(face)) => (
+                            run_face.style != face.style,
+                            run_face.weight.0.abs_diff(face.weight.0),
+                        ),
+                        _ => (true, u16::MAX),
+                    }
+                });
+            if font_id.is_some() {
+                return font_id;
+            }
+        }
+        None
+    }
+
+    /// Shapes the parts of a paragraph whose runs differ in font size each at their own size, so
+    /// that their advances and metrics are the font's at that size, and appends them to the line
+    /// in visual order. Like the bidi algorithm reorders runs, parts in the other direction than
+    /// the paragraph's, which is that of its first part, are reversed among themselves.
+    fn shape_font_size_ranges(
+        &mut self,
+        layout: &mut LineLayout,
+        text: &str,
+        size_ranges: SmallVec<[(Range<usize>, Pixels); 1]>,
+        font_runs: &[FontRun],
+        shaping: Shaping,
+    ) {
+        let mut parts = size_ranges
+            .into_iter()
+            .map(|(range, font_size)| {
+                let mut part = LineLayout {
+                    font_size: layout.font_size,
+                    ..Default::default()
+                };
+                self.shape_range(&mut part, text, range, font_runs, font_size, shaping);
+                let is_rtl = part
+                    .runs
+                    .iter()
+                    .flat_map(|run| run.glyphs.iter().map(|glyph| (glyph.index, run.direction)))
+                    .min_by_key(|(index, _)| *index)
+                    .map_or(false, |(_, direction)| {
+                        direction == TextDirection::RightToLeft
+                    });
+                (part, is_rtl)
+            })
+            .collect::<SmallVec<[_; 2]>>();
+        let is_rtl_paragraph = parts.first().map_or(false, |(_, is_rtl)| *is_rtl);
+        if is_rtl_paragraph {
+            parts.reverse();
+        }
+        let mut ix = 0;
+        while ix < parts.len() {
+            let len = parts[ix..]
+                .iter()
+                .take_while(|(_, is_rtl)| *is_rtl != is_rtl_paragraph)
+                .count();
+            parts[ix..ix + len].reverse();
+            ix += len.max(1);
+        }
+
+        for (part, _) in parts {
+            let offset = layout.width;
+            for run in &part.runs {
+                for glyph in &run.glyphs {
+                    push_glyph(
+                        layout,
+                        run.font_id,
+                        run.font_size,
+                        run.direction,
+                        ShapedGlyph {
+                            position: point(offset + glyph.position.x, glyph.position.y),
+                            ..glyph.clone()
+                        },
+                    );
+                }
+            }
+            layout.width += part.width;
+            layout.ascent = layout.ascent.max(part.ascent);
+            layout.descent = layout.descent.max(part.descent);
+        }
+    }
+
+    /// Shapes the given range of text at the given font size and appends its glyphs to the line.
+    fn shape_range(
+        &mut self,
+        layout: &mut LineLayout,
+        text: &str,
+        range: Range<usize>,
+        font_runs: &[FontRun],
+        font_size: Pixels,
+        shaping: Shaping,
+    ) {
+        let mut spans = FontSpans::new();
+        let mut offs = 0;
+        for run in font_runs {
+            // todo(linux) We need to check we are doing utf properly
+            let span = offs.max(range.start)..(offs + run.len).min(range.end);
+            offs += run.len;
+            if !span.is_empty() {
+                spans.push((
+                    (span.start - range.start)..(span.end - range.start),
+                    run.font_id,
+                ));
+            }
+        }
+        // Metrics only account for the fonts of the runs, see `normalize_fallback_metrics`.
+        let run_font_ids = spans
+            .iter()
+            .map(|(_, font_id)| *font_id)
+            .collect::<SmallVec<[_; 4]>>();
+        spans = self.apply_fallback_fonts(&text[range.clone()], spans);
+        let attrs_list = self.attrs_list(&spans);
+        let mut line = BufferLine::new(&text[range.clone()], attrs_list, shaping);
+        // Right-to-left lines are laid out from the right edge of the given width. We do our own
+        // wrapping, so with no width and left alignment, every line starts at 0.
+        line.set_align(Some(Align::Left));
+        let line_layout = line.layout(
+            &mut self.font_system,
+            font_size.0,
+            0.,
+            cosmic_text::Wrap::None,
+            None,
+        );
+
+        // Should cosmic-text still break the line, its lines are placed one after another.
+        let (line_width, max_ascent, max_descent) =
+            line_layout
+                .iter()
+                .fold((0., 0., 0.), |(width, ascent, descent), line| {
+                    (
+                        width + line.w,
+                        f32::max(ascent, line.max_ascent),
+                        f32::max(descent, line.max_descent),
+                    )
+                });
+        let mut glyphs = if let [line_layout] = line_layout {
+            Cow::Borrowed(line_layout.glyphs.as_slice())
+        } else {
+            let mut line_x = 0.;
+            Cow::Owned(
+                line_layout
+                    .iter()
+                    .flat_map(|line| {
+                        let offset = line_x;
+                        line_x += line.w;
+                        line.glyphs.iter().map(move |glyph| LayoutGlyph {
+                            x: glyph.x + offset,
+                            ..glyph.clone()
+                        })
+                    })
+                    .collect(),
+            )
+        };
+        let mut features_width = 0.;
+        if spans
+            .iter()
+            .any(|(_, font_id)| self.needs_reshaping(*font_id))
+        {
+            let (reshaped, width) =
+                self.reshape_with_font_settings(&text[range.clone()], &spans, &glyphs);
+            glyphs = Cow::Owned(reshaped);
+            features_width = width;
+        }
+        let run_font_id_at = |ix: usize| {
+            let mut run_end = 0;
+            font_runs
+                .iter()
+                .find(|run| {
+                    run_end += run.len;
+                    ix < run_end
+                })
+                .map(|run| run.font_id)
+        };
+        // Runs keep the font size they override the line's with, even if it's the line's size.
+        let run_font_size_at = |ix: usize| {
+            let mut run_end = 0;
+            font_runs
+                .iter()
+                .find(|run| {
+                    run_end += run.len;
+                    ix < run_end
+                })
+                .and_then(|run| run.font_size)
+        };
+        let mut emoji_overflow = px(0.);
+        // Invisible format characters take up no space, even if the font maps them to a glyph, and
+        // neither do control characters unless they're displayed as control pictures.
+        let mut format_char_advance = px(0.);
+        // Synthesized bold widens glyphs, pushing the ones after them over.
+        let mut synthetic_bold_advance = px(0.);
+        for glyph in glyphs.iter() {
+            let cluster = text
+                .get(range.start + glyph.start..range.start + glyph.end)
+                .filter(|cluster| !cluster.is_empty());
+            if let Some(picture) = cluster
+                .filter(|cluster| cluster.chars().count() == 1)
+                .and_then(|cluster| control_picture(cluster.chars().next()?))
+            {
+                let picture_glyph = match self.control_character_display {
+                    ControlCharacterDisplay::Hidden => None,
+                    ControlCharacterDisplay::Pictures => {
+                        let run_font_id = run_font_id_at(range.start + glyph.start);
+                        self.control_picture_glyph(run_font_id, picture)
+                    }
+                };
+                let mut advance = px(0.);
+                if let Some((font_id, glyph_id)) = picture_glyph {
+                    let position = point(
+                        layout.width + emoji_overflow + synthetic_bold_advance
+                            - format_char_advance
+                            + px(glyph.x),
+                        px(glyph.y),
+                    );
+                    push_glyph(
+                        layout,
+                        font_id,
+                        run_font_size_at(range.start + glyph.start),
+                        TextDirection::LeftToRight,
+                        ShapedGlyph {
+                            id: glyph_id,
+                            position,
+                            index: range.start + glyph.start,
+                            cluster_len: 0,
+                            is_emoji: false,
+                        },
+                    );
+                    let units_per_em = self.loaded_font(font_id).rustybuzz().units_per_em() as f32;
+                    if let Some(size) = self.advance(font_id, glyph_id).log_err() {
+                        advance = px(size.width / units_per_em * glyph.font_size);
+                    }
+                }
+                format_char_advance += px(glyph.w) - advance;
+                continue;
+            }
+            if cluster.map_or(false, |cluster| {
+                cluster.chars().all(is_zero_width_format_char)
+            }) {
+                format_char_advance += px(glyph.w);
+                continue;
+            }
+            // Characters that no font covers are shaped to the notdef glyph.
+            if glyph.glyph_id == 0 && !self.notdef_glyphs_enabled {
+                format_char_advance += px(glyph.w);
+                continue;
+            }
+
+            // Glyphs from the face of their run keep the run's font id, which may have features.
+            let run_font_id = run_font_id_at(range.start + glyph.start)
+                .filter(|font_id| self.loaded_font(*font_id).id() == glyph.font_id);
+            // Glyphs in fonts that can't be loaded are left out, leaving a gap.
+            let Some(font_id) = run_font_id.or_else(|| self.font_id_for_cosmic_id(glyph.font_id))
+            else {
+                continue;
+            };
+            let is_emoji = self.is_emoji(font_id);
+            let mut position = point(
+                layout.width + emoji_overflow + synthetic_bold_advance - format_char_advance
+                    + px(glyph.x),
+                px(glyph.y),
+            );
+            if self.is_synthetic_bold(font_id) {
+                synthetic_bold_advance += px(glyph.font_size * SYNTHETIC_BOLD_STRENGTH);
+            }
+            if is_emoji {
+                if let Some(ink_bounds) =
+                    self.emoji_ink_bounds(font_id, GlyphId(glyph.glyph_id.into()), glyph.font_size)
+                {
+                    let text_font_id = run_font_id_at(range.start + glyph.start);
+                    position.y +=
+                        self.emoji_baseline_offset(ink_bounds, text_font_id, glyph.font_size);
+                    let right = ink_bounds.origin.x + ink_bounds.size.width;
+                    emoji_overflow += px((right - glyph.w).max(0.));
+                }
+            }
+
+            let direction = if glyph.level.is_rtl() {
+                TextDirection::RightToLeft
+            } else {
+                TextDirection::LeftToRight
+            };
+            push_glyph(
+                layout,
+                font_id,
+                run_font_size_at(range.start + glyph.start),
+                direction,
+                ShapedGlyph {
+                    id: GlyphId(glyph.glyph_id as u32),
+                    position,
+                    index: range.start + glyph.start,
+                    cluster_len: 0,
+                    is_emoji: is_emoji && self.color_glyphs_enabled,
+                },
+            );
+        }
+
+        layout.width += px(line_width + features_width) + emoji_overflow + synthetic_bold_advance
+            - format_char_advance;
+        let (ascent, descent) = if self.normalize_fallback_metrics {
+            run_font_ids
+                .iter()
+                .map(|font_id| {
+                    let metrics = self
+                        .loaded_font(*font_id)
+                        .as_swash()
+                        .metrics(&self.normalized_coords(*font_id));
+                    let units_per_em = metrics.units_per_em as f32;
+                    (
+                        metrics.ascent / units_per_em * font_size.0,
+                        metrics.descent / units_per_em * font_size.0,
+                    )
+                })
+                .fold((0., 0.), |(ascent, descent), (run_ascent, run_descent)| {
+                    (f32::max(ascent, run_ascent), f32::max(descent, run_descent))
+                })
+        } else {
+            (max_ascent, max_descent)
+        };
+        layout.ascent = layout.ascent.max(px(ascent));
+        layout.descent = layout.descent.max(px(descent));
+    }
+
+    /// Whether the font has OpenType features or variation coordinates that cosmic-text doesn't
+    /// know about.
+    fn needs_reshaping(&self, font_id: FontId) -> bool {
+        self.font_features.contains_key(&font_id) || self.font_variations.contains_key(&font_id)
+    }
+
+    /// Shapes the glyphs of spans whose font has OpenType features or variation coordinates again
+    /// with those settings, since cosmic-text always shapes with a font's default features at its
+    /// default instance. This also applies the alternates a variable font's `rvrn` feature selects
+    /// for its coordinates. Glyphs that fell back to other fonts are left as they are. Returns the
+    /// glyphs and how much wider they are than before.
+    fn reshape_with_font_settings(
+        &self,
+        text: &str,
+        spans: &FontSpans,
+        glyphs: &[LayoutGlyph],
+    ) -> (Vec<LayoutGlyph>, f32) {
+        let mut reshaped = Vec::with_capacity(glyphs.len());
+        let mut width_delta = 0.;
+        let mut ix = 0;
+        while ix < glyphs.len() {
+            let first = &glyphs[ix];
+            let reshaped_span = spans.iter().find(|(span, font_id)| {
+                span.contains(&first.start)
+                    && self.needs_reshaping(*font_id)
+                    && self.loaded_font(*font_id).id() == first.font_id
+            });
+            let Some((span, font_id)) = reshaped_span else {
+                let mut glyph = first.clone();
+                glyph.x += width_delta;
+                reshaped.push(glyph);
+                ix += 1;
+                continue;
+            };
+
+            let group_len = glyphs[ix..]
+                .iter()
+                .take_while(|glyph| {
+                    glyph.font_id == first.font_id
+                        && glyph.level.is_rtl() == first.level.is_rtl()
+                        && span.contains(&glyph.start)
+                })
+                .count();
+            let group = &glyphs[ix..ix + group_len];
+            ix += group_len;
+            let group_start = group.iter().map(|glyph| glyph.start).min().unwrap_or(0);
+            let group_end = group.iter().map(|glyph| glyph.end).max().unwrap_or(0);
+            let old_width = group.iter().map(|glyph| glyph.w).sum::<f32>();
+
+            let mut face = self.loaded_font(*font_id).rustybuzz().clone();
+            if let Some(variations) = self.font_variations.get(font_id) {
+                face.set_variations(variations);
+            }
+            let features = self
+                .font_features
+                .get(font_id)
+                .map_or(&[][..], Vec::as_slice);
+            let mut buffer = rustybuzz::UnicodeBuffer::new();
+            buffer.push_str(&text[group_start..group_end]);
+            buffer.set_direction(if first.level.is_rtl() {
+                rustybuzz::Direction::RightToLeft
+            } else {
+                rustybuzz::Direction::LeftToRight
+            });
+            buffer.guess_segment_properties();
+            let output = rustybuzz::shape(&face, features, buffer);
+            // Glyphs come out in visual order, so the clusters of right-to-left text descend.
+            let clusters = output
+                .glyph_infos()
+                .iter()
+                .map(|info| info.cluster)
+                .collect::<BTreeSet<_>>();
+
+            let scale = first.font_size / face.units_per_em() as f32;
+            let mut pen = first.x - first.x_offset * first.font_size + width_delta;
+            let mut new_width = 0.;
+            for (info, position) in output.glyph_infos().iter().zip(output.glyph_positions()) {
+                let cluster_end = clusters
+                    .range(info.cluster + 1..)
+                    .next()
+                    .map_or(group_end, |cluster| group_start + *cluster as usize);
+                let mut glyph = first.clone();
+                glyph.start = group_start + info.cluster as usize;
+                glyph.end = cluster_end;
+                glyph.glyph_id = info.glyph_id as u16;
+                glyph.x_offset = position.x_offset as f32 * scale / first.font_size;
+                glyph.y_offset = position.y_offset as f32 * scale / first.font_size;
+                glyph.x = pen + position.x_offset as f32 * scale;
+                glyph.y = -position.y_offset as f32 * scale;
+                glyph.w = position.x_advance as f32 * scale;
+                pen += glyph.w;
+                new_width += glyph.w;
+                reshaped.push(glyph);
+            }
+            width_delta += new_width - old_width;
+        }
+        (reshaped, width_delta)
+    }
+}
+
+/// Sorts the names case-insensitively, e.g. for a font picker, and removes duplicates.
+fn sorted_font_names(mut names: Vec<String>) -> Vec<String> {
+    names.sort_by_cached_key(|name| (name.to_lowercase(), name.clone()));
+    names.dedup();
+    names
+}
+
+/// Splits the range after each character that ends a bidi paragraph, such as a newline.
+fn paragraph_ranges(text: &str, range: Range<usize>) -> SmallVec<[Range<usize>; 1]> {
+    let mut ranges = SmallVec::new();
+    let mut start = range.start;
+    for (ix, ch) in text[range.clone()].char_indices() {
+        if matches!(
+            ch,
+            '\n' | '\r' | '\u{1c}'..='\u{1e}' | '\u{85}' | '\u{2029}'
+        ) {
+            let end = range.start + ix + ch.len_utf8();
+            ranges.push(start..end);
+            start = end;
+        }
+    }
+    if start < range.end || ranges.is_empty() {
+        ranges.push(start..range.end);
+    }
+    ranges
+}
+
+/// Identifies font data by a hash and its length.
+fn font_data_key(data: &[u8]) -> (u64, usize) {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    (hasher.finish(), data.len())
+}
+
+/// Returns whether the face has color glyphs in a COLR, CBDT, sbix or SVG table.
+fn has_color_tables(face: &ttf_parser::Face) -> bool {
+    let tables = face.tables();
+    tables.colr.is_some() || tables.cbdt.is_some() || tables.sbix.is_some() || tables.svg.is_some()
+}
+
+/// Returns the OpenType features that are explicitly enabled or disabled.
+fn opentype_features(features: &FontFeatures) -> Vec<rustybuzz::Feature> {
+    [
+        (b"calt", features.calt()),
+        (b"case", features.case()),
+        (b"cpsp", features.cpsp()),
+        (b"frac", features.frac()),
+        (b"liga", features.liga()),
+        (b"onum", features.onum()),
+        (b"ordn", features.ordn()),
+        (b"pnum", features.pnum()),
+        (b"ss01", features.ss01()),
+        (b"ss02", features.ss02()),
+        (b"ss03", features.ss03()),
+        (b"ss04", features.ss04()),
+        (b"ss05", features.ss05()),
+        (b"ss06", features.ss06()),
+        (b"ss07", features.ss07()),
+        (b"ss08", features.ss08()),
+        (b"ss09", features.ss09()),
+        (b"ss10", features.ss10()),
+        (b"ss11", features.ss11()),
+        (b"ss12", features.ss12()),
+        (b"ss13", features.ss13()),
+        (b"ss14", features.ss14()),
+        (b"ss15", features.ss15()),
+        (b"ss16", features.ss16()),
+        (b"ss17", features.ss17()),
+        (b"ss18", features.ss18()),
+        (b"ss19", features.ss19()),
+        (b"ss20", features.ss20()),
+        (b"subs", features.subs()),
+        (b"sups", features.sups()),
+        (b"swsh", features.swsh()),
+        (b"titl", features.titl()),
+        (b"tnum", features.tnum()),
+        (b"zero", features.zero()),
+    ]
+    .into_iter()
+    .filter_map(|(tag, enabled)| {
+        Some(rustybuzz::Feature::new(
+            ttf_parser::Tag::from_bytes(tag),
+            enabled? as u32,
+            ..,
+        ))
+    })
+    .collect()
+}
+
+/// Appends a glyph to the last run of the layout, or starts a new run if the glyph's font, size or
+/// direction differ from it.
+fn push_glyph(
+    layout: &mut LineLayout,
+    font_id: FontId,
+    font_size: Option<Pixels>,
+    direction: TextDirection,
+    glyph: ShapedGlyph,
+) {
+    match layout.runs.last_mut() {
+        Some(run)
+            if run.font_id == font_id
+                && run.font_size == font_size
+                && run.direction == direction =>
+        {
+            run.glyphs.push(glyph);
+        }
+        _ => {
+            let mut glyphs = SmallVec::new();
+            glyphs.push(glyph);
+            layout.runs.push(crate::ShapedRun {
+                font_id,
+                font_size,
+                glyphs,
+                direction,
+            });
+        }
+    }
+}
+
+/// Splits the given range of text where the font size of its runs changes, returning each part
+/// with the size its runs are laid out at.
+fn font_size_ranges(
+    runs: &[FontRun],
+    range: Range<usize>,
+    line_font_size: Pixels,
+) -> SmallVec<[(Range<usize>, Pixels); 1]> {
+    let mut ranges = SmallVec::<[(Range<usize>, Pixels); 1]>::new();
+    let mut run_start = 0;
+    for run in runs {
+        let start = run_start.max(range.start);
+        let end = (run_start + run.len).min(range.end);
+        run_start += run.len;
+        if start >= end {
+            continue;
+        }
+        let run_font_size = run.font_size.unwrap_or(line_font_size);
+        match ranges.last_mut() {
+            Some((last_range, font_size)) if *font_size == run_font_size => {
+                last_range.end = end;
+            }
+            _ => ranges.push((start..end, run_font_size)),
+        }
+    }
+    ranges
+}
+
+/// Returns the parts of the font runs that cover the given range of text.
+fn font_runs_in_range(runs: &[FontRun], range: Range<usize>) -> SmallVec<[FontRun; 4]> {
+    let mut run_start = 0;
+    let mut line_runs = SmallVec::new();
+    for run in runs {
+        let run_range = run_start..run_start + run.len;
+        run_start += run.len;
+        let start = run_range.start.max(range.start);
+        let end = run_range.end.min(range.end);
+        if start < end {
+            line_runs.push(FontRun {
+                len: end - start,
+                ..*run
+            });
+        }
+    }
+    line_runs
+}
+
+/// Returns whether the character is an invisible formatting control, such as a zero-width space or
+/// joiner, a bidi mark, or a soft hyphen, which is only drawn when a line is wrapped at it.
+fn is_zero_width_format_char(ch: char) -> bool {
+    matches!(
+        ch,
+        SOFT_HYPHEN
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}'
+    )
+}
+
+/// Records where the line's grapheme clusters start, and moves the index of each glyph to the
+/// start of its cluster. A glyph extends up to the next glyph in the text, so glyphs that
+/// render several clusters, such as ligatures, cover all of them.
+fn compute_grapheme_boundaries(layout: &mut LineLayout, text: &str) {
+    let boundaries = text
+        .grapheme_indices(true)
+        .map(|(ix, _)| ix)
+        .collect::<Vec<_>>();
+    let mut glyph_starts = layout
+        .runs
+        .iter()
+        .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.index))
+        .collect::<Vec<_>>();
+    glyph_starts.sort_unstable();
+    glyph_starts.dedup();
+
+    // Text always starts a cluster, so every glyph index is in one.
+    let cluster_start = |ix: usize| boundaries[boundaries.partition_point(|b| *b <= ix) - 1];
+    let cluster_end = |ix: usize| {
+        boundaries
+            .get(boundaries.partition_point(|b| *b < ix))
+            .copied()
+            .unwrap_or(text.len())
+    };
+    if !boundaries.is_empty() {
+        for glyph in layout.runs.iter_mut().flat_map(|run| run.glyphs.iter_mut()) {
+            let next_glyph_start = glyph_starts
+                .get(glyph_starts.partition_point(|start| *start <= glyph.index))
+                .copied()
+                .unwrap_or(text.len());
+            let start = cluster_start(glyph.index);
+            glyph.cluster_len = cluster_end(next_glyph_start) - start;
+            glyph.index = start;
+        }
+    }
+    layout.grapheme_boundaries = boundaries;
+}
+
+/// Returns where the caret can be placed in a laid out line, as x positions and the grapheme
+/// boundaries they're at, in order of position. Boundaries inside a glyph that renders several
+/// clusters, such as a ligature, are spread evenly across its advance.
+fn caret_stops(layout: &LineLayout) -> Vec<(Pixels, usize)> {
+    // Boundaries are computed by `layout_line`, which also snaps glyph indices to them.
+    let boundaries = &layout.grapheme_boundaries;
+    let cluster_start = |ix: usize| match boundaries.partition_point(|b| *b <= ix) {
+        0 => ix,
+        end => boundaries[end - 1],
+    };
+    let mut glyph_stops = layout
+        .runs
+        .iter()
+        .flat_map(|run| run.glyphs.iter())
+        .map(|glyph| (glyph.position.x, cluster_start(glyph.index)))
+        .chain([(layout.width, layout.len)])
+        .collect::<Vec<_>>();
+    glyph_stops.sort();
+    // Glyphs that render the same cluster, like combining marks, share the stop of the first.
+    glyph_stops.dedup_by_key(|(_, index)| *index);
+
+    let mut stops = Vec::with_capacity(glyph_stops.len());
+    for (&(start_x, start), &(end_x, end)) in glyph_stops.iter().tuple_windows() {
+        stops.push((start_x, start));
+        let inner = boundaries
+            .iter()
+            .filter(|b| start < **b && **b < end)
+            .collect::<Vec<_>>();
+        let step = (end_x - start_x) / (inner.len() + 1) as f32;
+        stops.extend(
+            inner
+                .into_iter()
+                .enumerate()
+                .map(|(ix, b)| (start_x + step * (ix + 1) as f32, *b)),
+        );
+    }
+    stops.extend(glyph_stops.last());
+    stops
+}
+
+/// Returns the symbol from the Control Pictures block that stands for the given C0 control
+/// character or DEL. Tabs, line feeds and carriage returns are laid out as whitespace instead, so
+/// they have no picture.
+fn control_picture(ch: char) -> Option<char> {
+    match ch {
+        '\t' | '\n' | '\r' => None,
+        '\0'..='\u{1F}' => char::from_u32(0x2400 + ch as u32),
+        '\u{7F}' => Some('\u{2421}'),
+        _ => None,
+    }
+}
+
+/// Whether a font size is too small to produce any visible glyphs. Shaping and rasterizing at such
+/// sizes yields degenerate results, so they're skipped entirely.
+fn is_degenerate_font_size(font_size: impl Into<f32>) -> bool {
+    let font_size = font_size.into();
+    font_size.is_nan() || font_size < 1.
+}
+
+/// Rejects scale factors that can't come from a display. A scale factor of zero is allowed, and
+/// like other sizes too small to see renders nothing.
+fn check_scale_factor(scale_factor: f32) -> Result<()> {
+    if scale_factor.is_finite() && scale_factor >= 0. {
+        Ok(())
+    } else {
+        Err(anyhow!("invalid scale factor {scale_factor}"))
+    }
+}
+
+/// Loads the system fonts into the given database. If a cache file is given that lists the font
+/// files loaded last time, and none of their directories changed since, only those files are
+/// loaded. Otherwise fontdb searches the system font directories, and the files it found are
+/// written to the cache file.
+fn load_system_fonts(
+    db: &mut fontdb::Database,
+    modified_times: &mut HashMap<PathBuf, Option<SystemTime>>,
+    font_path_cache: Option<&Path>,
+) -> SystemFontStats {
+    let stats = match font_path_cache.and_then(read_font_path_cache) {
+        Some(cache) => {
+            set_generic_families(db, &cache.generic_families);
+            load_font_files(db, &cache.paths, modified_times)
+        }
+        None => {
+            let stats = discover_system_fonts(db, modified_times);
+            if let Some(cache_file) = font_path_cache {
+                write_font_path_cache(
+                    cache_file,
+                    font_file_paths(db.faces()),
+                    generic_families(db),
+                );
+            }
+            stats
+        }
+    };
+    log::info!(
+        "loaded {} of {} system font files ({} failed)",
+        stats.loaded,
+        stats.discovered,
+        stats.failed
+    );
+    stats
+}
+
+/// Loads the fonts that fontdb finds in the system font directories. fontdb skips the files it
+/// can't read or parse, only logging why, so the files it loaded faces from are counted as both
+/// discovered and loaded, and none as failed.
+fn discover_system_fonts(
+    db: &mut fontdb::Database,
+    modified_times: &mut HashMap<PathBuf, Option<SystemTime>>,
+) -> SystemFontStats {
+    let existing_face_ids = db.faces().map(|face| face.id).collect::<HashSet<_>>();
+    db.load_system_fonts();
+    let paths = font_file_paths(
+        db.faces()
+            .filter(|face| !existing_face_ids.contains(&face.id)),
+    );
+    for path in &paths {
+        modified_times.insert(path.clone(), file_modified_time(path));
+    }
+    SystemFontStats {
+        discovered: paths.len(),
+        loaded: paths.len(),
+        failed: 0,
+    }
+}
+
+/// Loads the faces in the given font files into the database. Parsing the files dominates startup,
+/// so it's spread over several threads, each loading a contiguous chunk of the files into a
+/// database of its own. Their faces are then added in the order of the files, so that they get
+/// the same ids every time.
+fn load_font_files(
+    db: &mut fontdb::Database,
+    paths: &[PathBuf],
+    modified_times: &mut HashMap<PathBuf, Option<SystemTime>>,
+) -> SystemFontStats {
+    let thread_count = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let chunk_size = paths.len().div_ceil(thread_count).max(1);
+    let loaded_files = std::thread::scope(|scope| {
+        paths
+            .chunks(chunk_size)
+            .map(|paths| {
+                let thread = scope.spawn(move || {
+                    paths
+                        .iter()
+                        .map(|path| (path, file_modified_time(path), parse_font_file(path)))
+                        .collect::<Vec<_>>()
+                });
+                (paths, thread)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|(paths, thread)| {
+                thread.join().unwrap_or_else(|_| {
+                    log::error!(
+                        "loading {} font files, starting with {:?}, panicked",
+                        paths.len(),
+                        paths[0]
+                    );
+                    // Without a modification time, the files are loaded again when the system
+                    // fonts are reloaded.
+                    paths
+                        .iter()
+                        .map(|path| (path, None, Err(FontLoadError::LoadPanicked(path.clone()))))
+                        .collect()
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut stats = SystemFontStats {
+        discovered: paths.len(),
+        ..Default::default()
+    };
+    for (path, modified_time, faces) in loaded_files {
+        modified_times.insert(path.clone(), modified_time);
+        match faces {
+            // The panic was already logged for all the files the thread was loading.
+            Err(FontLoadError::LoadPanicked(_)) => stats.failed += 1,
+            Err(error) => {
+                log::warn!("{error}");
+                stats.failed += 1;
+            }
+            Result::Ok(faces) => {
+                for face in faces {
+                    db.push_face_info(face);
+                }
+                stats.loaded += 1;
+            }
+        }
+    }
+    stats
+}
+
+/// Parses the faces in a font file, without adding them to any database.
+fn parse_font_file(path: &Path) -> Result<Vec<fontdb::FaceInfo>, FontLoadError> {
+    let mut db = fontdb::Database::new();
+    load_system_font_file(&mut db, path)?;
+    Result::Ok(db.faces().cloned().collect())
+}
+
+/// Loads the faces in a system font file.
+fn load_system_font_file(db: &mut fontdb::Database, path: &Path) -> Result<(), FontLoadError> {
+    let face_count = db.len();
+    match db.load_font_file(path) {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            Err(FontLoadError::FileNotFound(path.to_path_buf()))
+        }
+        Err(error) => Err(FontLoadError::Io {
+            path: path.to_path_buf(),
+            error,
+        }),
+        // Files that can't be parsed are skipped by fontdb without an error.
+        _ if db.len() == face_count => Err(FontLoadError::NoFaces(path.to_path_buf())),
+        _ => Result::Ok(()),
+    }
+}
+
+fn file_modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Returns the file the face was loaded from, if it wasn't loaded from memory.
+fn face_path(face: &fontdb::FaceInfo) -> Option<&Path> {
+    match &face.source {
+        fontdb::Source::File(path) | fontdb::Source::SharedFile(path, _) => Some(path),
+        fontdb::Source::Binary(_) => None,
+    }
+}
+
+/// Returns the files the given faces were loaded from, sorted so that faces are loaded in the
+/// same order every time.
+fn font_file_paths<'a>(faces: impl Iterator<Item = &'a fontdb::FaceInfo>) -> Vec<PathBuf> {
+    faces
+        .filter_map(face_path)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+/// The generic families, in the order their names are persisted in a [`FontPathCache`].
+const GENERIC_FAMILIES: [Family<'static>; 5] = [
+    Family::Serif,
+    Family::SansSerif,
+    Family::Monospace,
+    Family::Cursive,
+    Family::Fantasy,
+];
+
+fn generic_families(db: &fontdb::Database) -> Vec<String> {
+    GENERIC_FAMILIES
+        .iter()
+        .map(|family| db.family_name(family).to_string())
+        .collect()
+}
+
+fn set_generic_families(db: &mut fontdb::Database, names: &[String]) {
+    for (family, name) in GENERIC_FAMILIES.iter().zip(names) {
+        match family {
+            Family::Serif => db.set_serif_family(name),
+            Family::SansSerif => db.set_sans_serif_family(name),
+            Family::Monospace => db.set_monospace_family(name),
+            Family::Cursive => db.set_cursive_family(name),
+            Family::Fantasy => db.set_fantasy_family(name),
+            Family::Name(_) => {}
+        }
+    }
+}
+
+/// The system font files that were loaded, persisted so that the system font directories don't
+/// have to be searched again on the next start if none of the fonts were added or removed.
+#[derive(Serialize, Deserialize)]
+struct FontPathCache {
+    /// The directories of the font files and their parents, with when each was last modified.
+    /// Adding or removing a file or directory changes the modification time of its parent, so
+    /// the files are only searched for again if one of these changed. Fonts in new directories
+    /// elsewhere are found by `reload_changed_system_fonts`.
+    dirs: Vec<(PathBuf, Option<SystemTime>)>,
+    /// The names of the generic families, which fontdb configures while searching, e.g. from the
+    /// fontconfig aliases.
+    generic_families: Vec<String>,
+    paths: Vec<PathBuf>,
+}
+
+/// Reads the font files listed in the cache file, unless any of their directories changed since
+/// it was written.
+fn read_font_path_cache(cache_file: &Path) -> Option<FontPathCache> {
+    let cache = std::fs::read(cache_file).ok()?;
+    let cache = serde_json::from_slice::<FontPathCache>(&cache).ok()?;
+    cache
+        .dirs
+        .iter()
+        .all(|(dir, modified)| file_modified_time(dir) == *modified)
+        .then_some(cache)
+}
+
+fn write_font_path_cache(cache_file: &Path, paths: Vec<PathBuf>, generic_families: Vec<String>) {
+    let dirs = paths
+        .iter()
+        .flat_map(|path| path.ancestors().skip(1).take(2))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|dir| (dir.to_path_buf(), file_modified_time(dir)))
+        .collect();
+    let cache = FontPathCache {
+        dirs,
+        generic_families,
+        paths,
+    };
+    if let Some(parent) = cache_file.parent() {
+        std::fs::create_dir_all(parent).log_err();
+    }
+    serde_json::to_vec(&cache)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(std::fs::write(cache_file, json)?))
+        .log_err();
+}
+
+/// Writes glyph outlines as SVG path data. Font units have y pointing up while SVG has it pointing
+/// down, so every y coordinate is negated.
+struct SvgPathBuilder(String);
+
+impl ttf_parser::OutlineBuilder for SvgPathBuilder {
+    fn move_to(&mut self, x: f32, y: f32) {
+        write!(self.0, "M{x} {} ", -y).unwrap();
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        write!(self.0, "L{x} {} ", -y).unwrap();
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        write!(self.0, "Q{x1} {} {x} {} ", -y1, -y).unwrap();
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        write!(self.0, "C{x1} {} {x2} {} {x} {} ", -y1, -y2, -y).unwrap();
+    }
+
+    fn close(&mut self) {
+        self.0.push_str("Z ");
+    }
+}
+
+/// Mask for the primary language part of a Windows language id, which ignores the region.
+const PRIMARY_LANGUAGE_MASK: u16 = 0x3ff;
+
+/// Returns fonts suited to text in the given BCP 47 locale, Windows fonts before Linux ones, to try
+/// first when falling back. Han characters in particular are drawn differently depending on
+/// the language, so the fonts for it have to be preferred over other CJK fonts.
+fn default_fallback_families(locale: &str) -> &'static [&'static str] {
+    let locale = locale.replace('_', "-").to_lowercase();
+    match locale.as_str() {
+        "zh-tw" | "zh-hk" | "zh-mo" | "zh-hant" => &["Microsoft JhengHei UI", "Noto Sans CJK TC"],
+        _ => match locale.split('-').next().unwrap_or_default() {
+            "ja" => &["Yu Gothic UI", "Meiryo UI", "Noto Sans CJK JP"],
+            "ko" => &["Malgun Gothic", "Noto Sans CJK KR"],
+            "zh" => &["Microsoft YaHei UI", "Noto Sans CJK SC"],
+            "th" => &["Leelawadee UI", "Noto Sans Thai"],
+            "hi" | "mr" | "ne" => &["Nirmala UI", "Noto Sans Devanagari"],
+            "ar" | "fa" | "ur" => &["Segoe UI", "Noto Sans Arabic"],
+            "he" => &["Segoe UI", "Noto Sans Hebrew"],
+            _ => &[],
+        },
+    }
+}
+
+/// Returns whether lines can break after the character, which is whitespace other than the
+/// non-breaking spaces.
+fn is_breaking_whitespace(ch: char) -> bool {
+    ch.is_whitespace() && !matches!(ch, '\u{a0}' | '\u{2007}' | '\u{202f}')
+}
+
+/// Families that cover many symbols and scripts, tried for any character after the fonts for its
+/// script, Windows fonts before Linux ones.
+const COMMON_FALLBACK_FAMILIES: &[&str] = &[
+    "Segoe UI",
+    "Segoe UI Symbol",
+    "Segoe UI Emoji",
+    "Noto Sans",
+    "DejaVu Sans",
+    "Noto Sans Symbols",
+    "Noto Sans Symbols2",
+    "Noto Color Emoji",
+];
+
+/// Returns fonts for the script of the given character, Windows fonts before Linux ones, to try
+/// first when falling back, like cosmic-text's script fallback. Han characters use the fonts for
+/// the locale's language if it's written with them.
+fn script_fallback_families(ch: char, locale: &str) -> &'static [&'static str] {
+    match ch {
+        '\u{0530}'..='\u{058F}' => &["Segoe UI", "Noto Sans Armenian"],
+        '\u{0590}'..='\u{05FF}' | '\u{FB1D}'..='\u{FB4F}' => &["Segoe UI", "Noto Sans Hebrew"],
+        '\u{0600}'..='\u{06FF}'
+        | '\u{0750}'..='\u{077F}'
+        | '\u{08A0}'..='\u{08FF}'
+        | '\u{FB50}'..='\u{FDFF}'
+        | '\u{FE70}'..='\u{FEFF}' => &["Segoe UI", "Noto Sans Arabic"],
+        '\u{0900}'..='\u{097F}' => &["Nirmala UI", "Noto Sans Devanagari"],
+        '\u{0980}'..='\u{09FF}' => &["Nirmala UI", "Noto Sans Bengali"],
+        '\u{0B80}'..='\u{0BFF}' => &["Nirmala UI", "Noto Sans Tamil"],
+        '\u{0E00}'..='\u{0E7F}' => &["Leelawadee UI", "Noto Sans Thai"],
+        '\u{10A0}'..='\u{10FF}' => &["Segoe UI", "Noto Sans Georgian"],
+        '\u{1200}'..='\u{139F}' => &["Ebrima", "Noto Sans Ethiopic"],
+        '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' | '\u{AC00}'..='\u{D7AF}' => {
+            &["Malgun Gothic", "Noto Sans CJK KR"]
+        }
+        '\u{3040}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' => {
+            &["Yu Gothic UI", "Meiryo UI", "Noto Sans CJK JP"]
+        }
+        '\u{2E80}'..='\u{2FDF}'
+        | '\u{3000}'..='\u{303F}'
+        | '\u{3100}'..='\u{312F}'
+        | '\u{31A0}'..='\u{31BF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FF00}'..='\u{FFEF}'
+        | '\u{20000}'..='\u{3134F}' => match default_fallback_families(locale) {
+            families if families.iter().any(|family| family.contains("CJK")) => families,
+            _ => &["Microsoft YaHei UI", "Noto Sans CJK SC"],
+        },
+        _ => &[],
+    }
+}
+
+/// Maps a BCP 47 locale to the Windows language id used by `name` table records. Tags without a
+/// region map to the language's default region.
+fn windows_language_id(locale: &str) -> Option<u16> {
+    let locale = locale.replace('_', "-").to_lowercase();
+    let language_id = match locale.as_str() {
+        "zh-tw" | "zh-hant" => 0x0404,
+        "zh-hk" => 0x0c04,
+        "zh-mo" => 0x1404,
+        "zh-sg" => 0x1004,
+        "pt-pt" => 0x0816,
+        "en-gb" => 0x0809,
+        _ => match locale.split('-').next()? {
+            "ar" => 0x0401,
+            "cs" => 0x0405,
+            "de" => 0x0407,
+            "el" => 0x0408,
+            "en" => 0x0409,
+            "es" => 0x0c0a,
+            "fr" => 0x040c,
+            "he" => 0x040d,
+            "hi" => 0x0439,
+            "it" => 0x0410,
+            "ja" => 0x0411,
+            "ko" => 0x0412,
+            "nl" => 0x0413,
+            "pl" => 0x0415,
+            "pt" => 0x0416,
+            "ru" => 0x0419,
+            "th" => 0x041e,
+            "tr" => 0x041f,
+            "uk" => 0x0422,
+            "vi" => 0x042a,
+            "zh" => 0x0804,
+            _ => return None,
+        },
+    };
+    Some(language_id)
+}
+
+fn find_font_name(
+    font: &CosmicTextFont,
+    name_id: u16,
+    mut predicate: impl FnMut(&Name) -> bool,
+) -> Option<String> {
+    font.rustybuzz()
+        .names()
+        .into_iter()
+        .filter(|name| name.name_id == name_id && name.is_unicode())
+        .filter(|name| predicate(name))
+        .find_map(|name| name.to_string())
+}
+
+impl From<RectF> for Bounds<f32> {
+    fn from(rect: RectF) -> Self {
+        Bounds {
+            origin: point(rect.origin_x(), rect.origin_y()),
+            size: size(rect.width(), rect.height()),
+        }
+    }
+}
+
+impl From<RectI> for Bounds<DevicePixels> {
+    fn from(rect: RectI) -> Self {
+        Bounds {
+            origin: point(DevicePixels(rect.origin_x()), DevicePixels(rect.origin_y())),
+            size: size(DevicePixels(rect.width()), DevicePixels(rect.height())),
+        }
+    }
+}
+
+impl From<Vector2I> for Size<DevicePixels> {
+    fn from(value: Vector2I) -> Self {
+        size(value.x().into(), value.y().into())
+    }
+}
+
+impl From<RectI> for Bounds<i32> {
+    fn from(rect: RectI) -> Self {
+        Bounds {
+            origin: point(rect.origin_x(), rect.origin_y()),
+            size: size(rect.width(), rect.height()),
+        }
+    }
+}
+
+impl From<Point<u32>> for Vector2I {
+    fn from(size: Point<u32>) -> Self {
+        Vector2I::new(size.x as i32, size.y as i32)
+    }
+}
+
+impl From<Vector2F> for Size<f32> {
+    fn from(vec: Vector2F) -> Self {
+        size(vec.x(), vec.y())
+    }
+}
+
+impl From<FontWeight> for cosmic_text::Weight {
+    fn from(value: FontWeight) -> Self {
+        cosmic_text::Weight(value.0 as u16)
+    }
+}
+
+impl From<cosmic_text::Stretch> for FontStretch {
+    fn from(stretch: cosmic_text::Stretch) -> Self {
+        match stretch {
+            cosmic_text::Stretch::UltraCondensed => FontStretch::ULTRA_CONDENSED,
+            cosmic_text::Stretch::ExtraCondensed => FontStretch::EXTRA_CONDENSED,
+            cosmic_text::Stretch::Condensed => FontStretch::CONDENSED,
+            cosmic_text::Stretch::SemiCondensed => FontStretch::SEMI_CONDENSED,
+            cosmic_text::Stretch::Normal => FontStretch::NORMAL,
+            cosmic_text::Stretch::SemiExpanded => FontStretch::SEMI_EXPANDED,
+            cosmic_text::Stretch::Expanded => FontStretch::EXPANDED,
+            cosmic_text::Stretch::ExtraExpanded => FontStretch::EXTRA_EXPANDED,
+            cosmic_text::Stretch::UltraExpanded => FontStretch::ULTRA_EXPANDED,
+        }
+    }
+}
+
+impl From<FontStyle> for cosmic_text::Style {
+    fn from(style: FontStyle) -> Self {
+        match style {
+            FontStyle::Normal => cosmic_text::Style::Normal,
+            FontStyle::Italic => cosmic_text::Style::Italic,
+            FontStyle::Oblique => cosmic_text::Style::Oblique,
+        }
+    }
+}
+
+fn font_into_properties(font: &crate::Font) -> font_kit::properties::Properties {
+    font_kit::properties::Properties {
+        style: match font.style {
+            crate::FontStyle::Normal => font_kit::properties::Style::Normal,
+            crate::FontStyle::Italic => font_kit::properties::Style::Italic,
+            crate::FontStyle::Oblique => font_kit::properties::Style::Oblique,
+        },
+        weight: font_kit::properties::Weight(font.weight.0),
+        stretch: stretch_into_properties(font.stretch),
+    }
+}
+
+fn face_info_into_properties(
+    face_info: &cosmic_text::fontdb::FaceInfo,
+) -> font_kit::properties::Properties {
+    font_kit::properties::Properties {
+        style: match face_info.style {
+            cosmic_text::Style::Normal => font_kit::properties::Style::Normal,
+            cosmic_text::Style::Italic => font_kit::properties::Style::Italic,
+            cosmic_text::Style::Oblique => font_kit::properties::Style::Oblique,
+        },
+        // both libs use the same values for weight
+        weight: font_kit::properties::Weight(face_info.weight.0.into()),
+        stretch: stretch_into_properties(face_info.stretch.into()),
+    }
+}
+
+fn stretch_into_properties(stretch: FontStretch) -> font_kit::properties::Stretch {
+    // font-kit expresses widths as a fraction of the normal width rather than a percentage.
+    font_kit::properties::Stretch(stretch.0 / 100.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compute_grapheme_boundaries, default_fallback_families, font_file_paths, generic_families,
+        load_font_files, load_system_font_file, read_font_path_cache, write_font_path_cache,
+        CaretMetrics, ControlCharacterDisplay, FontLoadError, FontSource, MissingGlyphAdvance,
+        TabWidth, TextDecoration,
+    };
+    use crate::{
+        font, point, px, size, Bounds, CosmicTextSystem, DevicePixels, Font, FontId, FontRun,
+        FontStretch, FontStyle, FontWeight, GlyphId, LineLayout, PlatformTextSystem,
+        RenderGlyphParams, ShapedGlyph, ShapedRun, Size, TextDirection,
+    };
+    use collections::HashMap;
+    use cosmic_text::{fontdb, rustybuzz::ttf_parser, Shaping};
+    use itertools::Itertools;
+    use smallvec::SmallVec;
+    use std::{
+        borrow::Cow,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering::SeqCst},
+            Arc,
+        },
+        time::Duration,
+    };
+    use util::test::temp_tree;
+
+    fn text_system() -> CosmicTextSystem {
+        let text_system = CosmicTextSystem::new();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+            ))])
+            .unwrap();
+        text_system
+    }
+
+    #[test]
+    fn test_wait_for_fonts_loaded() {
+        let text_system = text_system();
+        text_system.wait_for_fonts_loaded();
+        assert!(text_system
+            .all_font_families()
+            .contains(&"Zed Mono".to_string()));
+
+        // Waiting again once everything is loaded is a no-op.
+        text_system.wait_for_fonts_loaded();
+    }
+
+    #[test]
+    fn test_layout_line_with_mixed_font_sizes() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let run = |len, font_size| FontRun {
+            len,
+            font_id,
+            font_size,
+        };
+
+        let small = text_system.layout_line("abc", px(12.), &[run(3, None)]);
+        let large = text_system.layout_line("abc", px(24.), &[run(3, None)]);
+        let mixed =
+            text_system.layout_line("abcabc", px(12.), &[run(3, None), run(3, Some(px(24.)))]);
+
+        assert_eq!(mixed.font_size, px(12.));
+        assert_eq!(mixed.ascent, large.ascent);
+        assert_eq!(mixed.descent, large.descent);
+        assert_eq!(mixed.width, small.width + large.width);
+
+        let glyphs = mixed
+            .runs
+            .iter()
+            .flat_map(|run| run.glyphs.iter().map(move |glyph| (run.font_size, glyph)))
+            .collect::<Vec<_>>();
+        assert_eq!(glyphs.len(), 6);
+        assert_eq!(glyphs[2].0, None);
+        assert_eq!(glyphs[3].0, Some(px(24.)));
+        assert_eq!(glyphs[3].1.index, 3);
+        assert_eq!(glyphs[3].1.position.x, small.width);
+        assert_eq!(glyphs[3].1.position.y, px(0.));
+
+        // Runs of different sizes are reordered together, so the first letter of a right-to-left
+        // line is drawn last even when it's larger than the rest.
+        let text = "\u{5e9}\u{5dc}\u{5d5}\u{5dd}";
+        let rtl = text_system.layout_line(text, px(12.), &[run(2, Some(px(24.))), run(6, None)]);
+        let glyphs = rtl
+            .runs
+            .iter()
+            .flat_map(|run| run.glyphs.iter().map(move |glyph| (run.font_size, glyph)))
+            .collect::<Vec<_>>();
+        assert_eq!(glyphs.len(), 4);
+        let (first_font_size, first) = glyphs.iter().find(|(_, glyph)| glyph.index == 0).unwrap();
+        assert_eq!(*first_font_size, Some(px(24.)));
+        assert!(glyphs
+            .iter()
+            .all(|(_, glyph)| glyph.position.x <= first.position.x));
+        assert!(first.position.x < rtl.width);
+
+        // Each size is shaped by itself, so the line is as wide as its parts, and as tall as the
+        // tallest of them in its own font, rather than the tallest font scaled to the largest size.
+        assert_eq!(
+            text_system.measure_width("abcabc", px(12.), &[run(3, None), run(3, Some(px(24.)))]),
+            mixed.width
+        );
+        let Ok(sans_font_id) = text_system.font_id(&font("DejaVu Sans")) else {
+            return;
+        };
+        let sans = text_system.layout_line(
+            "abc",
+            px(12.),
+            &[FontRun {
+                len: 3,
+                font_id: sans_font_id,
+                font_size: None,
+            }],
+        );
+        let mixed_fonts = text_system.layout_line(
+            "abcabc",
+            px(12.),
+            &[
+                FontRun {
+                    len: 3,
+                    font_id: sans_font_id,
+                    font_size: None,
+                },
+                run(3, Some(px(24.))),
+            ],
+        );
+        assert_eq!(mixed_fonts.width, sans.width + large.width);
+        assert_eq!(mixed_fonts.ascent, sans.ascent.max(large.ascent));
+        assert_eq!(mixed_fonts.descent, sans.descent.max(large.descent));
+    }
+
+    #[test]
+    fn test_layout_line_text_after_emoji() {
+        let font_size = px(16.);
+        let text = "a😀b";
+        // Returns whether the emoji is rendered in color.
+        let layout = |text_system: &CosmicTextSystem| {
+            let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+            let layout = text_system.layout_line(
+                text,
+                font_size,
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            );
+            let glyphs = layout
+                .runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(move |glyph| (run.font_id, glyph)))
+                .collect::<Vec<_>>();
+            let (emoji_font_id, emoji) = glyphs.iter().find(|(_, glyph)| glyph.index == 1).unwrap();
+            let (_, next) = glyphs.iter().find(|(_, glyph)| glyph.index == 5).unwrap();
+
+            let units_per_em = text_system.font_metrics(*emoji_font_id).units_per_em as f32;
+            let advance = text_system.advance(*emoji_font_id, emoji.id).unwrap().width
+                / units_per_em
+                * font_size;
+            let mut expected_x = emoji.position.x + advance;
+            if emoji.is_emoji {
+                // The bitmap may be wider than the advance, but never overlaps the next glyph.
+                let bounds = text_system
+                    .glyph_raster_bounds(&RenderGlyphParams {
+                        font_id: *emoji_font_id,
+                        glyph_id: emoji.id,
+                        font_size,
+                        subpixel_variant: Default::default(),
+                        scale_factor: 1.,
+                        is_emoji: true,
+                    })
+                    .unwrap();
+                let right = px((bounds.origin.x + bounds.size.width).0 as f32);
+                expected_x = expected_x.max(emoji.position.x + right);
+            }
+            assert!((next.position.x - expected_x).abs() < px(0.01));
+            emoji.is_emoji
+        };
+
+        // Without a color font, the emoji is drawn as an outline and takes up its advance.
+        let text_system_without_system_fonts = CosmicTextSystem::new_with_system_fonts(false);
+        text_system_without_system_fonts
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+            ))])
+            .unwrap();
+        assert!(!layout(&text_system_without_system_fonts));
+
+        let text_system = text_system();
+        assert_eq!(layout(&text_system), text_system.has_color_emoji_font());
+    }
+
+    #[test]
+    fn test_font_display_name() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let english_name = text_system.font_display_name(font_id, "en-US").unwrap();
+        assert!(english_name.starts_with("Zed Mono"));
+
+        // The bundled font only has English names, so other locales fall back to them.
+        assert_eq!(
+            text_system.font_display_name(font_id, "ja"),
+            Some(english_name.clone())
+        );
+        assert_eq!(
+            text_system.font_display_name(font_id, "not-a-locale"),
+            Some(english_name)
+        );
+        assert_eq!(text_system.font_sample_text(font_id), None);
+    }
+
+    #[test]
+    fn test_layout_line_run_directions() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "abc \u{5d0}\u{5d1}\u{5d2}";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+
+        let direction_at = |ix: usize| {
+            layout
+                .runs
+                .iter()
+                .find(|run| run.glyphs.iter().any(|glyph| glyph.index == ix))
+                .unwrap()
+                .direction
+        };
+        assert_eq!(direction_at(0), TextDirection::LeftToRight);
+        assert_eq!(
+            direction_at(text.find('\u{5d1}').unwrap()),
+            TextDirection::RightToLeft
+        );
+    }
+
+    #[test]
+    fn test_layout_right_to_left_line() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let layout = |text: &str| {
+            text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            )
+        };
+        let glyph_x = |layout: &LineLayout, ix: usize| {
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| &run.glyphs)
+                .find(|glyph| glyph.index == ix)
+                .unwrap()
+                .position
+                .x
+        };
+
+        // "Shalom olam" reads from right to left, so its first letter is drawn last.
+        let text = "\u{5e9}\u{5dc}\u{5d5}\u{5dd} \u{5e2}\u{5d5}\u{5dc}\u{5dd}";
+        let hebrew = layout(text);
+        let glyphs = hebrew
+            .runs
+            .iter()
+            .flat_map(|run| &run.glyphs)
+            .collect::<Vec<_>>();
+        assert_eq!(glyphs.len(), text.chars().count());
+        assert!(glyphs
+            .iter()
+            .all(|glyph| glyph.position.x >= px(0.) && glyph.position.x < hebrew.width));
+        let first_x = glyph_x(&hebrew, 0);
+        assert!(glyphs.iter().all(|glyph| glyph.position.x <= first_x));
+        assert!(glyph_x(&hebrew, text.len() - '\u{5dd}'.len_utf8()) < px(0.01));
+
+        // Hebrew in a left-to-right line is reordered on its own.
+        let text = "ab \u{5e9}\u{5dc} cd";
+        let mixed = layout(text);
+        assert!(glyph_x(&mixed, 0) < glyph_x(&mixed, 3));
+        assert!(glyph_x(&mixed, 3) > glyph_x(&mixed, 5));
+        assert!(glyph_x(&mixed, 5) < glyph_x(&mixed, 8));
+    }
+
+    #[test]
+    fn test_layout_line_with_zero_width_space() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let layout = |text: &str| {
+            text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            )
+        };
+
+        let plain = layout("ab");
+        let with_zwsp = layout("a\u{200B}b");
+        assert_eq!(with_zwsp.width, plain.width);
+        let glyphs = with_zwsp
+            .runs
+            .iter()
+            .flat_map(|run| run.glyphs.iter().map(|glyph| (glyph.index, glyph.position)))
+            .collect::<Vec<_>>();
+        assert_eq!(glyphs.len(), 2);
+        assert_eq!(glyphs[1].0, "a\u{200B}".len());
+        assert_eq!(glyphs[1].1, plain.runs[0].glyphs[1].position);
+    }
+
+    #[test]
+    fn test_layout_line_groups_glyphs_into_runs() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+            ))])
+            .unwrap();
+        let regular = text_system.font_id(&font("Zed Mono")).unwrap();
+        let bold = text_system
+            .font_id(&Font {
+                weight: FontWeight::BOLD,
+                ..font("Zed Mono")
+            })
+            .unwrap();
+        let run = |len, font_id| FontRun {
+            len,
+            font_id,
+            font_size: None,
+        };
+
+        let layout = text_system.layout_line(
+            "abcdefghi",
+            px(16.),
+            &[
+                run(3, regular),
+                run(2, regular),
+                run(2, bold),
+                run(2, regular),
+            ],
+        );
+        let runs = layout
+            .runs
+            .iter()
+            .map(|run| (run.font_id, run.glyphs.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(runs, [(regular, 5), (bold, 2), (regular, 2)]);
+    }
+
+    #[test]
+    fn test_layout_line_with_fallback_font() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        // Zed Mono has no Hebrew, so shaping falls back to a font that hasn't been loaded yet.
+        let text = "a\u{5d0}b";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+        let glyphs = layout
+            .runs
+            .iter()
+            .flat_map(|run| {
+                run.glyphs
+                    .iter()
+                    .map(move |glyph| (run.font_id, glyph.index))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(glyphs[0], (font_id, 0));
+        assert_eq!(glyphs.last(), Some(&(font_id, 3)));
+        for (glyph_font_id, index) in glyphs {
+            if index == 1 && glyph_font_id != font_id {
+                assert!(text_system.can_render(glyph_font_id, '\u{5d0}'));
+            }
+        }
+    }
+
+    #[test]
+    fn test_fallback_override() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let Some(family) = text_system
+            .all_font_families()
+            .into_iter()
+            .unique()
+            .find(|family| {
+                text_system
+                    .family_font_ids(family)
+                    .into_iter()
+                    .any(|font_id| text_system.can_render(font_id, '\u{5d0}'))
+            })
+        else {
+            return;
+        };
+
+        // The override covers 'a' too, but Zed Mono already does.
+        let text = "a\u{5d0}";
+        let runs = [FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        }];
+        text_system.push_fallback_override(family.clone());
+        let layout = text_system.layout_line(text, px(16.), &runs);
+        let font_id_at = |index| {
+            layout
+                .runs
+                .iter()
+                .find(|run| run.glyphs.iter().any(|glyph| glyph.index == index))
+                .unwrap()
+                .font_id
+        };
+        assert_eq!(font_id_at(0), font_id);
+        assert!(text_system
+            .family_font_ids(&family)
+            .contains(&font_id_at(1)));
+
+        assert_eq!(text_system.pop_fallback_override(), Some(family.into()));
+        assert_eq!(text_system.pop_fallback_override(), None);
+    }
+
+    #[test]
+    fn test_fallback_families() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let families = text_system
+            .all_font_families()
+            .into_iter()
+            .unique()
+            .collect_vec();
+        // Prefer a CJK character, but fall back to Hebrew on systems without CJK fonts. Zed Mono
+        // covers neither.
+        let Some((ch, family)) = ['\u{4e00}', '\u{5d0}'].into_iter().find_map(|ch| {
+            let family = families.iter().rev().find(|family| {
+                text_system
+                    .family_font_ids(family)
+                    .into_iter()
+                    .any(|font_id| text_system.can_render(font_id, ch))
+            })?;
+            Some((ch, family.clone()))
+        }) else {
+            return;
+        };
+        assert!(!text_system.can_render(font_id, ch));
+
+        text_system.set_fallback_fonts(vec!["Not A Font Family".to_string(), family.clone()]);
+        let text = format!("a{ch}");
+        let layout = text_system.layout_line(
+            &text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+        assert_eq!(layout.runs.len(), 2);
+        assert_eq!(layout.runs[0].font_id, font_id);
+        assert_ne!(layout.runs[1].font_id, font_id);
+        assert!(text_system
+            .family_font_ids(&family)
+            .contains(&layout.runs[1].font_id));
+    }
+
+    #[test]
+    fn test_control_characters() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let runs = |text: &str| {
+            [FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }]
+        };
+        let indices = |layout: &LineLayout| {
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.index))
+                .collect::<Vec<_>>()
+        };
+
+        let text = "a\u{1}b\u{7f}c";
+        let plain = text_system.layout_line("abc", px(16.), &runs("abc"));
+        let hidden = text_system.layout_line(text, px(16.), &runs(text));
+        assert_eq!(indices(&hidden), [0, 2, 4]);
+        assert!((hidden.width - plain.width).abs() < px(0.01));
+        assert!((hidden.x_for_index(4) - plain.x_for_index(2)).abs() < px(0.01));
+
+        text_system.set_control_character_display(ControlCharacterDisplay::Pictures);
+        let pictures = text_system.layout_line(text, px(16.), &runs(text));
+        if text_system.glyph_for_char(font_id, '\u{2401}').is_some() {
+            assert_eq!(indices(&pictures), [0, 1, 2, 3, 4]);
+            assert!(pictures.width > hidden.width);
+        }
+        assert!(pictures.width >= hidden.width);
+    }
+
+    #[test]
+    fn test_normalize_fallback_metrics() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "a\u{2230}\u{5d0}";
+        let runs = [FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        }];
+        let metrics = text_system.font_metrics(font_id);
+
+        let normalized = text_system.layout_line(text, px(16.), &runs);
+        assert_eq!(normalized.ascent, metrics.ascent(px(16.)));
+        assert_eq!(normalized.descent, -metrics.descent(px(16.)));
+
+        text_system.set_normalize_fallback_metrics(false);
+        let unnormalized = text_system.layout_line(text, px(16.), &runs);
+        assert!(unnormalized.ascent >= normalized.ascent);
+        assert!(unnormalized.descent >= normalized.descent);
+    }
+
+    #[test]
+    fn test_layout_line_with_replacement_character() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "a\u{FFFD}b";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+
+        let (run_font_id, glyph) = layout
+            .runs
+            .iter()
+            .flat_map(|run| run.glyphs.iter().map(move |glyph| (run.font_id, glyph)))
+            .find(|(_, glyph)| glyph.index == 1)
+            .unwrap();
+        assert_eq!(run_font_id, font_id);
+        let expected_glyph_id = text_system
+            .glyph_for_char(font_id, char::REPLACEMENT_CHARACTER)
+            .unwrap_or(GlyphId(0));
+        assert_eq!(glyph.id, expected_glyph_id);
+    }
+
+    #[test]
+    fn test_normalize_to_nfc() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let layout_line = |text: &str| {
+            text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            )
+        };
+        let glyphs = |layout: &LineLayout| {
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(|glyph| (glyph.id, glyph.index)))
+                .collect::<Vec<_>>()
+        };
+
+        let composed = layout_line("\u{e9}x");
+        text_system.set_normalize_to_nfc(true);
+        let decomposed = layout_line("e\u{301}x");
+        assert_eq!(decomposed.len, 4);
+        assert_eq!(decomposed.width, composed.width);
+        assert_eq!(
+            glyphs(&decomposed),
+            [(glyphs(&composed)[0].0, 0), (glyphs(&composed)[1].0, 3)]
+        );
+    }
+
+    #[test]
+    fn test_layout_line_with_max_width() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "hello world";
+        let runs = [FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        }];
+        let layout = text_system.layout_line(text, px(16.), &runs);
+        assert!(!layout.truncated);
+
+        let unbounded = text_system.layout_line_with_max_width(text, px(16.), &runs, None);
+        assert!(!unbounded.truncated);
+        assert_eq!(unbounded.width, layout.width);
+
+        let fits = text_system.layout_line_with_max_width(text, px(16.), &runs, Some(layout.width));
+        assert!(!fits.truncated);
+
+        let truncated =
+            text_system.layout_line_with_max_width(text, px(16.), &runs, Some(layout.width / 2.));
+        assert!(truncated.truncated);
+        assert_eq!(truncated.width, layout.width);
+        assert_eq!(truncated.runs.len(), layout.runs.len());
+    }
+
+    #[test]
+    fn test_wrap_line() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "The quick brown fox jumps over the lazy dog. Pack my box with five dozen \
+            liquor jugs. How vexingly quick daft zebras jump!";
+        let wrap_ixs = text_system.wrap_line(text, font_id, px(16.), px(160.));
+        assert!(wrap_ixs.len() > 3);
+        let mut line_start = 0;
+        for wrap_ix in wrap_ixs.iter().copied().chain(Some(text.len())) {
+            assert!(wrap_ix > line_start);
+            let line = &text[line_start..wrap_ix];
+            assert!(line_start == 0 || !line.starts_with(' '));
+            let layout = text_system.layout_line(
+                line.trim_end(),
+                px(16.),
+                &[FontRun {
+                    len: line.trim_end().len(),
+                    font_id,
+                    font_size: None,
+                }],
+            );
+            assert!(layout.width <= px(160.));
+            line_start = wrap_ix;
+        }
+
+        // Zed Mono's advance is 0.6em, so 7 characters fit in 72px at 16px.
+        assert_eq!(
+            text_system.wrap_line("aaaaaaaaaaaaaaaaa", font_id, px(16.), px(72.)),
+            [7, 14]
+        );
+        assert_eq!(
+            text_system.wrap_line("\u{e9}\u{e9}\u{e9}", font_id, px(16.), px(10.)),
+            [2, 4]
+        );
+        assert!(text_system
+            .wrap_line("", font_id, px(16.), px(72.))
+            .is_empty());
+
+        // Whitespace that overflows hangs at the end of the line, and any whitespace but
+        // non-breaking spaces is a break opportunity, though indentation isn't.
+        assert_eq!(
+            text_system.wrap_line("aaaaaaa bbb", font_id, px(16.), px(72.)),
+            [8]
+        );
+        assert_eq!(
+            text_system.wrap_line("aaaa\tbbbbb", font_id, px(16.), px(72.)),
+            [5]
+        );
+        assert_eq!(
+            text_system.wrap_line("aaaa\u{a0}bbbbb", font_id, px(16.), px(72.)),
+            ["aaaa\u{a0}bb".len()]
+        );
+        assert_eq!(
+            text_system.wrap_line("    aaaaaaaa", font_id, px(16.), px(72.)),
+            [7]
+        );
+
+        // Right-to-left text wraps at the start of its words, in the order of the text.
+        if !text_system.family_font_ids("DejaVu Sans").is_empty() {
+            let text = "\u{5d0}\u{5d1}\u{5d2} \u{5d3}\u{5d4}\u{5d5} \u{5d6}\u{5d7}\u{5d8}";
+            let width = text_system
+                .layout_line(
+                    &text[.."\u{5d0}\u{5d1}\u{5d2} \u{5d3}\u{5d4}\u{5d5}".len()],
+                    px(16.),
+                    &[FontRun {
+                        len: "\u{5d0}\u{5d1}\u{5d2} \u{5d3}\u{5d4}\u{5d5}".len(),
+                        font_id,
+                        font_size: None,
+                    }],
+                )
+                .width;
+            assert_eq!(
+                text_system.wrap_line(text, font_id, px(16.), width),
+                ["\u{5d0}\u{5d1}\u{5d2} \u{5d3}\u{5d4}\u{5d5} ".len()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_soft_hyphen() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let runs = |len| {
+            [FontRun {
+                len,
+                font_id,
+                font_size: None,
+            }]
+        };
+        let glyph_ids = |line: &LineLayout| {
+            line.runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.id))
+                .collect::<Vec<_>>()
+        };
+        let glyph_id = |ch| text_system.glyph_for_char(font_id, ch).unwrap();
+
+        // Away from a wrap boundary, soft hyphens are invisible.
+        let text = "aa\u{ad}bb";
+        let unwrapped = text_system.layout_line(text, px(16.), &runs(text.len()));
+        let plain = text_system.layout_line("aabb", px(16.), &runs(4));
+        assert_eq!(unwrapped.width, plain.width);
+        assert_eq!(glyph_ids(&unwrapped), glyph_ids(&plain));
+        let lines = text_system.layout_wrapped(text, px(16.), &runs(text.len()), px(72.), 2);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(glyph_ids(&lines[0]), glyph_ids(&plain));
+
+        // At a wrap boundary, they break the line and are drawn as a hyphen.
+        let text = "aaaa\u{ad}bbbbbb";
+        assert_eq!(
+            text_system.wrap_line(text, font_id, px(16.), px(72.)),
+            ["aaaa\u{ad}".len()]
+        );
+        let lines = text_system.layout_wrapped(text, px(16.), &runs(text.len()), px(72.), 2);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            glyph_ids(&lines[0]),
+            [
+                glyph_id('a'),
+                glyph_id('a'),
+                glyph_id('a'),
+                glyph_id('a'),
+                glyph_id('-')
+            ]
+        );
+        assert_eq!(lines[0].width, plain.width / 4. * 5.);
+        assert_eq!(lines[1].len, "bbbbbb".len());
+    }
+
+    #[test]
+    fn test_layout_wrapped() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "aa bbb cccc ddddd eeee";
+        let runs = [FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        }];
+        let line_lens = |lines: Vec<LineLayout>| lines.iter().map(|line| line.len).collect_vec();
+
+        // Zed Mono's advance is 0.6em, so 7 characters fit in 72px at 16px.
+        let lines = text_system.layout_wrapped(text, px(16.), &runs, px(72.), 4);
+        assert_eq!(
+            line_lens(lines),
+            ["aa bbb ".len(), "cccc ".len(), "ddddd ".len(), 4]
+        );
+
+        // Zed Mono's ellipsis is two characters wide, which leaves no room for the 'd'.
+        let lines = text_system.layout_wrapped(text, px(16.), &runs, px(72.), 2);
+        assert!(lines.iter().all(|line| line.width <= px(72.)));
+        assert_eq!(line_lens(lines), ["aa bbb ".len(), "cccc\u{2026}".len()]);
+
+        // Each line holds at least one character, even if it doesn't fit.
+        let runs = [FontRun { len: 3, ..runs[0] }];
+        let lines = text_system.layout_wrapped("abc", px(16.), &runs, px(5.), 5);
+        assert_eq!(line_lens(lines), [1, 1, 1]);
+        let lines = text_system.layout_wrapped("abc", px(16.), &runs, px(5.), 1);
+        assert_eq!(line_lens(lines), ["\u{2026}".len()]);
+
+        assert!(text_system
+            .layout_wrapped("abc", px(16.), &runs, px(72.), 0)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_layout_line_with_basic_shaping() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "Hello, world";
+        let runs = [FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        }];
+
+        let advanced = text_system.layout_line(text, px(16.), &runs);
+        let basic = text_system.layout_line_with_shaping(text, px(16.), &runs, Shaping::Basic);
+        assert_eq!(basic.width, advanced.width);
+        assert_eq!(basic.ascent, advanced.ascent);
+        assert_eq!(basic.descent, advanced.descent);
+        let glyphs = |layout: &LineLayout| {
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(|glyph| (glyph.id, glyph.position)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(glyphs(&basic), glyphs(&advanced));
+
+        // Zed Mono substitutes both characters of "=>" to draw an arrow, which only happens with
+        // advanced shaping.
+        let text = "=>";
+        let runs = [FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        }];
+        let glyph_ids = |layout: &LineLayout| {
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.id))
+                .collect::<Vec<_>>()
+        };
+        let advanced = text_system.layout_line(text, px(16.), &runs);
+        let basic = text_system.layout_line_with_shaping(text, px(16.), &runs, Shaping::Basic);
+        let unshaped = text
+            .chars()
+            .map(|ch| text_system.glyph_for_char(font_id, ch).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(glyph_ids(&basic), unshaped);
+        assert_ne!(glyph_ids(&advanced), unshaped);
+    }
+
+    #[test]
+    fn test_layout_line_with_repeated_runs() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+            ))])
+            .unwrap();
+        let regular = text_system.font_id(&font("Zed Mono")).unwrap();
+        let bold = text_system.font_id(&font("Zed Mono").bold()).unwrap();
+        let text = "Hello, world";
+        let runs = |font_id| {
+            [FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }]
+        };
+        let font_ids = |layout: &LineLayout| {
+            layout
+                .runs
+                .iter()
+                .map(|run| run.font_id)
+                .unique()
+                .collect::<Vec<_>>()
+        };
+
+        let first = text_system.layout_line(text, px(16.), &runs(regular));
+        let second = text_system.layout_line(text, px(16.), &runs(regular));
+        assert_eq!(font_ids(&first), vec![regular]);
+        assert_eq!(font_ids(&second), vec![regular]);
+        assert_eq!(first.width, second.width);
+
+        let bold_layout = text_system.layout_line(text, px(16.), &runs(bold));
+        assert_eq!(font_ids(&bold_layout), vec![bold]);
+    }
+
+    #[test]
+    fn test_describe_layout_fonts() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+            ))])
+            .unwrap();
+        let regular = text_system.font_id(&font("Zed Mono")).unwrap();
+        let bold = text_system.font_id(&font("Zed Mono").bold()).unwrap();
+        let runs = [
+            FontRun {
+                len: 6,
+                font_id: regular,
+                font_size: None,
+            },
+            FontRun {
+                len: 5,
+                font_id: bold,
+                font_size: None,
+            },
+        ];
+
+        let layout = text_system.layout_line("Hello world", px(16.), &runs);
+        assert_eq!(
+            text_system.describe_layout_fonts(&layout),
+            vec![
+                "0..6: Zed Mono (Zed-Mono-Extended)".to_string(),
+                "6..11: Zed Mono (Zed-Mono-Bold-Extended)".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_typographic_bounds() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let units_per_em = text_system.font_metrics(font_id).units_per_em as f32;
+
+        let j = text_system.glyph_for_char(font_id, 'j').unwrap();
+        let j_bounds = text_system.typographic_bounds(font_id, j).unwrap();
+        let j_advance = text_system.advance(font_id, j).unwrap();
+        assert!(
+            j_bounds.origin.y < 0.,
+            "descender should reach below the baseline"
+        );
+        assert!(j_bounds.size.height > -j_bounds.origin.y);
+        assert!(j_bounds.size.width < j_advance.width);
+        assert!(j_bounds.size.height < units_per_em);
+
+        let x = text_system.glyph_for_char(font_id, 'x').unwrap();
+        let x_bounds = text_system.typographic_bounds(font_id, x).unwrap();
+        assert_eq!(x_bounds.origin.y, 0.);
+        assert!(x_bounds.origin.x > 0.);
+    }
+
+    #[test]
+    fn test_run_ink_bounds() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extendeditalic.ttf"
+            ))])
+            .unwrap();
+        let font_id = text_system
+            .font_id(&Font {
+                style: FontStyle::Italic,
+                ..font("Zed Mono")
+            })
+            .unwrap();
+        let space = text_system.glyph_for_char(font_id, ' ').unwrap();
+        assert_eq!(
+            text_system.typographic_bounds(font_id, space).unwrap(),
+            Bounds::default()
+        );
+
+        // The descender of an italic 'j' hooks back under the preceding glyph.
+        let text = " j";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+        let position = layout.runs[0].glyphs[1].position;
+        let bounds = text_system.run_ink_bounds(&layout, 0).unwrap();
+        assert!(bounds.origin.x < position.x);
+        assert!(bounds.origin.y < px(0.));
+        assert!(bounds.lower_right().y > px(0.));
+
+        let layout = text_system.layout_line(
+            " ",
+            px(16.),
+            &[FontRun {
+                len: 1,
+                font_id,
+                font_size: None,
+            }],
+        );
+        assert_eq!(
+            text_system.run_ink_bounds(&layout, 0),
+            Some(Bounds {
+                origin: layout.runs[0].glyphs[0].position,
+                size: Size::default(),
+            })
+        );
+        assert_eq!(text_system.run_ink_bounds(&layout, 1), None);
+    }
+
+    #[test]
+    fn test_index_for_x() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "ab\u{301}c";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+
+        // Clicks just before the middle of a glyph snap to its start and clicks just after it snap
+        // to the start of the next one, skipping over the combining accent.
+        let advance = layout.x_for_index(1);
+        for (glyph_ix, index, next_index) in [(0, 0, 1), (1, 1, 4), (2, 4, 5)] {
+            let middle = advance * (glyph_ix as f32 + 0.5);
+            assert_eq!(text_system.index_for_x(&layout, middle - px(1.)), index);
+            assert_eq!(
+                text_system.index_for_x(&layout, middle + px(1.)),
+                next_index
+            );
+        }
+        assert_eq!(text_system.index_for_x(&layout, px(-10.)), 0);
+        assert_eq!(
+            text_system.index_for_x(&layout, layout.width + px(10.)),
+            text.len()
+        );
+
+        // The boundary inside a ligature is placed halfway across it.
+        let glyph = |index, x| ShapedGlyph {
+            id: GlyphId(0),
+            position: point(px(x), px(0.)),
+            index,
+            cluster_len: 0,
+            is_emoji: false,
+        };
+        let layout = LineLayout {
+            width: px(30.),
+            runs: vec![ShapedRun {
+                font_id,
+                font_size: None,
+                glyphs: SmallVec::from_iter([glyph(0, 0.), glyph(2, 20.)]),
+                direction: TextDirection::LeftToRight,
+            }],
+            len: 3,
+            grapheme_boundaries: vec![0, 1, 2],
+            ..Default::default()
+        };
+        assert_eq!(text_system.index_for_x(&layout, px(4.)), 0);
+        assert_eq!(text_system.index_for_x(&layout, px(9.)), 1);
+        assert_eq!(text_system.index_for_x(&layout, px(16.)), 2);
+        assert_eq!(text_system.index_for_x(&layout, px(26.)), 3);
+    }
+
+    #[test]
+    fn test_grapheme_boundaries() {
+        let glyph = |index| ShapedGlyph {
+            id: GlyphId(0),
+            position: point(px(0.), px(0.)),
+            index,
+            cluster_len: 0,
+            is_emoji: false,
+        };
+        let layout_with_glyphs = |text: &str, indices: &[usize]| LineLayout {
+            len: text.len(),
+            runs: vec![ShapedRun {
+                font_id: FontId(0),
+                font_size: None,
+                glyphs: indices.iter().map(|index| glyph(*index)).collect(),
+                direction: TextDirection::LeftToRight,
+            }],
+            ..Default::default()
+        };
+        let glyph_clusters = |layout: &LineLayout| {
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| &run.glyphs)
+                .map(|glyph| (glyph.index, glyph.cluster_len))
+                .collect::<Vec<_>>()
+        };
+
+        let text = "e\u{301}x👍🏽";
+        let mut layout = layout_with_glyphs(text, &[0, 3, 4]);
+        compute_grapheme_boundaries(&mut layout, text);
+        assert_eq!(layout.grapheme_boundaries(), vec![0, 3, 4]);
+        assert_eq!(glyph_clusters(&layout), [(0, 3), (3, 1), (4, 8)]);
+
+        // A combining mark shaped as its own glyph belongs to the cluster of its base letter.
+        let mut layout = layout_with_glyphs(text, &[0, 1, 3, 4]);
+        compute_grapheme_boundaries(&mut layout, text);
+        assert_eq!(layout.grapheme_boundaries(), vec![0, 3, 4]);
+        assert_eq!(glyph_clusters(&layout), [(0, 3), (0, 3), (3, 1), (4, 8)]);
+
+        // An "ffi" ligature followed by an accented "e" whose accent is a separate glyph.
+        let text = "ffie\u{301}";
+        let mut layout = layout_with_glyphs(text, &[0, 3, 4]);
+        compute_grapheme_boundaries(&mut layout, text);
+        assert_eq!(glyph_clusters(&layout), [(0, 3), (3, 3), (3, 3)]);
+        for (index, cluster_len) in glyph_clusters(&layout) {
+            assert!(text.is_char_boundary(index));
+            assert!(layout.grapheme_boundaries().contains(&index));
+            let end = index + cluster_len;
+            assert!(end == text.len() || layout.grapheme_boundaries().contains(&end));
+        }
+
+        // Lines laid out by the text system come with their boundaries.
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "ab\u{301}c";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+        assert_eq!(layout.grapheme_boundaries(), vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn test_x_for_index() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "ab\u{301}c";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+
+        // Cluster boundaries round-trip through x positions.
+        for index in layout.grapheme_boundaries().into_iter().chain([text.len()]) {
+            let x = text_system.x_for_index(&layout, index);
+            assert_eq!(text_system.index_for_x(&layout, x), index);
+        }
+        assert_eq!(text_system.x_for_index(&layout, 0), px(0.));
+        assert_eq!(text_system.x_for_index(&layout, text.len()), layout.width);
+        // The accent is part of the cluster starting at "b", so it snaps to the leading edge of "b".
+        assert_eq!(
+            text_system.x_for_index(&layout, 2),
+            text_system.x_for_index(&layout, 1)
+        );
+    }
+
+    #[test]
+    fn test_decoration_rect() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let font_size = px(16.);
+        let ascent = text_system.font_metrics(font_id).ascent(font_size);
+        let rect = |kind| {
+            text_system
+                .decoration_rect(font_id, font_size, kind, px(100.))
+                .unwrap()
+        };
+
+        let underline = rect(TextDecoration::Underline);
+        assert!(underline.origin.y > px(0.));
+        assert!(underline.size.height > px(0.));
+        assert_eq!(underline.size.width, px(100.));
+
+        let strikethrough = rect(TextDecoration::Strikethrough);
+        assert!(strikethrough.origin.y < px(0.) && strikethrough.origin.y > -ascent);
+
+        let overline = rect(TextDecoration::Overline);
+        assert!((overline.origin.y + ascent).abs() < px(0.01));
+    }
+
+    #[test]
+    fn test_font_metrics_bounding_box() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let metrics = text_system.font_metrics(font_id);
+
+        // Zed Mono's head table has xMin -1200, yMin -483, xMax 1347, yMax 1189.
+        assert_eq!(metrics.units_per_em, 1000);
+        assert_eq!(
+            metrics.bounding_box,
+            Bounds {
+                origin: point(-1200., -483.),
+                size: size(2547., 1672.),
+            }
+        );
+    }
+
+    #[test]
+    fn test_font_load_errors() {
+        let text_system = text_system();
+
+        let error = text_system
+            .add_fonts(vec![
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+                )),
+                Cow::Owned(b"not a font".to_vec()),
+            ])
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FontLoadError>(),
+            Some(FontLoadError::ParseFailed { index: 1, .. })
+        ));
+        // None of the fonts are loaded if any of them is invalid.
+        assert!(!text_system
+            .all_font_names()
+            .contains(&"Zed-Mono-Bold-Extended".to_string()));
+
+        let error = text_system
+            .font_id(&font("Nonexistent Family"))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FontLoadError>(),
+            Some(FontLoadError::FamilyNotFound(family)) if family == "Nonexistent Family"
+        ));
+    }
+
+    #[test]
+    fn test_has_color_emoji_font() {
+        let text_system = CosmicTextSystem::new_with_system_fonts(false);
+        assert!(!text_system.has_color_emoji_font());
+
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+            ))])
+            .unwrap();
+        assert!(!text_system.has_color_emoji_font());
+
+        let system_text_system = CosmicTextSystem::new();
+        let has_noto_color_emoji = system_text_system
+            .font_id(&font("Noto Color Emoji"))
+            .is_ok();
+        if has_noto_color_emoji {
+            assert!(system_text_system.has_color_emoji_font());
+        }
+    }
+
+    #[test]
+    fn test_layout_line_with_hard_breaks() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let layout = |text: &str| {
+            text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            )
+        };
+        let glyph_indices = |layout: &LineLayout| {
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.index))
+                .collect::<Vec<_>>()
+        };
+
+        let single = layout("abcd");
+        let broken = layout("ab\ncd");
+        // The newline is shown as a control picture between the paragraphs.
+        assert_eq!(glyph_indices(&broken), [0, 1, 2, 3, 4]);
+        let positions = broken
+            .runs
+            .iter()
+            .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.position.x))
+            .collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(broken.width > single.width);
+        assert_eq!(broken.ascent, single.ascent);
+        assert_eq!(broken.descent, single.descent);
+
+        // Paragraphs of different directions are shaped separately.
+        let mixed = layout("\u{5d0}\u{5d1}\r\nab");
+        let indices = glyph_indices(&mixed);
+        for ix in [0, 2, 6, 7] {
+            assert!(indices.contains(&ix), "missing glyph at {ix}: {indices:?}");
+        }
+    }
+
+    #[test]
+    fn test_unload_fonts() {
+        let text_system = text_system();
+        let mono_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let sans_ids = text_system
+            .add_fonts_with_ids(
+                vec![Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-sans/zed-sans-extended.ttf"
+                ))],
+                true,
+            )
+            .unwrap();
+        let sans_id = text_system.font_id(&font("Zed Sans")).unwrap();
+        assert_eq!(sans_ids, [sans_id]);
+        let sans_without_ligatures_id = text_system
+            .font_id(&Font {
+                features: serde_json::from_value(serde_json::json!({ "calt": false })).unwrap(),
+                ..font("Zed Sans")
+            })
+            .unwrap();
+        let glyph_id = text_system.glyph_for_char(sans_id, 'a').unwrap();
+
+        // Bundled fonts can't be unloaded, and failing to unload one unloads nothing.
+        assert!(text_system.unload_fonts(&[mono_id]).is_err());
+        assert!(text_system.unload_fonts(&[sans_id, mono_id]).is_err());
+        assert_eq!(text_system.font_id(&font("Zed Sans")).unwrap(), sans_id);
+
+        let render_params = |font_id| RenderGlyphParams {
+            font_id,
+            glyph_id: text_system.glyph_for_char(font_id, 'a').unwrap(),
+            font_size: px(16.),
+            subpixel_variant: Default::default(),
+            scale_factor: 1.,
+            is_emoji: false,
+        };
+        let face_id = |font_id| text_system.0.read().loaded_font(font_id).id();
+        let (sans_face_id, mono_face_id) = (face_id(sans_id), face_id(mono_id));
+        let cached_glyphs = |face_id| {
+            text_system
+                .0
+                .read()
+                .swash_cache
+                .image_cache
+                .keys()
+                .filter(|cache_key| cache_key.font_id == face_id)
+                .count()
+        };
+        text_system
+            .glyph_raster_bounds(&render_params(sans_id))
+            .unwrap();
+        text_system
+            .glyph_raster_bounds(&render_params(mono_id))
+            .unwrap();
+        assert_eq!(cached_glyphs(sans_face_id), 1);
+        assert_eq!(cached_glyphs(mono_face_id), 1);
+
+        text_system.unload_fonts(&sans_ids).unwrap();
+        // Only the glyphs of the unloaded font are evicted from the glyph cache.
+        assert_eq!(cached_glyphs(sans_face_id), 0);
+        assert_eq!(cached_glyphs(mono_face_id), 1);
+        // Unloading a font again does nothing.
+        text_system.unload_fonts(&sans_ids).unwrap();
+        assert!(text_system.font_id(&font("Zed Sans")).is_err());
+        assert!(!text_system
+            .all_font_names()
+            .contains(&"Zed Sans".to_string()));
+        assert_eq!(text_system.font_for_id(sans_without_ligatures_id), None);
+        // Lines laid out before the font was unloaded fail to render instead of panicking.
+        assert!(text_system
+            .glyph_raster_bounds(&RenderGlyphParams {
+                font_id: sans_id,
+                glyph_id,
+                font_size: px(16.),
+                subpixel_variant: Default::default(),
+                scale_factor: 1.,
+                is_emoji: false,
+            })
+            .is_err());
+
+        // Other fonts keep their ids.
+        assert_eq!(text_system.font_id(&font("Zed Mono")).unwrap(), mono_id);
+        let layout = text_system.layout_line(
+            "abc",
+            px(16.),
+            &[FontRun {
+                len: 3,
+                font_id: mono_id,
+                font_size: None,
+            }],
+        );
+        assert_eq!(layout.runs[0].glyphs.len(), 3);
+
+        // The font can be added again.
+        let sans_ids = text_system
+            .add_fonts_with_ids(
+                vec![Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-sans/zed-sans-extended.ttf"
+                ))],
+                true,
+            )
+            .unwrap();
+        assert_eq!(text_system.font_id(&font("Zed Sans")).unwrap(), sans_ids[0]);
+        assert_ne!(sans_ids[0], sans_id);
+    }
+
+    #[test]
+    fn test_pin_family() {
+        let text_system = text_system();
+        let add_fonts = |data: Vec<u8>| {
+            text_system
+                .add_fonts_with_ids(vec![Cow::Owned(data)], false)
+                .unwrap()
+        };
+        let sans_data =
+            include_bytes!("../../../../../assets/fonts/zed-sans/zed-sans-extended.ttf").to_vec();
+
+        // Icon font families are pinned by default.
+        text_system.set_icon_font_families(vec!["Zed Sans".into()]);
+        let icon_ids = add_fonts(sans_data.clone());
+        assert!(text_system.unload_fonts(&icon_ids).is_err());
+        text_system.set_icon_font_families(Vec::new());
+        text_system.unload_fonts(&icon_ids).unwrap();
+
+        // Pinning a family covers faces of it that are added later.
+        text_system.pin_family("Zed Sans");
+        let sans_ids = add_fonts(sans_data);
+        let rvrn_ids = add_fonts(rvrn_test_font());
+        assert!(text_system.unload_fonts(&sans_ids).is_err());
+        assert!(text_system
+            .unload_fonts(&[rvrn_ids[0], sans_ids[0]])
+            .is_err());
+        text_system.unload_fonts(&rvrn_ids).unwrap();
+        assert!(text_system.font_id(&font("Rvrn Test")).is_err());
+        assert_eq!(text_system.font_id(&font("Zed Sans")).unwrap(), sans_ids[0]);
+    }
+
+    #[test]
+    fn test_unloaded_font_accessors() {
+        let text_system = text_system();
+        let font_id = text_system
+            .add_fonts_with_ids(
+                vec![Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-sans/zed-sans-extended.ttf"
+                ))],
+                true,
+            )
+            .unwrap()[0];
+        let glyph_id = text_system.glyph_for_char(font_id, 'a').unwrap();
+        let runs = [FontRun {
+            len: 3,
+            font_id,
+            font_size: None,
+        }];
+        let layout = text_system.layout_line("abc", px(16.), &runs);
+        text_system.unload_fonts(&[font_id]).unwrap();
+
+        let metrics = text_system.font_metrics(font_id);
+        assert_eq!(metrics.ascent(px(16.)), px(0.));
+        assert_eq!(metrics.descent(px(16.)), px(0.));
+        assert!(text_system.typographic_bounds(font_id, glyph_id).is_err());
+        assert!(text_system.advance(font_id, glyph_id).is_err());
+        assert_eq!(text_system.glyph_for_char(font_id, 'a'), None);
+        assert!(!text_system.can_render(font_id, 'a'));
+        assert!(!text_system.is_emoji(font_id));
+        assert!(!text_system.is_monospace(font_id));
+        assert!(!text_system.supports_shaping_for(font_id, "latn", None));
+        assert!(!text_system.forms_ligature(font_id, &['=', '>']));
+        assert_eq!(text_system.font_version(font_id), None);
+        assert_eq!(text_system.font_path(font_id), None);
+        assert_eq!(text_system.font_display_name(font_id, "en"), None);
+        assert_eq!(text_system.font_for_id(font_id), None);
+        assert_eq!(text_system.font_sample_text(font_id), None);
+        assert_eq!(text_system.glyph_to_svg(font_id, glyph_id), None);
+        assert_eq!(text_system.italic_angle(font_id), None);
+        assert_eq!(text_system.caret_metrics(font_id), None);
+        assert_eq!(
+            text_system.decoration_rect(font_id, px(16.), TextDecoration::Underline, px(10.)),
+            None
+        );
+        assert_eq!(
+            text_system.tab_advance(font_id, px(16.), px(0.), TabWidth::Spaces(4)),
+            None
+        );
+        assert_eq!(text_system.baseline_to_baseline(font_id, px(16.)), None);
+        assert!(text_system
+            .register_instance(font_id, &[(ttf_parser::Tag::from_bytes(b"wght"), 700.)])
+            .is_err());
+
+        // Lines laid out before the font was unloaded can still be inspected.
+        assert_eq!(text_system.run_ink_bounds(&layout, 0), None);
+        assert_eq!(
+            text_system.describe_layout_fonts(&layout),
+            ["0..3: unknown family (unknown)"]
+        );
+
+        // Laying out text in the font gives an empty line.
+        let layout = text_system.layout_line("abc", px(16.), &runs);
+        assert_eq!(layout.len, 3);
+        assert!(layout.runs.is_empty());
+        assert_eq!(layout.width, px(0.));
+        assert_eq!(text_system.measure_width("abc", px(16.), &runs), px(0.));
+        assert!(text_system
+            .wrap_line("abc", font_id, px(16.), px(10.))
+            .is_empty());
+        assert_eq!(
+            text_system
+                .layout_wrapped("abc", px(16.), &runs, px(100.), 2)
+                .len(),
+            1
+        );
+    }
+
+    /// Asserts that every loaded face is found through `font_ids_by_face_id` under the first font
+    /// id it was loaded as, and that the map only has faces that are loaded.
+    fn assert_font_ids_by_face_id_in_sync(text_system: &CosmicTextSystem) {
+        let state = text_system.0.read();
+        for (ix, font) in state.loaded_fonts_store.iter().flatten().enumerate() {
+            let base_font_id = state.font_ids_by_face_id[&font.id()];
+            assert!(base_font_id.0 <= ix);
+            assert_eq!(state.loaded_font(base_font_id).id(), font.id());
+        }
+        for (face_id, font_id) in &state.font_ids_by_face_id {
+            let first_ix = state
+                .loaded_fonts_store
+                .iter()
+                .position(|font| font.as_ref().unwrap().id() == *face_id);
+            assert_eq!(first_ix, Some(font_id.0));
+        }
+    }
+
+    #[test]
+    fn test_font_ids_by_face_id() {
+        let text_system = text_system();
+        let added_font_ids = text_system
+            .add_fonts_with_ids(
+                vec![Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-sans/zed-sans-extended.ttf"
+                ))],
+                true,
+            )
+            .unwrap();
+        assert_font_ids_by_face_id_in_sync(&text_system);
+
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        assert_eq!(
+            text_system.font_id(&font("Zed Sans")).unwrap(),
+            added_font_ids[0]
+        );
+        text_system
+            .font_id(&Font {
+                features: serde_json::from_value(serde_json::json!({ "calt": false })).unwrap(),
+                ..font("Zed Mono")
+            })
+            .unwrap();
+        assert_font_ids_by_face_id_in_sync(&text_system);
+
+        // Shaping characters Zed Mono doesn't cover loads the faces they fall back to.
+        let text = "a\u{5d0}\u{3b1}";
+        text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+        {
+            let state = text_system.0.read();
+            assert!(state.loaded_fonts_store.len() > state.font_ids_by_face_id.len());
+        }
+        assert_font_ids_by_face_id_in_sync(&text_system);
+    }
+
+    #[test]
+    fn test_layout_long_line() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let runs = |text: &str| {
+            [FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }]
+        };
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(230);
+        assert!(text.len() >= 10_000);
+        let layout = text_system.layout_line(&text, px(16.), &runs(&text));
+
+        // Every glyph maps back to the requested font.
+        assert_eq!(layout.runs.len(), 1);
+        assert_eq!(layout.runs[0].font_id, font_id);
+        assert_eq!(layout.runs[0].glyphs.len(), text.len());
+
+        // Glyphs in fallback fonts are mapped back to font ids through `font_ids_by_face_id`, so
+        // once their faces are loaded, a long line of them loads nothing else.
+        let segment = "fox \u{5d0}\u{5d1} \u{3b1}\u{3b2} ";
+        text_system.layout_line(segment, px(16.), &runs(segment));
+        let loaded_font_count = text_system.0.read().loaded_fonts_store.len();
+        let text = segment.repeat(1_000);
+        let layout = text_system.layout_line(&text, px(16.), &runs(&text));
+        assert_eq!(
+            layout
+                .runs
+                .iter()
+                .map(|run| run.glyphs.len())
+                .sum::<usize>(),
+            text.chars().count()
+        );
+        assert_eq!(
+            text_system.0.read().loaded_fonts_store.len(),
+            loaded_font_count
+        );
+        assert_font_ids_by_face_id_in_sync(&text_system);
+    }
+
+    #[test]
+    fn test_font_id_with_stretch() {
+        let text_system = CosmicTextSystem::new();
+        let normal = font("DejaVu Sans");
+        let condensed = Font {
+            stretch: FontStretch::CONDENSED,
+            ..font("DejaVu Sans")
+        };
+        let (Ok(normal_id), Ok(condensed_id)) = (
+            text_system.font_id(&normal),
+            text_system.font_id(&condensed),
+        ) else {
+            return;
+        };
+        let condensed_font = text_system.font_for_id(condensed_id).unwrap();
+        if condensed_font.stretch == FontStretch::NORMAL {
+            // The system has no condensed DejaVu Sans.
+            return;
+        }
+
+        assert_ne!(normal_id, condensed_id);
+        assert_eq!(
+            text_system.font_for_id(normal_id).unwrap().stretch,
+            FontStretch::NORMAL
+        );
+        assert!(condensed_font.stretch < FontStretch::NORMAL);
+        assert_eq!(text_system.font_id(&condensed_font).unwrap(), condensed_id);
+    }
+
+    #[test]
+    fn test_synthetic_styles() {
+        let text_system = text_system();
+        let regular = text_system.font_id(&font("Zed Mono")).unwrap();
+        let bold = text_system.font_id(&font("Zed Mono").bold()).unwrap();
+        let italic = text_system.font_id(&font("Zed Mono").italic()).unwrap();
+        assert_ne!(regular, bold);
+        assert_ne!(regular, italic);
+        assert_eq!(text_system.font_id(&font("Zed Mono").bold()).unwrap(), bold);
+
+        let glyph_id = text_system.glyph_for_char(regular, 'l').unwrap();
+        let ink = |font_id| {
+            let params = RenderGlyphParams {
+                font_id,
+                glyph_id,
+                font_size: px(32.),
+                subpixel_variant: Default::default(),
+                scale_factor: 1.,
+                is_emoji: false,
+            };
+            let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+            let (_, bytes) = text_system.rasterize_glyph(&params, bounds).unwrap();
+            (
+                bounds,
+                bytes.iter().map(|coverage| *coverage as u32).sum::<u32>(),
+            )
+        };
+        let (regular_bounds, regular_ink) = ink(regular);
+        let (bold_bounds, bold_ink) = ink(bold);
+        assert!(bold_ink > regular_ink);
+        assert!(bold_bounds.size.width > regular_bounds.size.width);
+        assert!(
+            text_system.advance(bold, glyph_id).unwrap().width
+                > text_system.advance(regular, glyph_id).unwrap().width
+        );
+
+        let line_width = |font_id| {
+            text_system
+                .layout_line(
+                    "ll",
+                    px(32.),
+                    &[FontRun {
+                        len: 2,
+                        font_id,
+                        font_size: None,
+                    }],
+                )
+                .width
+        };
+        assert!((line_width(bold) - line_width(regular) - px(32. / 12.)).abs() < px(0.01));
+
+        let (italic_bounds, _) = ink(italic);
+        assert_ne!(italic_bounds, regular_bounds);
+        assert_eq!(text_system.italic_angle(italic), Some(-14.));
+        assert_eq!(text_system.italic_angle(regular), Some(0.));
+    }
+
+    #[test]
+    fn test_advance_cache() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let glyph_id = text_system.glyph_for_char(font_id, 'm').unwrap();
+
+        let advance = text_system.advance(font_id, glyph_id).unwrap();
+        for _ in 0..1000 {
+            assert_eq!(text_system.advance(font_id, glyph_id).unwrap(), advance);
+        }
+
+        let state = text_system.0.read();
+        assert_eq!(state.advance_cache.lock()[&font_id].len(), 1);
+        let metrics = state.loaded_font(font_id).as_swash().glyph_metrics(&[]);
+        assert_eq!(
+            advance,
+            size(
+                metrics.advance_width(glyph_id.0 as u16),
+                metrics.advance_height(glyph_id.0 as u16)
+            )
+        );
+    }
+
+    #[test]
+    fn test_monospace_fonts() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-sans/zed-sans-extended.ttf"
+            ))])
+            .unwrap();
+        let mono_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let sans_id = text_system.font_id(&font("Zed Sans")).unwrap();
+        assert!(text_system.is_monospace(mono_id));
+        assert!(!text_system.is_monospace(sans_id));
+
+        let families = text_system.monospace_font_families();
+        assert!(families.contains(&"Zed Mono".to_string()));
+        assert!(!families.contains(&"Zed Sans".to_string()));
+        let all_families = text_system.all_font_families();
+        assert!(families.iter().all(|family| all_families.contains(family)));
+        // Every family that's listed resolves to a font.
+        for family in &families {
+            assert!(text_system.font_id(&font(family.clone())).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_all_font_families() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+                )),
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extendeditalic.ttf"
+                )),
+            ])
+            .unwrap();
+
+        let families = text_system.all_font_families();
+        assert_eq!(
+            families
+                .iter()
+                .filter(|family| *family == "Zed Mono")
+                .count(),
+            1
+        );
+        assert!(families
+            .windows(2)
+            .all(|pair| pair[0].to_lowercase() <= pair[1].to_lowercase()));
+
+        let names = text_system.all_font_names();
+        assert!(names.iter().all_unique());
+        assert!(names.iter().any(|name| name == "Zed-Mono-Bold-Extended"));
+    }
+
+    #[test]
+    fn test_load_font_files() {
+        let mut system_fonts = fontdb::Database::new();
+        system_fonts.load_system_fonts();
+        let paths = font_file_paths(system_fonts.faces());
+        let faces = |db: &fontdb::Database| {
+            db.faces()
+                .map(|face| {
+                    (
+                        face.id,
+                        format!("{:?}", face.source),
+                        face.index,
+                        face.post_script_name.clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut serial_db = fontdb::Database::new();
+        let mut loaded = 0;
+        for path in &paths {
+            if load_system_font_file(&mut serial_db, path).is_ok() {
+                loaded += 1;
+            }
+        }
+
+        // Loading the files in parallel adds the same faces in the same order.
+        let mut db = fontdb::Database::new();
+        let mut modified_times = HashMap::default();
+        let stats = load_font_files(&mut db, &paths, &mut modified_times);
+        assert_eq!(faces(&db), faces(&serial_db));
+        assert_eq!(stats.discovered, paths.len());
+        assert_eq!(stats.loaded, loaded);
+        assert_eq!(stats.failed, paths.len() - loaded);
+        assert_eq!(modified_times.len(), paths.len());
+    }
+
+    #[test]
+    fn test_font_path_cache() {
+        let dir = temp_tree(serde_json::json!({
+            "fonts": {
+                "truetype": {
+                    "a": { "a.ttf": "" },
+                    "b": { "b.otf": "" },
+                },
+            },
+        }));
+        let fonts_dir = dir.path().join("fonts/truetype");
+        let cache_file = dir.path().join("cache/font-paths.json");
+        let paths = vec![fonts_dir.join("a/a.ttf"), fonts_dir.join("b/b.otf")];
+        let generic_families = generic_families(&fontdb::Database::new());
+        let write_cache = || {
+            write_font_path_cache(&cache_file, paths.clone(), generic_families.clone());
+        };
+
+        // While the directories are unchanged, the cached list is used without searching them.
+        write_cache();
+        let cache = read_font_path_cache(&cache_file).unwrap();
+        assert_eq!(cache.paths, paths);
+        assert_eq!(cache.generic_families, generic_families);
+
+        // Adding a font changes the modification time of its directory.
+        std::fs::write(fonts_dir.join("a/c.ttc"), "").unwrap();
+        assert!(read_font_path_cache(&cache_file).is_none());
+
+        // So does adding a directory of fonts next to the known ones.
+        write_cache();
+        assert!(read_font_path_cache(&cache_file).is_some());
+        std::fs::create_dir(fonts_dir.join("d")).unwrap();
+        assert!(read_font_path_cache(&cache_file).is_none());
+    }
+
+    #[test]
+    fn test_rasterize_unrenderable_glyph() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let params = |glyph_id| RenderGlyphParams {
+            font_id,
+            glyph_id,
+            font_size: px(16.),
+            subpixel_variant: Default::default(),
+            scale_factor: 1.,
+            is_emoji: false,
+        };
+
+        // Zed Mono has far fewer glyphs than this.
+        let missing = params(GlyphId(u16::MAX as u32));
+        assert!(text_system.glyph_raster_bounds(&missing).is_err());
+        let bounds = text_system
+            .glyph_raster_bounds(&params(text_system.glyph_for_char(font_id, 'a').unwrap()))
+            .unwrap();
+        assert!(text_system.rasterize_glyph(&missing, bounds).is_err());
+    }
+
+    #[test]
+    fn test_is_emoji() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        assert!(!text_system.is_emoji(font_id));
+
+        for family in ["Noto Color Emoji", "Segoe UI Emoji", "Apple Color Emoji"] {
+            if let Ok(font_id) = text_system.font_id(&font(family)) {
+                assert!(
+                    text_system.is_emoji(font_id),
+                    "{family} should be an emoji font"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_font_lookup_while_system_fonts_load() {
+        let text_system = CosmicTextSystem::new();
+        // Bundled fonts resolve without waiting for the system fonts.
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+            ))])
+            .unwrap();
+        assert!(text_system.font_id(&font("Zed Mono")).is_ok());
+
+        // Families that may only be installed on the system wait for them to load.
+        let sans_serif = text_system.font_id(&font("DejaVu Sans")).ok();
+        let names = text_system.all_font_names();
+        let families = text_system.all_font_families();
+        text_system.wait_for_fonts_loaded();
+        assert_eq!(text_system.font_id(&font("DejaVu Sans")).ok(), sans_serif);
+        assert_eq!(text_system.all_font_names(), names);
+        assert_eq!(text_system.all_font_families(), families);
+
+        let (discovered, loaded, failed) = text_system.system_font_stats();
+        assert_eq!(discovered, loaded + failed);
+    }
+
+    #[test]
+    fn test_font_features() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-sans/zed-sans-extended.ttf"
+            ))])
+            .unwrap();
+        let fractions = Font {
+            features: serde_json::from_value(serde_json::json!({ "frac": true })).unwrap(),
+            ..font("Zed Sans")
+        };
+        let font_id = text_system.font_id(&font("Zed Sans")).unwrap();
+        let fractions_font_id = text_system.font_id(&fractions).unwrap();
+        assert_ne!(font_id, fractions_font_id);
+        assert_eq!(text_system.font_id(&fractions).unwrap(), fractions_font_id);
+
+        let layout = |font_id| {
+            let text = "1/2 x";
+            text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            )
+        };
+        let plain = layout(font_id);
+        let with_fractions = layout(fractions_font_id);
+        assert_eq!(with_fractions.runs[0].font_id, fractions_font_id);
+        let glyph_ids = |layout: &LineLayout| {
+            layout.runs[0]
+                .glyphs
+                .iter()
+                .map(|glyph| glyph.id)
+                .collect::<Vec<_>>()
+        };
+        // The numerator, slash and denominator are substituted, while the rest is unaffected.
+        let (plain_ids, fraction_ids) = (glyph_ids(&plain), glyph_ids(&with_fractions));
+        assert_eq!(plain_ids.len(), fraction_ids.len());
+        assert!((0..3).all(|ix| plain_ids[ix] != fraction_ids[ix]));
+        assert_eq!(plain_ids[3..], fraction_ids[3..]);
+        assert_eq!(with_fractions.width, plain.width);
+    }
+
+    #[test]
+    fn test_register_instance() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let wght = ttf_parser::Tag::from_bytes(b"wght");
+        let wdth = ttf_parser::Tag::from_bytes(b"wdth");
+
+        let instance = text_system
+            .register_instance(font_id, &[(wght, 450.), (wdth, 90.)])
+            .unwrap();
+        assert_ne!(instance, font_id);
+        assert_eq!(
+            text_system
+                .register_instance(font_id, &[(wght, 450.), (wdth, 90.)])
+                .unwrap(),
+            instance
+        );
+        assert_ne!(
+            text_system
+                .register_instance(font_id, &[(wght, 600.)])
+                .unwrap(),
+            instance
+        );
+
+        let text = "abc";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id: instance,
+                font_size: None,
+            }],
+        );
+        assert_eq!(layout.runs.len(), 1);
+        assert_eq!(layout.runs[0].font_id, instance);
+        assert_eq!(layout.runs[0].glyphs.len(), 3);
+        assert!((layout.width - px(28.8)).abs() < px(0.01));
+
+        // Zed Mono has no variation axes, so its instances render like the font itself.
+        let raster = |font_id| {
+            let params = RenderGlyphParams {
+                font_id,
+                glyph_id: layout.runs[0].glyphs[0].id,
+                font_size: px(16.),
+                subpixel_variant: Default::default(),
+                scale_factor: 1.,
+                is_emoji: false,
+            };
+            let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+            text_system.rasterize_glyph(&params, bounds).unwrap()
+        };
+        assert_eq!(raster(instance), raster(font_id));
+    }
+
+    #[test]
+    fn test_measure_width() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let runs = |text: &str| {
+            [FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }]
+        };
+        for text in [
+            "a",
+            "hello world",
+            "tab\tstop",
+            "\u{5d0}\u{5d1}",
+            "e\u{301}",
+            "🙂 smile",
+        ] {
+            let width = text_system.measure_width(text, px(16.), &runs(text));
+            let layout_width = text_system.layout_line(text, px(16.), &runs(text)).width;
+            assert!((width - layout_width).abs() < px(0.01), "{text:?}");
+        }
+        assert_eq!(text_system.measure_width("", px(16.), &[]), px(0.));
+    }
+
+    #[test]
+    fn test_letter_spacing() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let text = "He\u{301}llo";
+        let runs = [FontRun {
+            len: text.len(),
+            font_id,
+            font_size: None,
+        }];
+        let layout = text_system.layout_line(text, px(16.), &runs);
+        let tracked = text_system.layout_line_with_letter_spacing(text, px(16.), &runs, px(2.));
+
+        // The accent stays with its letter, so there are five clusters and four gaps between them.
+        assert_eq!(tracked.width - layout.width, px(2.) * 4.);
+        let glyphs = |layout: &LineLayout| {
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| {
+                    run.glyphs
+                        .iter()
+                        .map(|glyph| (glyph.index, glyph.position.x))
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut clusters = 0;
+        let mut previous_index = 0;
+        for ((index, x), (tracked_index, tracked_x)) in
+            glyphs(&layout).into_iter().zip(glyphs(&tracked))
+        {
+            assert_eq!(index, tracked_index);
+            if index != previous_index {
+                clusters += 1;
+                previous_index = index;
+            }
+            assert!((tracked_x - x - px(2.) * clusters as f32).abs() < px(0.01));
+        }
+        assert_eq!(clusters, 4);
+    }
+
+    #[test]
+    fn test_kerning() {
+        let text_system = text_system();
+        // DejaVu Sans kerns "AV" closer together, if it's installed.
+        let Ok(font_id) = text_system.font_id(&font("DejaVu Sans")) else {
+            return;
+        };
+        let layout = |font_id: FontId, run_lens: &[usize], text: &str| {
+            let runs = run_lens
+                .iter()
+                .map(|len| FontRun {
+                    len: *len,
+                    font_id,
+                    font_size: None,
+                })
+                .collect::<Vec<_>>();
+            text_system.layout_line(text, px(16.), &runs)
+        };
+
+        let a = layout(font_id, &[1], "A");
+        let v = layout(font_id, &[1], "V");
+        let av = layout(font_id, &[2], "AV");
+        assert!(av.width < a.width + v.width);
+        assert!(av.runs[0].glyphs[1].position.x < a.width);
+
+        // The pair is still kerned when it's split across runs of the same font, and when the font
+        // has features that make it get reshaped.
+        assert_eq!(layout(font_id, &[1, 1], "AV").width, av.width);
+        // The same goes for runs that set a font size, even if it's the line's size.
+        let sized = text_system.layout_line(
+            "AV",
+            px(16.),
+            &[
+                FontRun {
+                    len: 1,
+                    font_id,
+                    font_size: None,
+                },
+                FontRun {
+                    len: 1,
+                    font_id,
+                    font_size: Some(px(16.)),
+                },
+            ],
+        );
+        assert_eq!(sized.width, av.width);
+        assert_eq!(sized.runs[1].font_size, Some(px(16.)));
+        let font_id = text_system
+            .font_id(&Font {
+                features: serde_json::from_value(serde_json::json!({ "liga": false })).unwrap(),
+                ..font("DejaVu Sans")
+            })
+            .unwrap();
+        assert_eq!(layout(font_id, &[2], "AV").width, av.width);
+    }
+
+    #[test]
+    fn test_add_fonts_with_ids() {
+        let text_system = CosmicTextSystem::new_with_system_fonts(false);
+        let font_ids = text_system
+            .add_fonts_with_ids(
+                vec![
+                    Cow::Borrowed(include_bytes!(
+                        "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+                    )),
+                    Cow::Borrowed(include_bytes!(
+                        "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+                    )),
+                ],
+                true,
+            )
+            .unwrap();
+
+        // The returned ids are the ones looking the faces up by family resolves to.
+        let bold = Font {
+            weight: FontWeight::BOLD,
+            ..font("Zed Mono")
+        };
+        assert_eq!(
+            font_ids,
+            [
+                text_system.font_id(&font("Zed Mono")).unwrap(),
+                text_system.font_id(&bold).unwrap()
+            ]
+        );
+        assert_eq!(
+            text_system.font_for_id(font_ids[1]).unwrap().weight,
+            FontWeight::BOLD
+        );
+    }
+
+    #[test]
+    fn test_add_fonts_deduplicates_data() {
+        let text_system = CosmicTextSystem::new_with_system_fonts(false);
+        let bold = || {
+            vec![Cow::Borrowed(
+                &include_bytes!("../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf")[..],
+            )]
+        };
+        let font_ids = text_system.add_fonts_with_ids(bold(), true).unwrap();
+        assert_eq!(font_ids.len(), 1);
+        assert_eq!(
+            text_system.add_fonts_with_ids(bold(), true).unwrap(),
+            font_ids
+        );
+        text_system.add_fonts(bold()).unwrap();
+        let face_count = || text_system.0.read().font_system.db().len();
+        assert_eq!(face_count(), 1);
+
+        let readded_font_ids = text_system.add_fonts_with_ids(bold(), false).unwrap();
+        assert_ne!(readded_font_ids, font_ids);
+        assert_eq!(face_count(), 2);
+    }
+
+    #[test]
+    fn test_font_variations() {
+        let text_system = text_system();
+        // None of the bundled fonts are variable, so this relies on a system font with a weight
+        // axis, such as the variable Noto Sans or Cantarell many distributions ship.
+        let Some(family) = text_system.all_font_families().into_iter().find(|family| {
+            text_system
+                .family_font_ids(family)
+                .into_iter()
+                .any(|font_id| {
+                    let state = text_system.0.read();
+                    let face = state.loaded_font(font_id).rustybuzz();
+                    face.variation_axes()
+                        .into_iter()
+                        .any(|axis| axis.tag == ttf_parser::Tag::from_bytes(b"wght"))
+                })
+        }) else {
+            return;
+        };
+
+        let thin = text_system
+            .font_id(&font(family.clone()).variation(*b"wght", 100.))
+            .unwrap();
+        let black = text_system
+            .font_id(&font(family.clone()).variation(*b"wght", 900.))
+            .unwrap();
+        assert_ne!(thin, black);
+        assert_eq!(
+            text_system
+                .font_id(&font(family.clone()).variation(*b"wght", 100.))
+                .unwrap(),
+            thin
+        );
+        assert_eq!(
+            text_system.font_for_id(black).unwrap().variations,
+            font(family).variation(*b"wght", 900.).variations
+        );
+
+        let raster = |font_id| {
+            let params = RenderGlyphParams {
+                font_id,
+                glyph_id: text_system.glyph_for_char(font_id, 'm').unwrap(),
+                font_size: px(32.),
+                subpixel_variant: Default::default(),
+                scale_factor: 1.,
+                is_emoji: false,
+            };
+            let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+            text_system.rasterize_glyph(&params, bounds).unwrap()
+        };
+        assert_ne!(raster(thin), raster(black));
+    }
+
+    #[test]
+    fn test_required_variation_alternates() {
+        let text_system = CosmicTextSystem::new_with_system_fonts(false);
+        text_system
+            .add_fonts(vec![Cow::Owned(rvrn_test_font())])
+            .unwrap();
+        let glyph_id = |font: Font, text: &str| {
+            let font_id = text_system.font_id(&font).unwrap();
+            let layout = text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            );
+            layout.runs[0].glyphs[0].id
+        };
+
+        let regular = glyph_id(font("Rvrn Test"), "m");
+        assert_eq!(regular, GlyphId(1));
+        assert_eq!(
+            glyph_id(font("Rvrn Test").variation(*b"wght", 100.), "m"),
+            regular
+        );
+        assert_eq!(
+            glyph_id(font("Rvrn Test").variation(*b"wght", 900.), "m"),
+            GlyphId(2)
+        );
+        // Right-to-left text gets the alternates too.
+        assert_eq!(
+            glyph_id(font("Rvrn Test").variation(*b"wght", 900.), "\u{5d0}"),
+            GlyphId(2)
+        );
+    }
+
+    /// Builds a variable font with a `wght` axis from 100 to 900, whose `rvrn` feature substitutes
+    /// the glyph of "m" and alef at weights of 650 and above, like fonts that switch to simpler shapes at
+    /// heavy weights.
+    fn rvrn_test_font() -> Vec<u8> {
+        fn be16(values: &[u16]) -> Vec<u8> {
+            values
+                .iter()
+                .flat_map(|value| value.to_be_bytes())
+                .collect()
+        }
+        fn be32(value: u32) -> Vec<u8> {
+            value.to_be_bytes().to_vec()
+        }
+        fn utf16(text: &str) -> Vec<u8> {
+            be16(&text.encode_utf16().collect::<Vec<_>>())
+        }
+
+        let head = be16(&[
+            1, 0, 1, 0, 0, 0, 0x5f0f, 0x3cf5, 0, 1000, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 2,
+            0, 0,
+        ]);
+        let hhea = be16(&[
+            1,
+            0,
+            800,
+            -200i16 as u16,
+            0,
+            500,
+            0,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            3,
+        ]);
+        let maxp = be16(&[0, 0x5000, 3]);
+        let hmtx = be16(&[500, 0, 500, 0, 500, 0]);
+        // A format 4 subtable that maps both "m" and the Hebrew letter alef to glyph 1.
+        let cmap = [
+            be16(&[0, 1, 3, 1]),
+            be32(12),
+            be16(&[4, 40, 0, 6, 4, 1, 2]),
+            be16(&[0x6d, 0x5d0, 0xffff, 0, 0x6d, 0x5d0, 0xffff]),
+            be16(&[
+                1u16.wrapping_sub(0x6d),
+                1u16.wrapping_sub(0x5d0),
+                1,
+                0,
+                0,
+                0,
+            ]),
+        ]
+        .concat();
+        let family = utf16("Rvrn Test");
+        let postscript_name = utf16("RvrnTest-Regular");
+        let name = [
+            be16(&[0, 2, 30]),
+            be16(&[3, 1, 0x409, 1, family.len() as u16, 0]),
+            be16(&[
+                3,
+                1,
+                0x409,
+                6,
+                postscript_name.len() as u16,
+                family.len() as u16,
+            ]),
+            family,
+            postscript_name,
+        ]
+        .concat();
+        let fvar = [
+            be16(&[1, 0, 16, 2, 1, 20, 0, 8]),
+            b"wght".to_vec(),
+            be16(&[100, 0, 400, 0, 900, 0, 0, 256]),
+        ]
+        .concat();
+
+        // By default, `rvrn` has no lookups. Past the middle of the weight axis, it's replaced by
+        // a version that substitutes glyph 1 with glyph 2.
+        let script_list = [
+            be16(&[1]),
+            b"DFLT".to_vec(),
+            be16(&[8, 4, 0, 0, 0xffff, 1, 0]),
+        ]
+        .concat();
+        let feature_list = [be16(&[1]), b"rvrn".to_vec(), be16(&[8, 0, 0])].concat();
+        let lookup_list = be16(&[1, 4, 1, 0, 1, 8, 2, 8, 1, 2, 1, 1, 1]);
+        let feature_variations = [
+            be16(&[1, 0]),
+            be32(1),
+            be32(16),
+            be32(30),
+            be16(&[1]),
+            be32(6),
+            be16(&[1, 0, 0x2000, 0x4000]),
+            be16(&[1, 0, 1, 0]),
+            be32(12),
+            be16(&[0, 1, 0]),
+        ]
+        .concat();
+        let script_list_offset = 14;
+        let feature_list_offset = script_list_offset + script_list.len() as u16;
+        let lookup_list_offset = feature_list_offset + feature_list.len() as u16;
+        let feature_variations_offset = lookup_list_offset + lookup_list.len() as u16;
+        let gsub = [
+            be16(&[
+                1,
+                1,
+                script_list_offset,
+                feature_list_offset,
+                lookup_list_offset,
+            ]),
+            be32(feature_variations_offset as u32),
+            script_list,
+            feature_list,
+            lookup_list,
+            feature_variations,
+        ]
+        .concat();
+
+        let tables: [(&[u8; 4], Vec<u8>); 8] = [
+            (b"GSUB", gsub),
+            (b"cmap", cmap),
+            (b"fvar", fvar),
+            (b"head", head),
+            (b"hhea", hhea),
+            (b"hmtx", hmtx),
+            (b"maxp", maxp),
+            (b"name", name),
+        ];
+        let mut font = [be32(0x10000), be16(&[tables.len() as u16, 0, 0, 0])].concat();
+        let mut offset = 12 + 16 * tables.len();
+        let mut data = Vec::new();
+        for (tag, table) in &tables {
+            font.extend_from_slice(*tag);
+            font.extend(be32(0));
+            font.extend(be32(offset as u32));
+            font.extend(be32(table.len() as u32));
+            let padded_len = table.len().next_multiple_of(4);
+            data.extend_from_slice(table);
+            data.resize(data.len() + padded_len - table.len(), 0);
+            offset += padded_len;
+        }
+        font.extend(data);
+        font
+    }
+
+    #[test]
+    fn test_caret_metrics() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extendeditalic.ttf"
+            ))])
+            .unwrap();
+
+        let regular = text_system.font_id(&font("Zed Mono")).unwrap();
+        let caret = text_system.caret_metrics(regular).unwrap();
+        assert_eq!(caret.slope_run, 0.);
+        assert_eq!(caret.slope(), 0.);
+
+        // Zed Mono Italic's hhea table declares a caret slope of 25/1000 with an offset of 8.
+        let italic = text_system
+            .font_id(&Font {
+                style: FontStyle::Italic,
+                ..font("Zed Mono")
+            })
+            .unwrap();
+        assert_eq!(
+            text_system.caret_metrics(italic),
+            Some(CaretMetrics {
+                slope_rise: 1000.,
+                slope_run: 25.,
+                offset: 8.,
+            })
+        );
+    }
+
+    #[test]
+    fn test_tab_advance() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let tab_advance = |x, tab_width| {
+            text_system
+                .tab_advance(font_id, px(16.), px(x), tab_width)
+                .unwrap()
+        };
+
+        assert_eq!(tab_advance(0., TabWidth::Pixels(px(32.))), px(32.));
+        assert_eq!(tab_advance(10., TabWidth::Pixels(px(32.))), px(22.));
+        assert_eq!(tab_advance(32., TabWidth::Pixels(px(32.))), px(32.));
+        assert_eq!(tab_advance(10., TabWidth::Pixels(px(0.))), px(0.));
+
+        // Zed Mono's advance is 0.6em, so four spaces are 38.4px wide at 16px.
+        assert!((tab_advance(0., TabWidth::Spaces(4)) - px(38.4)).abs() < px(0.01));
+        assert!((tab_advance(9.6, TabWidth::Spaces(4)) - px(28.8)).abs() < px(0.01));
+        assert!((tab_advance(40., TabWidth::Spaces(4)) - px(36.8)).abs() < px(0.01));
+    }
+
+    #[test]
+    fn test_baseline_to_baseline() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let metrics = text_system.font_metrics(font_id);
+        let font_size = px(16.);
+        let distance = text_system
+            .baseline_to_baseline(font_id, font_size)
+            .unwrap();
+        let expected =
+            metrics.ascent(font_size) - metrics.descent(font_size) + metrics.line_gap(font_size);
+        assert!((distance - expected).abs() < px(0.01));
+        assert_eq!(
+            text_system.baseline_to_baseline(font_id, px(32.)),
+            Some(distance * 2.)
+        );
+    }
+
+    #[test]
+    fn test_glyph_to_svg() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+
+        let glyph_id = text_system.glyph_for_char(font_id, 'o').unwrap();
+        let svg = text_system.glyph_to_svg(font_id, glyph_id).unwrap();
+        let units_per_em = text_system.font_metrics(font_id).units_per_em;
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 -"));
+        assert!(svg.contains(&format!(" {units_per_em} {units_per_em}\">")));
+        assert!(svg.contains("<path d=\"M"));
+        assert!(svg.ends_with("Z\"/></svg>"));
+
+        let space = text_system.glyph_for_char(font_id, ' ').unwrap();
+        assert_eq!(text_system.glyph_to_svg(font_id, space), None);
+    }
+
+    #[test]
+    fn test_fallback_attempts_limit() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        text_system.set_max_fallback_attempts(0);
+
+        // With no fallback attempts allowed, characters missing from the fonts for their script
+        // and the common fallback fonts render as notdef.
+        let text = "a\u{10FFFD}b";
+        let layout = text_system.layout_line(
+            text,
+            px(16.),
+            &[FontRun {
+                len: text.len(),
+                font_id,
+                font_size: None,
+            }],
+        );
+        let glyphs = layout
+            .runs
+            .iter()
+            .flat_map(|run| run.glyphs.iter().map(move |glyph| (run.font_id, glyph)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            glyphs
+                .iter()
+                .map(|(_, glyph)| glyph.index)
+                .collect::<Vec<_>>(),
+            [0, 1, 5]
+        );
+        assert_eq!(glyphs[1].0, font_id);
+        assert_eq!(glyphs[1].1.id.0, 0);
+        assert!(glyphs[1].1.position.x > glyphs[0].1.position.x);
+        assert!(glyphs[2].1.position.x > glyphs[1].1.position.x);
+
+        // The fonts for a character's script and the common fallback fonts don't count toward the
+        // limit, so Hebrew still falls back to a font covering it, even one narrower than Zed Mono.
+        if !text_system.family_font_ids("DejaVu Sans").is_empty() {
+            let text = "a\u{05D0}b";
+            let layout = text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            );
+            let hebrew_glyph = layout
+                .runs
+                .iter()
+                .find_map(|run| {
+                    run.glyphs
+                        .iter()
+                        .find(|glyph| glyph.index == 1)
+                        .map(|glyph| (run.font_id, glyph.id))
+                })
+                .unwrap();
+            assert_ne!(hebrew_glyph.0, font_id);
+            assert_ne!(hebrew_glyph.1 .0, 0);
+        }
+    }
+
+    #[test]
+    fn test_notdef_glyphs() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let layout_line = |text: &str| {
+            text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            )
+        };
+
+        // No loaded font covers this private use character, so it renders as the notdef glyph of
+        // the run's font.
+        let text = "a\u{F8FF}b";
+        let layout = layout_line(text);
+        let notdef = layout
+            .runs
+            .iter()
+            .flat_map(|run| run.glyphs.iter().map(move |glyph| (run.font_id, glyph)))
+            .find(|(_, glyph)| glyph.index == 1)
+            .unwrap();
+        assert_eq!(notdef.0, font_id);
+        assert_eq!(notdef.1.id, GlyphId(0));
+
+        // When disabled, the character is left out and takes up no space.
+        text_system.set_notdef_glyphs_enabled(false);
+        let layout = layout_line(text);
+        assert_eq!(
+            layout
+                .runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.index))
+                .collect::<Vec<_>>(),
+            [0, 4]
+        );
+        assert_eq!(layout.width, layout_line("ab").width);
+    }
+
+    #[test]
+    fn test_emoji_ink_bounds() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let glyph_id = text_system.glyph_for_char(font_id, 'M').unwrap();
+        let state = text_system.0.read();
+
+        // Glyphs without a bitmap strike use their outline, which sits above the baseline.
+        let bounds = state.emoji_ink_bounds(font_id, glyph_id, 16.).unwrap();
+        assert!(bounds.origin.y < 0.);
+        assert!(bounds.size.width > 0. && bounds.size.height > 0.);
+
+        // The bounds scale with the font size, rather than with the size it's rasterized at.
+        let doubled = state.emoji_ink_bounds(font_id, glyph_id, 32.).unwrap();
+        assert!((doubled.size.width - bounds.size.width * 2.).abs() < 0.001);
+        assert!((doubled.origin.y - bounds.origin.y * 2.).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_emoji_baseline_offset() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let state = text_system.0.read();
+        let face = state.loaded_font(font_id).rustybuzz();
+        let cap_height = face.capital_height().unwrap() as f32 / face.units_per_em() as f32 * 16.;
+
+        // A bitmap spanning from the baseline to twice the cap height needs to move down by half
+        // the cap height to be centered on the capital letters.
+        let ink_bounds = Bounds {
+            origin: point(0., -2. * cap_height),
+            size: size(16., 2. * cap_height),
+        };
+        let offset = state.emoji_baseline_offset(ink_bounds, Some(font_id), 16.);
+        assert!((offset - px(cap_height / 2.)).abs() < px(0.001));
+
+        // Already centered bitmaps stay where they are.
+        let ink_bounds = Bounds {
+            origin: point(0., -cap_height),
+            size: size(16., cap_height),
+        };
+        let offset = state.emoji_baseline_offset(ink_bounds, Some(font_id), 16.);
+        assert!(offset.abs() < px(0.001));
+        assert_eq!(state.emoji_baseline_offset(ink_bounds, None, 16.), px(0.));
+    }
+
+    #[test]
+    fn test_system_font_stats() {
+        let text_system = text_system();
+        let (discovered, loaded, failed) = text_system.system_font_stats();
+        assert_eq!(discovered, loaded + failed);
+
+        // Only the explicitly added font is counted outside of the system font stats.
+        let system_faces = text_system.0.read().font_system.db().len() - 1;
+        assert!(loaded <= system_faces);
+    }
+
+    #[test]
+    fn test_font_version() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        assert!(text_system.font_version(font_id).is_some());
+        assert_eq!(text_system.reload_changed_system_fonts(), 0);
+    }
+
+    #[test]
+    fn test_reload_changed_system_fonts() {
+        let text_system = CosmicTextSystem::new();
+        let Some(family) = text_system.all_font_families().into_iter().next() else {
+            // The system has no fonts.
+            return;
+        };
+        let font_id = text_system.font_id(&font(family.clone())).unwrap();
+        let path = text_system.font_path(font_id).unwrap();
+        let stats = text_system.system_font_stats();
+
+        // Pretend the font file was modified after it was loaded.
+        text_system
+            .0
+            .write()
+            .system_font_modified_times
+            .insert(path.clone(), None);
+        assert_eq!(text_system.reload_changed_system_fonts(), 1);
+        assert_eq!(text_system.font_path(font_id), None);
+        let reloaded_id = text_system.font_id(&font(family)).unwrap();
+        assert_ne!(reloaded_id, font_id);
+        assert_eq!(text_system.font_path(reloaded_id), Some(path));
+        assert_eq!(text_system.system_font_stats(), stats);
+    }
+
+    #[test]
+    fn test_font_path() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        assert_eq!(text_system.font_path(font_id), None);
+
+        let Some(family) = CosmicTextSystem::new()
+            .all_font_families()
+            .into_iter()
+            .next()
+        else {
+            // The system has no fonts.
+            return;
+        };
+        let font_id = text_system.font_id(&font(family)).unwrap();
+        let path = text_system.font_path(font_id).unwrap();
+        let extension = path.extension().unwrap().to_ascii_lowercase();
+        assert!(["ttf", "otf", "ttc", "otc"]
+            .iter()
+            .any(|ext| extension == *ext));
+    }
+
+    #[test]
+    fn test_fonts_equivalent() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+            ))])
+            .unwrap();
+
+        let regular = font("Zed Mono");
+        let medium = Font {
+            weight: FontWeight::MEDIUM,
+            ..regular.clone()
+        };
+        let extra_bold = Font {
+            weight: FontWeight::EXTRA_BOLD,
+            ..regular.clone()
+        };
+        assert!(text_system.fonts_equivalent(&regular, &regular.clone()));
+        assert!(text_system.fonts_equivalent(&regular, &medium));
+        assert!(text_system.fonts_equivalent(&regular.clone().bold(), &extra_bold));
+        assert!(!text_system.fonts_equivalent(&regular, &regular.clone().bold()));
+        // Without an italic face, the italic font is the regular face slanted.
+        assert!(!text_system.fonts_equivalent(&regular, &regular.clone().italic()));
+        assert!(!text_system.fonts_equivalent(&regular, &font("Not A Font Family")));
+    }
+
+    #[test]
+    fn test_icon_font_families() {
+        let text_system = text_system();
+        text_system
+            .set_icon_font_families(vec!["Not An Icon Font".to_string(), "Zed Mono".to_string()]);
+        assert_eq!(text_system.icon_font_family().as_deref(), Some("Zed Mono"));
+        assert_eq!(
+            text_system.font_id(&font("Not An Icon Font")).unwrap(),
+            text_system.font_id(&font("Zed Mono")).unwrap()
+        );
+
+        text_system.set_icon_font_families(vec!["Not An Icon Font".to_string()]);
+        assert_eq!(text_system.icon_font_family(), None);
+        assert!(text_system.font_id(&font("Not An Icon Font")).is_err());
+    }
+
+    #[test]
+    fn test_default_icon_font() {
+        let text_system = CosmicTextSystem::new();
+        let fluent_icons = text_system.font_id(&font("Segoe Fluent Icons"));
+        let mdl2_assets = text_system.font_id(&font("Segoe MDL2 Assets"));
+        match text_system.icon_font_family() {
+            // Either family name resolves to whichever of them is installed.
+            Some(_) => assert_eq!(fluent_icons.unwrap(), mdl2_assets.unwrap()),
+            None => assert!(fluent_icons.is_err() && mdl2_assets.is_err()),
+        }
+    }
+
+    #[test]
+    fn test_without_system_fonts() {
+        let text_system = CosmicTextSystem::new_with_system_fonts(false);
+        assert_eq!(text_system.system_font_stats(), (0, 0, 0));
+        assert!(text_system.all_font_families().is_empty());
+        let error = text_system.font_id(&font("Zed Mono")).unwrap_err();
+        assert!(error.to_string().contains("Zed Mono"));
+
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+            ))])
+            .unwrap();
+        assert_eq!(
+            text_system.all_font_families(),
+            vec!["Zed Mono".to_string()]
+        );
+        assert!(text_system.font_id(&font("Zed Mono")).is_ok());
+    }
+
+    #[test]
+    fn test_font_id_by_full_name() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let full_name = text_system.font_display_name(font_id, "en").unwrap();
+
+        assert_eq!(text_system.font_id_by_full_name(&full_name), Some(font_id));
+        assert_eq!(
+            text_system.font_id_by_full_name(&full_name.to_uppercase()),
+            Some(font_id)
+        );
+        assert_eq!(
+            text_system.font_id_by_full_name("Zed Mono Does Not Exist"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_supports_shaping_for() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        assert!(text_system.supports_shaping_for(font_id, "latn", None));
+        assert!(!text_system.supports_shaping_for(font_id, "arab", None));
+        assert!(!text_system.supports_shaping_for(font_id, "latn", Some("XXX")));
+    }
+
+    #[test]
+    fn test_font_without_cmap() {
+        // Rename the cmap table in the table directory so that the font no longer has one. The new
+        // tag keeps the directory sorted, which is required for looking up the other tables.
+        let mut bytes =
+            include_bytes!("../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf").to_vec();
+        let table_count = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+        let cmap_record = (0..table_count)
+            .map(|ix| 12 + ix * 16)
+            .find(|offset| &bytes[*offset..*offset + 4] == b"cmap")
+            .unwrap();
+        bytes[cmap_record..cmap_record + 4].copy_from_slice(b"cmaq");
+
+        let text_system = CosmicTextSystem::new();
+        text_system.add_fonts(vec![Cow::Owned(bytes)]).unwrap();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+
+        assert_eq!(text_system.glyph_for_char(font_id, 'm'), None);
+        assert!(!text_system.can_render(font_id, 'm'));
+
+        // Glyphs can still be rendered by id.
+        let bounds = text_system
+            .glyph_raster_bounds(&RenderGlyphParams {
+                font_id,
+                glyph_id: GlyphId(10),
+                font_size: px(16.),
+                subpixel_variant: Default::default(),
+                scale_factor: 1.,
+                is_emoji: false,
+            })
+            .unwrap();
+        assert!(bounds.size.width.0 > 0 && bounds.size.height.0 > 0);
+    }
+
+    #[test]
+    fn test_missing_glyph_advance() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let notdef_advance = text_system.advance(font_id, GlyphId(0)).unwrap();
+        let missing_glyph_id = GlyphId(u16::MAX as u32 + 1);
+        assert_eq!(
+            text_system.advance(font_id, missing_glyph_id).unwrap(),
+            notdef_advance
+        );
+
+        text_system.set_missing_glyph_advance(MissingGlyphAdvance::Zero);
+        assert_eq!(
+            text_system.advance(font_id, missing_glyph_id).unwrap(),
+            Size::default()
+        );
+        assert_eq!(
+            text_system.advance(font_id, GlyphId(0)).unwrap(),
+            notdef_advance
+        );
+    }
+
+    #[test]
+    fn test_fallback_fonts() {
+        assert_eq!(
+            default_fallback_families("ja-JP").first(),
+            Some(&"Yu Gothic UI")
+        );
+        assert_eq!(
+            default_fallback_families("zh_TW").first(),
+            Some(&"Microsoft JhengHei UI")
+        );
+        assert!(default_fallback_families("en-US").is_empty());
+
+        let text_system = text_system();
+        text_system.set_fallback_fonts(vec!["Zed Mono".to_string()]);
+        assert_eq!(text_system.fallback_fonts(), vec!["Zed Mono".to_string()]);
+    }
+
+    #[test]
+    fn test_font_ids() {
+        let text_system = text_system();
+        let fonts = [
+            font("Zed Mono"),
+            font("Not A Font Family"),
+            font("Zed Mono").italic(),
+        ];
+        let font_ids = text_system.font_ids(&fonts);
+        assert_eq!(font_ids.len(), 3);
+        assert_eq!(
+            *font_ids[0].as_ref().unwrap(),
+            text_system.font_id(&fonts[0]).unwrap()
+        );
+        assert!(font_ids[1].is_err());
+        assert!(font_ids[2].is_ok());
+    }
+
+    #[test]
+    fn test_family_font_ids() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+                )),
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extendeditalic.ttf"
+                )),
+            ])
+            .unwrap();
+
+        let font_ids = text_system.family_font_ids("Zed Mono");
+        assert_eq!(font_ids.len(), 3);
+        for font in [
+            font("Zed Mono"),
+            font("Zed Mono").bold(),
+            font("Zed Mono").italic(),
+        ] {
+            assert!(font_ids.contains(&text_system.font_id(&font).unwrap()));
+        }
+        assert_eq!(text_system.family_font_ids("Zed Mono"), font_ids);
+        assert!(text_system.family_font_ids("Not A Font Family").is_empty());
+    }
+
+    #[test]
+    fn test_italic_angle() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extendeditalic.ttf"
+            ))])
+            .unwrap();
+
+        let upright = text_system.font_id(&font("Zed Mono")).unwrap();
+        assert_eq!(text_system.italic_angle(upright), Some(0.));
+        let italic = text_system.font_id(&font("Zed Mono").italic()).unwrap();
+        assert!(text_system.italic_angle(italic).unwrap() < 0.);
+    }
+
+    #[test]
+    fn test_max_glyph_pixels() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let params = RenderGlyphParams {
+            font_id,
+            glyph_id: text_system.glyph_for_char(font_id, 'm').unwrap(),
+            font_size: px(64.),
+            subpixel_variant: Default::default(),
+            scale_factor: 1.,
+            is_emoji: false,
+        };
+        let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+        assert!(text_system.rasterize_glyph(&params, bounds).is_ok());
+
+        text_system.set_max_glyph_pixels(100);
+        assert!(text_system.rasterize_glyph(&params, bounds).is_err());
+        let larger_params = RenderGlyphParams {
+            font_size: px(128.),
+            ..params
+        };
+        assert!(text_system.glyph_raster_bounds(&larger_params).is_err());
+    }
+
+    #[test]
+    fn test_faces_by_postscript_prefix() {
+        let text_system = CosmicTextSystem::new_with_system_fonts(false);
+        text_system
+            .add_fonts(vec![
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+                )),
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+                )),
+            ])
+            .unwrap();
+        let regular = text_system.font_id(&font("Zed Mono")).unwrap();
+        let bold = text_system
+            .font_id(&Font {
+                weight: FontWeight::BOLD,
+                ..font("Zed Mono")
+            })
+            .unwrap();
+
+        let font_ids = text_system.faces_by_postscript_prefix("zed-MONO");
+        assert_eq!(font_ids.len(), 2);
+        assert!(font_ids.contains(&regular));
+        assert!(font_ids.contains(&bold));
+        assert_eq!(
+            text_system.faces_by_postscript_prefix("Zed-Mono-Bold-Extended"),
+            [bold]
+        );
+        assert!(text_system.faces_by_postscript_prefix("Zed-Mon").is_empty());
+    }
+
+    #[test]
+    fn test_font_id_for_postscript_name() {
+        let text_system = CosmicTextSystem::new_with_system_fonts(false);
+        text_system
+            .add_fonts(vec![
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+                )),
+                Cow::Borrowed(include_bytes!(
+                    "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+                )),
+            ])
+            .unwrap();
+        let bold = text_system
+            .font_id(&Font {
+                weight: FontWeight::BOLD,
+                ..font("Zed Mono")
+            })
+            .unwrap();
+
+        assert_eq!(
+            text_system.font_id_for_postscript_name("Zed-Mono-Bold-Extended"),
+            Some(bold)
+        );
+        assert_eq!(
+            text_system.font_id_for_postscript_name("zed-mono-bold-extended"),
+            None
+        );
+        assert_eq!(text_system.font_id_for_postscript_name("Zed-Mono"), None);
+    }
+
+    #[test]
+    fn test_forms_ligature() {
+        let text_system = text_system();
+        // Zed Mono draws ligatures by substituting each character, so they stay separate glyphs.
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        assert!(!text_system.forms_ligature(font_id, &['=', '>']));
+        assert!(!text_system.forms_ligature(font_id, &['f', 'i']));
+
+        // DejaVu Sans joins "fi" into a single glyph, if it's installed.
+        let Some(font_id) = text_system.family_font_ids("DejaVu Sans").first().copied() else {
+            return;
+        };
+        assert!(text_system.forms_ligature(font_id, &['f', 'i']));
+        assert!(!text_system.forms_ligature(font_id, &['a', 'b']));
+        assert!(!text_system.forms_ligature(font_id, &['f']));
+    }
+
+    #[test]
+    fn test_stylistic_sets() {
+        let text_system = text_system();
+        let glyph_ids = |font: &Font, text: &str| {
+            let font_id = text_system.font_id(font).unwrap();
+            let layout = text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            );
+            (
+                font_id,
+                layout
+                    .runs
+                    .iter()
+                    .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.id))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let with_set = |tag: &str| Font {
+            features: serde_json::from_value(serde_json::json!({ tag: true })).unwrap(),
+            ..font("Zed Mono")
+        };
+
+        // Zed Mono's first stylistic set draws "#" differently.
+        let (font_id, plain) = glyph_ids(&font("Zed Mono"), "#");
+        let (ss01_font_id, ss01) = glyph_ids(&with_set("ss01"), "#");
+        assert_ne!(font_id, ss01_font_id);
+        assert_eq!(ss01.len(), 1);
+        assert_ne!(plain, ss01);
+        // Each set gets its own font, and all of them stay usable side by side.
+        let (ss15_font_id, ss15) = glyph_ids(&with_set("ss15"), "#");
+        assert_ne!(ss15_font_id, ss01_font_id);
+        assert_ne!(ss15, ss01);
+        assert_eq!(glyph_ids(&with_set("ss01"), "#"), (ss01_font_id, ss01));
+        assert_eq!(glyph_ids(&font("Zed Mono"), "#"), (font_id, plain));
+    }
+
+    #[test]
+    fn test_disable_ligatures() {
+        let text_system = text_system();
+        let glyph_ids = |font: &Font, text: &str| {
+            let font_id = text_system.font_id(font).unwrap();
+            let layout = text_system.layout_line(
+                text,
+                px(16.),
+                &[FontRun {
+                    len: text.len(),
+                    font_id,
+                    font_size: None,
+                }],
+            );
+            (
+                font_id,
+                layout
+                    .runs
+                    .iter()
+                    .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.id))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let without_ligatures = |family: &str| Font {
+            features: serde_json::from_value(serde_json::json!({ "calt": false, "liga": false }))
+                .unwrap(),
+            ..font(family.to_string())
+        };
+
+        // Zed Mono draws "=>" as an arrow by substituting both characters contextually, so the
+        // glyph count stays the same but the glyphs differ.
+        let (font_id, arrow) = glyph_ids(&font("Zed Mono"), "=>");
+        let (plain_font_id, plain) = glyph_ids(&without_ligatures("Zed Mono"), "=>");
+        assert_ne!(font_id, plain_font_id);
+        assert_eq!(plain.len(), 2);
+        assert_ne!(arrow, plain);
+        // Both fonts stay usable side by side.
+        assert_eq!(glyph_ids(&font("Zed Mono"), "=>").1, arrow);
+
+        // DejaVu Sans joins "fi" into a single glyph, if it's installed.
+        if text_system.family_font_ids("DejaVu Sans").is_empty() {
+            return;
+        }
+        assert_eq!(glyph_ids(&font("DejaVu Sans"), "fi").1.len(), 1);
+        assert_eq!(
+            glyph_ids(&without_ligatures("DejaVu Sans"), "fi").1.len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_font_for_id() {
+        let text_system = text_system();
+        text_system
+            .add_fonts(vec![Cow::Borrowed(include_bytes!(
+                "../../../../../assets/fonts/zed-mono/zed-mono-extendedbold.ttf"
+            ))])
+            .unwrap();
+        for weight in [FontWeight::NORMAL, FontWeight::BOLD] {
+            let font = Font {
+                weight,
+                ..font("Zed Mono")
+            };
+            let font_id = text_system.font_id(&font).unwrap();
+            let resolved = text_system.font_for_id(font_id).unwrap();
+            assert_eq!(resolved.family, font.family);
+            assert_eq!(resolved.weight, weight);
+            assert_eq!(text_system.font_id(&resolved).unwrap(), font_id);
+        }
+        assert!(text_system.font_for_id(FontId(1000)).is_none());
+    }
+
+    #[test]
+    fn test_emoji_scale() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let glyph_id = text_system.glyph_for_char(font_id, 'm').unwrap();
+        let params = RenderGlyphParams {
+            font_id,
+            glyph_id,
+            font_size: px(16.),
+            subpixel_variant: Default::default(),
+            scale_factor: 1.,
+            is_emoji: false,
+        };
+        let advance = text_system.advance(font_id, glyph_id).unwrap();
+        let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+
+        // Text glyphs are unaffected.
+        text_system.set_emoji_scale(2.);
+        assert_eq!(text_system.advance(font_id, glyph_id).unwrap(), advance);
+        assert_eq!(text_system.glyph_raster_bounds(&params).unwrap(), bounds);
+
+        let Some(emoji_font_id) = text_system
+            .family_font_ids("Noto Color Emoji")
+            .first()
+            .copied()
+        else {
+            return;
+        };
+        let emoji_glyph_id = text_system
+            .glyph_for_char(emoji_font_id, '\u{1f600}')
+            .unwrap();
+        let scaled_advance = text_system.advance(emoji_font_id, emoji_glyph_id).unwrap();
+        text_system.set_emoji_scale(1.);
+        let advance = text_system.advance(emoji_font_id, emoji_glyph_id).unwrap();
+        assert_eq!(scaled_advance.width, advance.width * 2.);
+    }
+
+    #[test]
+    fn test_rasterize_color_emoji() {
+        let text_system = text_system();
+        // No color emoji font is bundled, so this only runs where one is installed.
+        let Some(font_id) = text_system
+            .family_font_ids("Noto Color Emoji")
+            .first()
+            .copied()
+        else {
+            return;
+        };
+        let params = RenderGlyphParams {
+            font_id,
+            glyph_id: text_system.glyph_for_char(font_id, '\u{1f600}').unwrap(),
+            font_size: px(16.),
+            subpixel_variant: Default::default(),
+            scale_factor: 1.,
+            is_emoji: true,
+        };
+        let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+        let (size, bytes) = text_system.rasterize_glyph(&params, bounds).unwrap();
+        assert_eq!(size, bounds.size);
+        assert_eq!(
+            bytes.len(),
+            size.width.0 as usize * size.height.0 as usize * 4
+        );
+    }
+
+    #[test]
+    fn test_subpixel_variants() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let params = RenderGlyphParams {
+            font_id,
+            glyph_id: text_system.glyph_for_char(font_id, 'l').unwrap(),
+            font_size: px(16.),
+            subpixel_variant: Default::default(),
+            scale_factor: 1.,
+            is_emoji: false,
+        };
+        let shifted_params = RenderGlyphParams {
+            subpixel_variant: point(2, 0),
+            ..params
+        };
+
+        let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+        let bitmap = text_system.rasterize_glyph(&params, bounds).unwrap();
+        let shifted_bounds = text_system.glyph_raster_bounds(&shifted_params).unwrap();
+        let shifted_bitmap = text_system
+            .rasterize_glyph(&shifted_params, shifted_bounds)
+            .unwrap();
+        assert_ne!(bitmap, shifted_bitmap);
+        assert_eq!(text_system.raster_stats().cache_misses, 2);
+    }
+
+    #[test]
+    fn test_raster_stats() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let params = RenderGlyphParams {
+            font_id,
+            glyph_id: text_system.glyph_for_char(font_id, 'g').unwrap(),
+            font_size: px(16.),
+            subpixel_variant: Default::default(),
+            scale_factor: 1.,
+            is_emoji: false,
+        };
+        let stats = text_system.raster_stats();
+        assert_eq!(stats.glyphs_rasterized, 0);
+        assert_eq!(stats.cache_misses, 0);
+
+        let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+        text_system.rasterize_glyph(&params, bounds).unwrap();
+        text_system.rasterize_glyph(&params, bounds).unwrap();
+        let stats = text_system.raster_stats();
+        assert_eq!(stats.glyphs_rasterized, 2);
+        assert_eq!(stats.cache_misses, 1);
+        assert_eq!(stats.cache_hits, 2);
+        assert!(stats.total_raster_time > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_glyph_padding() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let params = RenderGlyphParams {
+            font_id,
+            glyph_id: text_system.glyph_for_char(font_id, 'm').unwrap(),
+            font_size: px(16.),
+            subpixel_variant: Default::default(),
+            scale_factor: 1.,
+            is_emoji: false,
+        };
+        let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+        let (_, bitmap) = text_system.rasterize_glyph(&params, bounds).unwrap();
+
+        text_system.set_glyph_padding(2);
+        let padded_bounds = text_system.glyph_raster_bounds(&params).unwrap();
+        assert_eq!(
+            padded_bounds.origin,
+            point(
+                bounds.origin.x - DevicePixels(2),
+                bounds.origin.y - DevicePixels(2)
+            )
+        );
+        assert_eq!(
+            padded_bounds.size,
+            size(
+                bounds.size.width + DevicePixels(4),
+                bounds.size.height + DevicePixels(4)
+            )
+        );
+
+        let (padded_size, padded_bitmap) =
+            text_system.rasterize_glyph(&params, padded_bounds).unwrap();
+        assert_eq!(padded_size, padded_bounds.size);
+        let width = padded_size.width.0 as usize;
+        let rows = padded_bitmap.chunks_exact(width).collect::<Vec<_>>();
+        assert_eq!(rows.len(), padded_size.height.0 as usize);
+        assert!(rows[..2]
+            .iter()
+            .chain(&rows[rows.len() - 2..])
+            .all(|row| row.iter().all(|a| *a == 0)));
+        let unpadded_rows = rows[2..rows.len() - 2]
+            .iter()
+            .flat_map(|row| row[2..width - 2].iter().copied())
+            .collect::<Vec<_>>();
+        assert_eq!(unpadded_rows, bitmap);
+
+        // Changing the padding doesn't affect bounds that were computed before.
+        text_system.set_glyph_padding(1);
+        let (size, bitmap) = text_system.rasterize_glyph(&params, padded_bounds).unwrap();
+        assert_eq!((size, bitmap), (padded_size, padded_bitmap));
+        let (size, _) = text_system.rasterize_glyph(&params, bounds).unwrap();
+        assert_eq!(size, bounds.size);
+
+        // Glyphs without ink stay empty.
+        let space = RenderGlyphParams {
+            glyph_id: text_system.glyph_for_char(font_id, ' ').unwrap(),
+            ..params
+        };
+        let space_bounds = text_system.glyph_raster_bounds(&space).unwrap();
+        assert!(space_bounds.size.width.0 == 0 || space_bounds.size.height.0 == 0);
+        let (size, bitmap) = text_system.rasterize_glyph(&space, space_bounds).unwrap();
+        assert_eq!(size, Size::default());
+        assert!(bitmap.is_empty());
+    }
+
+    #[test]
+    fn test_zero_font_size() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let run = |len, font_size| FontRun {
+            len,
+            font_id,
+            font_size,
+        };
+
+        let layout = text_system.layout_line("abc", px(0.), &[run(3, None)]);
+        assert_eq!(layout.len, 3);
+        assert_eq!(layout.width, px(0.));
+        assert!(layout.runs.is_empty());
+
+        // Only the runs with a usable size contribute to the line.
+        let small = text_system.layout_line("abc", px(12.), &[run(3, None)]);
+        let mixed =
+            text_system.layout_line("abcabc", px(12.), &[run(3, Some(px(0.))), run(3, None)]);
+        assert_eq!(mixed.width, small.width);
+        assert_eq!(mixed.runs.len(), 1);
+        assert_eq!(mixed.runs[0].glyphs.len(), 3);
+        assert_eq!(mixed.runs[0].glyphs[0].index, 3);
+        assert_eq!(mixed.runs[0].glyphs[0].position.x, px(0.));
+
+        let params = RenderGlyphParams {
+            font_id,
+            glyph_id: text_system.glyph_for_char(font_id, 'a').unwrap(),
+            font_size: px(0.),
+            subpixel_variant: Default::default(),
+            scale_factor: 2.,
+            is_emoji: false,
+        };
+        let bounds = text_system.glyph_raster_bounds(&params).unwrap();
+        assert_eq!(bounds, Bounds::default());
+        let (size, bytes) = text_system.rasterize_glyph(&params, bounds).unwrap();
+        assert_eq!(size, Size::default());
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_scale_factors() {
+        let text_system = text_system();
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        let params = |font_size: f32, scale_factor: f32| RenderGlyphParams {
+            font_id,
+            glyph_id: text_system.glyph_for_char(font_id, 'a').unwrap(),
+            font_size: px(font_size),
+            subpixel_variant: Default::default(),
+            scale_factor,
+            is_emoji: false,
+        };
+        let raster = |params: &RenderGlyphParams| {
+            let bounds = text_system.glyph_raster_bounds(params).unwrap();
+            (bounds, text_system.rasterize_glyph(params, bounds).unwrap())
+        };
+
+        // Nothing is visible at a scale factor of zero.
+        let (bounds, (size, bytes)) = raster(&params(16., 0.));
+        assert_eq!(bounds, Bounds::default());
+        assert_eq!(size, Size::default());
+        assert!(bytes.is_empty());
+        for scale_factor in [f32::NAN, f32::INFINITY, -1.] {
+            assert!(text_system
+                .glyph_raster_bounds(&params(16., scale_factor))
+                .is_err());
+            assert!(text_system
+                .rasterize_glyph(&params(16., scale_factor), Bounds::default())
+                .is_err());
+        }
+
+        // The same size in device pixels renders the same glyph, reusing the cached image, even
+        // when the sizes only differ by a rounding error.
+        let device_size = raster(&params(20., 1.));
+        for (font_size, scale_factor) in [(16., 1.25), (10., 2.)] {
+            let hits = text_system.raster_stats().cache_hits;
+            assert_eq!(raster(&params(font_size, scale_factor)), device_size);
+            assert!(text_system.raster_stats().cache_hits > hits);
+        }
+        // 8.1 * 1.5 comes out slightly above 12.15 in single precision.
+        assert_ne!(8.1_f32 * 1.5, 12.15);
+        let fractional = raster(&params(12.15, 1.));
+        let hits = text_system.raster_stats().cache_hits;
+        assert_eq!(raster(&params(8.1, 1.5)), fractional);
+        assert!(text_system.raster_stats().cache_hits > hits);
+    }
+
+    #[test]
+    fn test_font_source() {
+        struct TestFontSource(Arc<AtomicUsize>);
+
+        impl FontSource for TestFontSource {
+            fn load_family(&self, family: &str) -> Option<Vec<Cow<'static, [u8]>>> {
+                self.0.fetch_add(1, SeqCst);
+                (family == "Zed Mono").then(|| {
+                    vec![Cow::Borrowed(
+                        include_bytes!(
+                            "../../../../../assets/fonts/zed-mono/zed-mono-extended.ttf"
+                        )
+                        .as_slice(),
+                    )]
+                })
+            }
+        }
+
+        let text_system = CosmicTextSystem::new();
+        let requests = Arc::new(AtomicUsize::new(0));
+        text_system.register_font_source(Box::new(TestFontSource(requests.clone())));
+
+        let font_id = text_system.font_id(&font("Zed Mono")).unwrap();
+        assert_eq!(requests.load(SeqCst), 1);
+        assert!(text_system.glyph_for_char(font_id, 'm').is_some());
+
+        // Families are only fetched once.
+        assert_eq!(text_system.font_id(&font("Zed Mono")).unwrap(), font_id);
+        assert_eq!(requests.load(SeqCst), 1);
+
+        assert!(text_system.font_id(&font("Not A Font Family")).is_err());
+        assert_eq!(requests.load(SeqCst), 2);
     }
 }
@@ -255,7 +255,7 @@ fn wl_seat_version(version: u32) -> u32 {
 }
 
 impl WaylandClient {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(font_path_cache: Option<PathBuf>) -> Self {
         let conn = Connection::connect_to_env().unwrap();
 
         let (globals, mut event_queue) =
@@ -293,7 +293,7 @@ impl WaylandClient {
 
         let event_loop = EventLoop::<WaylandClientStatePtr>::try_new().unwrap();
 
-        let (common, main_receiver) = LinuxCommon::new(event_loop.get_signal());
+        let (common, main_receiver) = LinuxCommon::new(event_loop.get_signal(), font_path_cache);
 
         let handle = event_loop.handle();
         handle.insert_source(main_receiver, |event, _, _: &mut WaylandClientStatePtr| {
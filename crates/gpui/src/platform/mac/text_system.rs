@@ -1,7 +1,8 @@
 use crate::{
     point, px, size, Bounds, DevicePixels, Font, FontFeatures, FontId, FontMetrics, FontRun,
-    FontStyle, FontWeight, GlyphId, LineLayout, Pixels, PlatformTextSystem, Point,
-    RenderGlyphParams, Result, ShapedGlyph, ShapedRun, SharedString, Size, SUBPIXEL_VARIANTS,
+    FontStretch, FontStyle, FontWeight, GlyphId, LineLayout, Pixels, PlatformTextSystem, Point,
+    RenderGlyphParams, Result, ShapedGlyph, ShapedRun, SharedString, Size, TextDirection,
+    SUBPIXEL_VARIANTS,
 };
 use anyhow::anyhow;
 use cocoa::appkit::{CGFloat, CGPoint};
@@ -30,7 +31,7 @@ use font_kit::{
     handle::Handle,
     hinting::HintingOptions,
     metrics::Metrics,
-    properties::{Style as FontkitStyle, Weight as FontkitWeight},
+    properties::{Stretch as FontkitStretch, Style as FontkitStyle, Weight as FontkitWeight},
     source::SystemSource,
     sources::mem::MemSource,
 };
@@ -142,7 +143,7 @@ impl PlatformTextSystem for MacTextSystem {
                 &font_kit::properties::Properties {
                     style: font.style.into(),
                     weight: font.weight.into(),
-                    stretch: Default::default(),
+                    stretch: font.stretch.into(),
                 },
             )?;
 
@@ -435,6 +436,10 @@ impl MacTextSystemState {
     }
 
     fn layout_line(&mut self, text: &str, font_size: Pixels, font_runs: &[FontRun]) -> LineLayout {
+        debug_assert!(
+            font_runs.iter().all(|run| run.font_size.is_none()),
+            "per-run font sizes aren't supported by the CoreText text system"
+        );
         // Construct the attributed string, converting UTF8 ranges to UTF16 ranges.
         let mut string = CFMutableAttributedString::new();
         {
@@ -499,11 +504,18 @@ impl MacTextSystemState {
                     id: GlyphId(*glyph_id as u32),
                     position: point(position.x as f32, position.y as f32).map(px),
                     index: ix_converter.utf8_ix,
+                    cluster_len: 0,
                     is_emoji: self.is_emoji(font_id),
                 });
             }
 
-            runs.push(ShapedRun { font_id, glyphs })
+            runs.push(ShapedRun {
+                font_id,
+                font_size: None,
+                glyphs,
+                // todo(mac) read the direction from the run's status
+                direction: TextDirection::LeftToRight,
+            })
         }
 
         let typographic_bounds = line.get_typographic_bounds();
@@ -514,6 +526,8 @@ impl MacTextSystemState {
             ascent: typographic_bounds.ascent.into(),
             descent: typographic_bounds.descent.into(),
             len: text.len(),
+            grapheme_boundaries: Vec::new(),
+            truncated: false,
         }
     }
 }
@@ -624,6 +638,12 @@ impl From<FontWeight> for FontkitWeight {
     }
 }
 
+impl From<FontStretch> for FontkitStretch {
+    fn from(value: FontStretch) -> Self {
+        FontkitStretch(value.0 / 100.)
+    }
+}
+
 impl From<FontStyle> for FontkitStyle {
     fn from(style: FontStyle) -> Self {
         match style {
@@ -685,6 +705,7 @@ mod tests {
         let mut style = FontRun {
             font_id,
             len: line.len(),
+            font_size: None,
         };
 
         let layout = fonts.layout_line(line, px(16.), &[style]);
@@ -356,6 +356,10 @@ impl DirectWriteState {
     }
 
     fn layout_line(&mut self, text: &str, font_size: Pixels, font_runs: &[FontRun]) -> LineLayout {
+        debug_assert!(
+            font_runs.iter().all(|run| run.font_size.is_none()),
+            "per-run font sizes aren't supported by the DirectWrite text system"
+        );
         if font_runs.is_empty() {
             return LineLayout {
                 font_size,
@@ -483,6 +487,8 @@ impl DirectWriteState {
                 descent,
                 runs,
                 len: text.len(),
+                grapheme_boundaries: Vec::new(),
+                truncated: false,
             }
         }
     }
@@ -971,12 +977,22 @@ impl IDWriteTextRenderer_Impl for TextRenderer {
                     id,
                     position: point(px(context.width), px(0.0)),
                     index: context.index_converter.utf8_ix,
+                    cluster_len: 0,
                     is_emoji,
                 });
                 context.utf16_index += utf16_length_per_glyph;
                 context.width += *glyphrun.glyphAdvances.add(index);
             }
-            context.runs.push(ShapedRun { font_id, glyphs });
+            context.runs.push(ShapedRun {
+                font_id,
+                font_size: None,
+                glyphs,
+                direction: if glyphrun.bidiLevel % 2 == 1 {
+                    TextDirection::RightToLeft
+                } else {
+                    TextDirection::LeftToRight
+                },
+            });
         }
         Ok(())
     }
@@ -1139,6 +1155,8 @@ unsafe fn get_postscript_name_and_font(
         features: FontFeatures::default(),
         weight: font_face.GetWeight().into(),
         style: font_face.GetStyle().into(),
+        stretch: Default::default(),
+        variations: Vec::new(),
     };
     let is_emoji = font_face.IsColorFont().as_bool();
     Some((postscript_name, font_struct, is_emoji))
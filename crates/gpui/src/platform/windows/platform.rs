@@ -149,7 +149,7 @@ impl WindowsPlatformSystemSettings {
 }
 
 impl WindowsPlatform {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(font_path_cache: Option<PathBuf>) -> Self {
         unsafe {
             OleInitialize(None).expect("unable to initialize Windows OLE");
         }
@@ -164,7 +164,8 @@ impl WindowsPlatform {
             Arc::new(direct_write) as Arc<dyn PlatformTextSystem>
         } else {
             log::info!("Using cosmic text system.");
-            Arc::new(CosmicTextSystem::new()) as Arc<dyn PlatformTextSystem>
+            Arc::new(CosmicTextSystem::new_with_font_path_cache(font_path_cache))
+                as Arc<dyn PlatformTextSystem>
         };
         let callbacks = Mutex::new(Callbacks::default());
         let raw_window_handles = RwLock::new(SmallVec::new());
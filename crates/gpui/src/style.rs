@@ -265,6 +265,8 @@ impl TextStyle {
             features: self.font_features.clone(),
             weight: self.font_weight,
             style: self.font_style,
+            stretch: Default::default(),
+            variations: Vec::new(),
         }
     }
 
@@ -282,6 +284,8 @@ impl TextStyle {
                 features: Default::default(),
                 weight: self.font_weight,
                 style: self.font_style,
+                stretch: Default::default(),
+                variations: Vec::new(),
             },
             color: self.color,
             background_color: self.background_color,
@@ -795,11 +795,14 @@ pub trait Styled: Sized {
 
     /// Change the font of this element and its children.
     fn font(mut self, font: Font) -> Self {
+        // Text styles have no widths or variable font coordinates, so those are left out.
         let Font {
             family,
             features,
             weight,
             style,
+            stretch: _,
+            variations: _,
         } = font;
 
         let text_style = self.text_style().get_or_insert_with(Default::default);
@@ -398,6 +398,7 @@ impl WindowTextSystem {
                     font_runs.push(FontRun {
                         len: run_len_within_line,
                         font_id: self.resolve_font(&run.font),
+                        font_size: None,
                     });
                 }
 
@@ -498,6 +499,7 @@ impl WindowTextSystem {
             font_runs.push(FontRun {
                 len: run.len,
                 font_id,
+                font_size: None,
             });
         }
 
@@ -593,6 +595,47 @@ impl FontWeight {
     pub const BLACK: FontWeight = FontWeight(900.0);
 }
 
+/// The width of a font as a percentage of its normal width. This value ranges from 50.0 to 200.0,
+/// with 100.0 as normal.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct FontStretch(pub f32);
+
+impl Default for FontStretch {
+    #[inline]
+    fn default() -> FontStretch {
+        FontStretch::NORMAL
+    }
+}
+
+impl Hash for FontStretch {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(u32::from_be_bytes(self.0.to_be_bytes()));
+    }
+}
+
+impl Eq for FontStretch {}
+
+impl FontStretch {
+    /// Ultra-condensed width (50%), the narrowest value.
+    pub const ULTRA_CONDENSED: FontStretch = FontStretch(50.0);
+    /// Extra-condensed width (62.5%).
+    pub const EXTRA_CONDENSED: FontStretch = FontStretch(62.5);
+    /// Condensed width (75%).
+    pub const CONDENSED: FontStretch = FontStretch(75.0);
+    /// Semi-condensed width (87.5%).
+    pub const SEMI_CONDENSED: FontStretch = FontStretch(87.5);
+    /// Normal width (100%).
+    pub const NORMAL: FontStretch = FontStretch(100.0);
+    /// Semi-expanded width (112.5%).
+    pub const SEMI_EXPANDED: FontStretch = FontStretch(112.5);
+    /// Expanded width (125%).
+    pub const EXPANDED: FontStretch = FontStretch(125.0);
+    /// Extra-expanded width (150%).
+    pub const EXTRA_EXPANDED: FontStretch = FontStretch(150.0);
+    /// Ultra-expanded width (200%), the widest value.
+    pub const ULTRA_EXPANDED: FontStretch = FontStretch(200.0);
+}
+
 /// Allows italic or oblique faces to be selected.
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Hash, Default)]
 pub enum FontStyle {
@@ -691,6 +734,31 @@ pub struct Font {
 
     /// The font style.
     pub style: FontStyle,
+
+    /// The font width, e.g. to select the condensed faces of a family.
+    pub stretch: FontStretch,
+
+    /// The coordinates on the axes of a variable font to use. Axes that aren't listed keep their
+    /// default coordinates.
+    pub variations: Vec<FontVariation>,
+}
+
+/// A coordinate on one of the axes of a variable font, e.g. a weight of 450 on the `wght` axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FontVariation {
+    /// The OpenType tag of the axis, e.g. `wght`, `wdth`, or a custom axis of the font.
+    pub tag: [u8; 4],
+    /// The coordinate on the axis, in the axis' own units.
+    pub value: f32,
+}
+
+impl Eq for FontVariation {}
+
+impl Hash for FontVariation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.tag.hash(state);
+        self.value.to_bits().hash(state);
+    }
 }
 
 /// Get a [`Font`] for a given name.
@@ -700,9 +768,29 @@ pub fn font(family: impl Into<SharedString>) -> Font {
         features: FontFeatures::default(),
         weight: FontWeight::default(),
         style: FontStyle::default(),
+        stretch: FontStretch::default(),
+        variations: Vec::new(),
     }
 }
 
+/// The DPI at which one pixel is exactly 1/96 of an inch, matching the Windows and CSS reference.
+const REFERENCE_DPI: f32 = 96.;
+
+/// The number of points in an inch.
+const POINTS_PER_INCH: f32 = 72.;
+
+/// Convert a font size in points to pixels at the given scale factor, using 96 DPI as the
+/// reference for a scale factor of 1. Pass a scale factor of 1 to get a size in logical pixels.
+pub fn points_to_pixels(points: f32, scale_factor: f32) -> Pixels {
+    px(points * REFERENCE_DPI * scale_factor / POINTS_PER_INCH)
+}
+
+/// Convert a font size in pixels at the given scale factor back to points. This is the inverse of
+/// [`points_to_pixels`].
+pub fn pixels_to_points(pixels: Pixels, scale_factor: f32) -> f32 {
+    pixels.0 * POINTS_PER_INCH / (REFERENCE_DPI * scale_factor)
+}
+
 impl Font {
     /// Set this Font to be bold
     pub fn bold(mut self) -> Self {
@@ -715,6 +803,13 @@ impl Font {
         self.style = FontStyle::Italic;
         self
     }
+
+    /// Set the coordinate of this Font on the given axis of a variable font, e.g. `b"wght"`
+    pub fn variation(mut self, tag: [u8; 4], value: f32) -> Self {
+        self.variations.retain(|variation| variation.tag != tag);
+        self.variations.push(FontVariation { tag, value });
+        self
+    }
 }
 
 /// A struct for storing font metrics.
@@ -792,3 +887,19 @@ impl FontMetrics {
         (self.bounding_box / self.units_per_em as f32 * font_size.0).map(px)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_points_to_pixels() {
+        assert_eq!(points_to_pixels(12., 1.), px(16.));
+        assert_eq!(points_to_pixels(12., 1.5), px(24.));
+        assert_eq!(points_to_pixels(9., 2.), px(24.));
+
+        assert_eq!(pixels_to_points(px(16.), 1.), 12.);
+        assert_eq!(pixels_to_points(px(24.), 1.5), 12.);
+        assert_eq!(pixels_to_points(points_to_pixels(10.5, 1.25), 1.25), 10.5);
+    }
+}
@@ -1,11 +1,19 @@
 use crate::{
-    black, fill, point, px, size, Bounds, Hsla, LineLayout, Pixels, Point, Result, SharedString,
-    StrikethroughStyle, UnderlineStyle, WindowContext, WrapBoundary, WrappedLineLayout,
+    black, fill, point, px, size, Bounds, FontId, GlyphId, Hsla, LineLayout, Pixels, Point, Result,
+    SharedString, StrikethroughStyle, UnderlineStyle, WindowContext, WrapBoundary,
+    WrappedLineLayout,
 };
+use collections::HashSet;
 use derive_more::{Deref, DerefMut};
+use parking_lot::Mutex;
 use smallvec::SmallVec;
 use std::sync::Arc;
 
+lazy_static::lazy_static! {
+    /// Glyphs that failed to paint, so that each failure is logged once rather than every frame.
+    static ref UNPAINTABLE_GLYPHS: Mutex<HashSet<(FontId, GlyphId)>> = Mutex::default();
+}
+
 /// Set the text decoration for a run of text.
 #[derive(Debug, Clone)]
 pub struct DecorationRun {
@@ -124,7 +132,8 @@ fn paint_line(
         let mut glyph_origin = origin;
         let mut prev_glyph_position = Point::default();
         for (run_ix, run) in layout.runs.iter().enumerate() {
-            let max_glyph_size = text_system.bounding_box(run.font_id, layout.font_size).size;
+            let font_size = run.font_size.unwrap_or(layout.font_size);
+            let max_glyph_size = text_system.bounding_box(run.font_id, font_size).size;
 
             for (glyph_ix, glyph) in run.glyphs.iter().enumerate() {
                 glyph_origin.x += glyph.position.x - prev_glyph_position.x;
@@ -266,21 +275,22 @@ fn paint_line(
 
                 let content_mask = cx.content_mask();
                 if max_glyph_bounds.intersects(&content_mask.bounds) {
-                    if glyph.is_emoji {
-                        cx.paint_emoji(
-                            glyph_origin + baseline_offset,
-                            run.font_id,
-                            glyph.id,
-                            layout.font_size,
-                        )?;
+                    let glyph_baseline =
+                        glyph_origin + baseline_offset + point(px(0.), glyph.position.y);
+                    // Glyphs that can't be rasterized are left out rather than ending the line.
+                    let result = if glyph.is_emoji {
+                        cx.paint_emoji(glyph_baseline, run.font_id, glyph.id, font_size)
                     } else {
-                        cx.paint_glyph(
-                            glyph_origin + baseline_offset,
-                            run.font_id,
-                            glyph.id,
-                            layout.font_size,
-                            color,
-                        )?;
+                        cx.paint_glyph(glyph_baseline, run.font_id, glyph.id, font_size, color)
+                    };
+                    if let Err(error) = result {
+                        if UNPAINTABLE_GLYPHS.lock().insert((run.font_id, glyph.id)) {
+                            log::error!(
+                                "failed to paint glyph {:?} of font {:?}: {error:?}",
+                                glyph.id,
+                                run.font_id
+                            );
+                        }
                     }
                 }
             }
@@ -10,7 +10,7 @@ use std::{
 };
 
 /// A laid out and styled line of text
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct LineLayout {
     /// The font size for this line
     pub font_size: Pixels,
@@ -24,15 +24,34 @@ pub struct LineLayout {
     pub runs: Vec<ShapedRun>,
     /// The length of the line in utf-8 bytes
     pub len: usize,
+    /// The byte offsets at which grapheme clusters start, filled in by text systems that map glyphs
+    /// to grapheme clusters
+    pub(crate) grapheme_boundaries: Vec<usize>,
+    /// Whether the line was laid out with a maximum width that its content didn't fit in
+    pub truncated: bool,
 }
 
 /// A run of text that has been shaped .
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ShapedRun {
     /// The font id for this run
     pub font_id: FontId,
+    /// The font size for this run, if it differs from the line's font size
+    pub font_size: Option<Pixels>,
     /// The glyphs that make up this run
     pub glyphs: SmallVec<[ShapedGlyph; 8]>,
+    /// The direction of the text in this run, which determines the order of its glyphs
+    pub direction: TextDirection,
+}
+
+/// The direction in which a run of text is read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TextDirection {
+    /// Left to right, e.g. Latin or CJK text.
+    #[default]
+    LeftToRight,
+    /// Right to left, e.g. Arabic or Hebrew text.
+    RightToLeft,
 }
 
 /// A single glyph, ready to paint.
@@ -44,9 +63,16 @@ pub struct ShapedGlyph {
     /// The position of this glyph in its containing line.
     pub position: Point<Pixels>,
 
-    /// The index of this glyph in the original text.
+    /// The index of this glyph in the original text. With text systems that compute
+    /// [`LineLayout::grapheme_boundaries`], this is the start of the grapheme cluster the glyph
+    /// belongs to.
     pub index: usize,
 
+    /// The length in bytes of the grapheme clusters this glyph renders, e.g. all three letters of
+    /// an "ffi" ligature, or a letter and its combining accent, so the caret can be moved by whole
+    /// clusters. Filled in along with [`LineLayout::grapheme_boundaries`], and zero otherwise.
+    pub cluster_len: usize,
+
     /// Whether this glyph is an emoji
     pub is_emoji: bool,
 }
@@ -103,6 +129,13 @@ impl LineLayout {
         self.width
     }
 
+    /// The byte offsets at which grapheme clusters start, for stepping through the line one
+    /// user-perceived character at a time. Every [`ShapedGlyph`]'s index is one of them. Only the
+    /// cosmic-text text system computes these so far; other text systems return no boundaries.
+    pub fn grapheme_boundaries(&self) -> Vec<usize> {
+        self.grapheme_boundaries.clone()
+    }
+
     /// The corresponding Font at the given index
     pub fn font_id_for_index(&self, index: usize) -> Option<FontId> {
         for run in &self.runs {
@@ -461,6 +494,9 @@ impl LineLayoutCache {
 pub struct FontRun {
     pub(crate) len: usize,
     pub(crate) font_id: FontId,
+    /// Overrides the font size of the line for this run. Only the cosmic-text text system supports
+    /// this so far.
+    pub(crate) font_size: Option<Pixels>,
 }
 
 trait AsCacheKeyRef {
@@ -3,8 +3,8 @@ use crate::{Appearance, SyntaxTheme, Theme, ThemeRegistry, ThemeStyleContent};
 use anyhow::Result;
 use derive_more::{Deref, DerefMut};
 use gpui::{
-    px, AppContext, Font, FontFeatures, FontStyle, FontWeight, Global, Pixels, Subscription,
-    ViewContext,
+    px, AppContext, Font, FontFeatures, FontStretch, FontStyle, FontWeight, Global, Pixels,
+    Subscription, ViewContext,
 };
 use refineable::Refineable;
 use schemars::{
@@ -394,12 +394,16 @@ impl settings::Settings for ThemeSettings {
                 features: defaults.ui_font_features.clone().unwrap(),
                 weight: Default::default(),
                 style: Default::default(),
+                stretch: Default::default(),
+                variations: Vec::new(),
             },
             buffer_font: Font {
                 family: defaults.buffer_font_family.clone().unwrap().into(),
                 features: defaults.buffer_font_features.clone().unwrap(),
                 weight: FontWeight::default(),
                 style: FontStyle::default(),
+                stretch: FontStretch::default(),
+                variations: Vec::new(),
             },
             buffer_font_size: defaults.buffer_font_size.unwrap().into(),
             buffer_line_height: defaults.buffer_line_height.unwrap(),
@@ -562,6 +562,9 @@ impl<'a> PartialOrd for NumericPrefixWithSuffix<'a> {
 }
 lazy_static! {
     static ref EMOJI_REGEX: regex::Regex = regex::Regex::new("(\\p{Emoji}|\u{200D})").unwrap();
+    static ref EMOJI_PRESENTATION_REGEX: regex::Regex =
+        regex::Regex::new("\\p{Emoji_Presentation}|\\p{Extended_Pictographic}|\u{FE0F}|\u{20E3}")
+            .unwrap();
 }
 
 /// Returns true if the given string consists of emojis only.
@@ -577,6 +580,13 @@ pub fn word_consists_of_emojis(s: &str) -> bool {
     prev_end == s.len()
 }
 
+/// Returns true if the given string may contain an emoji, based on Unicode properties alone.
+/// Unlike `\p{Emoji}`, this doesn't match plain digits or `#`, so pure text is rejected cheaply.
+/// E.g. "a👋" and "#️⃣" will return true, but "123" will return false.
+pub fn contains_emoji(s: &str) -> bool {
+    !s.is_ascii() && EMOJI_PRESENTATION_REGEX.is_match(s)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -704,6 +714,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_contains_emoji() {
+        let words_to_test = vec![
+            ("👋", true),
+            ("Hello 👋!", true),
+            ("❤️", true),
+            ("#️⃣", true),
+            ("🇺🇸", true),
+            ("Test", false),
+            ("123 #", false),
+            ("→ ü 日本語", false),
+            ("", false),
+        ];
+
+        for (text, expected_result) in words_to_test {
+            assert_eq!(contains_emoji(text), expected_result, "{text:?}");
+        }
+    }
+
     #[test]
     fn test_truncate_lines_and_trailoff() {
         let text = r#"Line 1
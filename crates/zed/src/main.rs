@@ -180,7 +180,8 @@ fn init_ui(args: Args) {
     }
 
     log::info!("========== starting zed ==========");
-    let app = App::new().with_assets(Assets);
+    let app = App::new_with_font_path_cache(Some(paths::TEMP_DIR.join("system-font-paths.json")))
+        .with_assets(Assets);
 
     let (installation_id, existing_installation_id_found) = app
         .background_executor()